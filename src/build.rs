@@ -7,35 +7,93 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
 use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::BufWriter;
 use std::io::Read;
+use std::io::Write;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering as AtomicOrdering;
 
 use indicatif::ProgressBar;
 use indicatif::ProgressStyle;
 use log::debug;
+use log::error;
+use log::info;
 use log::trace;
+use log::warn;
 
 use ggcat_api::{GGCATInstance};
+use itertools::Itertools;
+
+use crate::fastx;
+
+// Rough peak memory use of ggcat per input base, in bytes
+const GGCAT_BYTES_PER_BASE: f64 = 1.0;
+
+// Files that build_pangenome_graph can write next to a graph
+pub const SIDE_FILE_SUFFIXES: [&str; 5] = [".building", ".inputs.tsv", ".colors.tsv", ".stats.tsv", ".gfa"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    Fasta,
+    // Unitigs with their links as <graph>.gfa, in addition to the FASTA
+    Gfa,
+}
 
 #[derive(Clone)]
 pub struct GGCATParams {
     // k-mer sketching
     pub kmer_size: u32,
     pub kmer_min_multiplicity: u64,
+    // Inputs that are pre-filtered with their own minimum multiplicity
+    pub min_multiplicity_per_input: HashMap<String, u64>,
 
     // Graph construction
     pub minimizer_length: Option<usize>,
     pub no_reverse_complement: bool,
     pub unitig_type: ggcat_api::ExtraElaboration,
+    // Colored graph with a <graph>.colors.tsv of the input names
+    pub colors: bool,
+    // Unitig statistics of each graph in <graph>.stats.tsv
+    pub graph_stats: bool,
+    pub output_format: GraphFormat,
+    // Graphs that were completely built by an earlier run are not rebuilt
+    pub skip_existing: bool,
 
     // Resources
     pub threads: u32,
     pub memory: u32,
     pub temp_dir_path: String,
+    // Compressed inputs are decompressed to temp_dir_path before building
+    pub keep_decompressed: bool,
 
     // Output
     pub out_prefix: String,
 
+    // Clusters outside the size range are not built
+    pub min_cluster_size: usize,
+    pub max_cluster_size: Option<usize>,
+
+    // Graphs estimated to need more memory (GB) are skipped or filtered
+    pub max_ram_per_graph: Option<f64>,
+    pub oversized_graph: String,
+    pub fallback_min_multiplicity: u64,
+
+    // Stop at the first graph that fails to build. On by default since
+    // dereplicate sketches its graphs in the next iteration, `build` turns
+    // it off unless --strict is given and reports the failed graphs instead
+    pub strict: bool,
+
+    // Build graphs from at most this many members of each cluster
+    pub members_per_cluster: Option<usize>,
+
+    // Stop after building this many graphs, for quick test runs
+    pub build_limit: Option<usize>,
+
+    // Graphs built in one chunk before the buffered output is released
+    pub max_concurrent_graphs: usize,
+
     // Intermediate outputs
     pub intermediate_compression_level: Option<u32>,
     pub stats_file: Option<PathBuf>,
@@ -49,17 +107,38 @@ impl Default for GGCATParams {
         GGCATParams {
             kmer_size: 51,
             kmer_min_multiplicity: 1,
+            min_multiplicity_per_input: HashMap::new(),
 
             minimizer_length: None,
             no_reverse_complement: false,
             unitig_type: ggcat_api::ExtraElaboration::GreedyMatchtigs,
+            colors: false,
+            graph_stats: false,
+            output_format: GraphFormat::Fasta,
+            skip_existing: false,
 
             threads: 1,
             memory: 4,
             temp_dir_path: "/tmp".to_string(),
+            keep_decompressed: false,
 
 	    out_prefix: "".to_string(),
 
+            min_cluster_size: 2,
+            max_cluster_size: None,
+
+            max_ram_per_graph: None,
+            oversized_graph: "skip".to_string(),
+            fallback_min_multiplicity: 2,
+
+            strict: true,
+
+            members_per_cluster: None,
+
+            build_limit: None,
+
+            max_concurrent_graphs: 256,
+
             intermediate_compression_level: None,
             stats_file: None,
 
@@ -95,14 +174,144 @@ pub fn init_ggcat(opt: &Option<GGCATParams>) -> &ggcat_api::GGCATInstance {
     return instance;
 }
 
+fn prefilter_input(input_seq_name: &String, out_file: PathBuf, min_multiplicity: u64, instance: &GGCATInstance, params: &GGCATParams) -> PathBuf {
+    // ggcat only takes a single threshold for all inputs, so inputs with their
+    // own threshold are first reduced to the unitigs of a single-input graph
+    debug!("Pre-filtering {} with minimum k-mer count {}", input_seq_name, min_multiplicity);
+    let mut buf = gag::BufferRedirect::stdout().unwrap();
+    let filtered = instance.build_graph(
+        vec![ggcat_api::GeneralSequenceBlockData::FASTA((PathBuf::from(input_seq_name), None))],
+        out_file,
+        Some(&[input_seq_name.clone()]),
+        params.kmer_size as usize,
+        params.threads as usize,
+        params.no_reverse_complement,
+        params.minimizer_length,
+        false, // No colors
+        min_multiplicity as usize,
+        ggcat_api::ExtraElaboration::None,
+    );
+    let mut output = String::new();
+    buf.read_to_string(&mut output).unwrap();
+    drop(buf);
+    for line in output.lines() {
+	trace!("{}", line);
+    }
+    return filtered;
+}
+
+fn unitig_elaboration(params: &GGCATParams) -> ggcat_api::ExtraElaboration {
+    // The links between unitigs are only written by this elaboration
+    return if params.output_format == GraphFormat::Gfa { ggcat_api::ExtraElaboration::UnitigLinks } else { params.unitig_type };
+}
+
+fn unitig_type_name(unitig_type: ggcat_api::ExtraElaboration) -> &'static str {
+    return match unitig_type {
+	ggcat_api::ExtraElaboration::GreedyMatchtigs => "greedymatchtigs",
+	ggcat_api::ExtraElaboration::UnitigLinks => "unitiglinks",
+	ggcat_api::ExtraElaboration::Eulertigs => "eulertigs",
+	ggcat_api::ExtraElaboration::Pathtigs => "pathtigs",
+	_ => "unitigs",
+    };
+}
+
+fn graph_inputs_record(input_seq_names: &[String], params: &GGCATParams) -> String {
+    // Everything that changes the graph, written next to it as <graph>.inputs.tsv
+    let mut record = format!("#k={}\tmin_multiplicity={}\tminimizer_length={}\tno_reverse_complement={}\tunitigs={}\tcolors={}\n",
+			     params.kmer_size, params.kmer_min_multiplicity, params.minimizer_length.map_or("default".to_string(), |x| x.to_string()),
+			     params.no_reverse_complement, unitig_type_name(unitig_elaboration(params)), params.colors);
+    input_seq_names.iter().for_each(|x| {
+	record += &format!("{}\t{}\n", x, params.min_multiplicity_per_input.get(x).unwrap_or(&1));
+    });
+    return record;
+}
+
+fn graph_is_complete(graph_file: &PathBuf, marker_file: &PathBuf, inputs_file: &PathBuf, inputs_record: &String) -> bool {
+    // A build that was killed leaves its marker behind, and a graph of other
+    // inputs or parameters has a different record
+    return std::fs::metadata(graph_file).is_ok_and(|x| x.len() > 0) && !marker_file.exists()
+	&& std::fs::read_to_string(inputs_file).is_ok_and(|x| &x == inputs_record);
+}
+
+fn write_side_files(input_seq_names: &[String], prefix: &String, params: &GGCATParams, overwrite: bool) {
+    // Side files of a graph skipped as complete are only written if missing
+    let graph_file = params.out_prefix.clone() + prefix;
+    let missing = |x: &String| overwrite || !std::path::Path::new(x).exists();
+    let colors_file = graph_file.clone() + ".colors.tsv";
+    if params.colors && missing(&colors_file) {
+	// ggcat numbers the colors in the order of the inputs
+	let mut writer = BufWriter::new(std::fs::File::create(colors_file).unwrap());
+	input_seq_names.iter().enumerate().for_each(|x| { writeln!(writer, "{}\t{}", x.0, x.1).unwrap() });
+	writer.flush().unwrap();
+    }
+    if params.graph_stats && missing(&(graph_file.clone() + ".stats.tsv")) {
+	write_graph_stats(&graph_file);
+    }
+    if params.output_format == GraphFormat::Gfa && missing(&(graph_file.clone() + ".gfa")) {
+	write_gfa(&graph_file, params.kmer_size as usize - 1);
+    }
+}
+
+struct TempFiles(Vec<PathBuf>);
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+	self.0.iter().for_each(|x| { let _ = std::fs::remove_file(x); });
+    }
+}
+
 fn build_pangenome_graph(input_seq_names: &[String], prefix: &String, instance: &GGCATInstance, params: &GGCATParams) {
+    let graph_file = PathBuf::from(params.out_prefix.clone() + prefix);
+    let marker_file = PathBuf::from(params.out_prefix.clone() + prefix + ".building");
+    let inputs_file = PathBuf::from(params.out_prefix.clone() + prefix + ".inputs.tsv");
+    let inputs_record = graph_inputs_record(input_seq_names, params);
+    if params.skip_existing && graph_is_complete(&graph_file, &marker_file, &inputs_file, &inputs_record) {
+	info!("Skipping existing graph {}", graph_file.display());
+	write_side_files(input_seq_names, prefix, params, false);
+	return;
+    }
+    std::fs::File::create(&marker_file).unwrap();
+    // A rebuilt graph must not be matched by the record of an earlier one
+    let _ = std::fs::remove_file(&inputs_file);
+
     debug!("Building graph {} from {} sequences:", prefix, input_seq_names.len());
     input_seq_names.iter().for_each(|x| { debug!("\t{}", x) });
 
-    let graph_file = PathBuf::from(params.out_prefix.clone() + prefix);
-    let inputs: Vec<ggcat_api::GeneralSequenceBlockData> = input_seq_names
+    let graph_name = std::path::Path::new(prefix).file_name().map_or("graph".to_string(), |x| x.to_string_lossy().to_string());
+    // Removed when dropped, so also when ggcat panics and the failure is caught
+    let mut temp_files = TempFiles(Vec::new());
+    let input_files: Vec<PathBuf> = input_seq_names
         .iter()
-        .map(|x| ggcat_api::GeneralSequenceBlockData::FASTA((PathBuf::from(x), None)))
+	.enumerate()
+        .map(|(i, x)| {
+	    // ggcat is not guaranteed to read every compression format
+	    let input = if fastx::is_compressed(x) {
+		let out_file = params.temp_dir_path.clone() + "/" + &graph_name + "-" + &i.to_string() + "-" + &fastx::decompressed_name(x);
+		if !params.keep_decompressed {
+		    temp_files.0.push(PathBuf::from(&out_file));
+		}
+		fastx::decompress(x, &out_file);
+		out_file
+	    } else {
+		x.clone()
+	    };
+	    match params.min_multiplicity_per_input.get(x) {
+		Some(min_multiplicity) if *min_multiplicity > 1 => {
+		    let out_file = PathBuf::from(params.out_prefix.clone() + prefix + ".prefilter-" + &i.to_string() + ".fasta");
+		    temp_files.0.push(out_file.clone());
+		    let filtered = prefilter_input(&input, out_file, *min_multiplicity, instance, params);
+		    if &input != x && !params.keep_decompressed {
+			let _ = std::fs::remove_file(&input);
+		    }
+		    filtered
+		},
+		_ => PathBuf::from(input),
+	    }
+	})
+        .collect();
+    let inputs: Vec<ggcat_api::GeneralSequenceBlockData> = input_files
+        .iter()
+        .map(|x| ggcat_api::GeneralSequenceBlockData::FASTA((x.clone(), None)))
         .collect();
 
     let mut buf = gag::BufferRedirect::stdout().unwrap();
@@ -114,9 +323,9 @@ fn build_pangenome_graph(input_seq_names: &[String], prefix: &String, instance:
         params.threads as usize,
         params.no_reverse_complement,
         params.minimizer_length,
-        false, // No colors
+        params.colors,
         params.kmer_min_multiplicity as usize,
-        params.unitig_type,
+        unitig_elaboration(params),
     );
     let mut output = String::new();
     buf.read_to_string(&mut output).unwrap();
@@ -124,28 +333,343 @@ fn build_pangenome_graph(input_seq_names: &[String], prefix: &String, instance:
     for line in output.lines() {
 	trace!("{}", line);
     }
+
+    drop(temp_files);
+    // Only graphs that can be skipped later get a record, not the intermediate ones
+    if params.skip_existing {
+	std::fs::write(&inputs_file, &inputs_record).unwrap();
+    }
+    let _ = std::fs::remove_file(&marker_file);
+
+    write_side_files(input_seq_names, prefix, params, true);
 }
 
-pub fn build_pangenome_representations(
+fn parse_unitig_links(header: &str) -> (String, Vec<(String, String, String)>) {
+    // ggcat writes the links in the unitig header as L:<from orientation>:<to>:<to orientation>
+    let mut tokens = header[1..].split_whitespace();
+    let id = tokens.next().unwrap_or("").to_string();
+    let links = tokens.filter_map(|token| {
+	let fields: Vec<&str> = token.split(':').collect();
+	if fields.len() == 4 && fields[0] == "L" {
+	    Some((fields[1].to_string(), fields[2].to_string(), fields[3].to_string()))
+	} else {
+	    None
+	}
+    }).collect();
+    return (id, links);
+}
+
+fn is_canonical_link(from: &str, from_orientation: &str, to: &str, to_orientation: &str) -> bool {
+    // Each link is listed from both of its unitigs, keep the one that sorts first
+    let flip = |x: &str| if x == "+" { "-" } else { "+" };
+    return (from, from_orientation, to, to_orientation) <= (to, flip(to_orientation), from, flip(from_orientation));
+}
+
+fn write_gfa(graph_file: &String, overlap: usize) {
+    // Segment names are the unitig ids in the FASTA headers so the two outputs can be matched
+    let reader = fastx::open_fastx(graph_file).unwrap();
+    let mut writer = BufWriter::new(std::fs::File::create(graph_file.clone() + ".gfa").unwrap());
+    writeln!(writer, "H\tVN:Z:1.0").unwrap();
+
+    let mut write_segment = |id: &String, seq: &String, links: &Vec<(String, String, String)>| {
+	writeln!(writer, "S\t{}\t{}\tLN:i:{}", id, seq, seq.len()).unwrap();
+	links.iter().filter(|x| is_canonical_link(id, &x.0, &x.1, &x.2)).for_each(|x| {
+	    writeln!(writer, "L\t{}\t{}\t{}\t{}\t{}M", id, x.0, x.1, x.2, overlap).unwrap();
+	});
+    };
+
+    let mut current: Option<(String, Vec<(String, String, String)>)> = None;
+    let mut seq = String::new();
+    for line in reader.lines() {
+	let line = line.unwrap();
+	if line.starts_with('>') {
+	    if let Some((id, links)) = current.take() {
+		write_segment(&id, &seq, &links);
+	    }
+	    current = Some(parse_unitig_links(&line));
+	    seq.clear();
+	} else {
+	    seq.push_str(line.trim_end());
+	}
+    }
+    if let Some((id, links)) = current.take() {
+	write_segment(&id, &seq, &links);
+    }
+    writer.flush().unwrap();
+}
+
+fn write_graph_stats(graph_file: &String) {
+    // Only the unitig lengths are kept in memory
+    let lengths = fastx::contig_lengths(graph_file);
+    let mut writer = BufWriter::new(std::fs::File::create(graph_file.clone() + ".stats.tsv").unwrap());
+    writeln!(writer, "unitigs\ttotal_length\tn50\tmax_length").unwrap();
+    writeln!(writer, "{}\t{}\t{}\t{}", lengths.len(), lengths.iter().sum::<usize>(), fastx::n50(&lengths), lengths.iter().max().unwrap_or(&0)).unwrap();
+    writer.flush().unwrap();
+}
+
+fn count_open_descriptors() -> Option<usize> {
+    // Only available on Linux
+    return std::fs::read_dir("/proc/self/fd").ok().map(|x| x.count());
+}
+
+fn release_chunk(chunk_nr: usize, open_at_start: Option<usize>) {
+    // ggcat and the stdout redirection hold files while building, make sure
+    // nothing buffered keeps them open and report if descriptors are leaking
+    std::io::stdout().flush().unwrap();
+    std::io::stderr().flush().unwrap();
+    let open_now = count_open_descriptors();
+    debug!("{} file descriptors open after graph chunk {}", open_now.map_or("unknown".to_string(), |x| x.to_string()), chunk_nr + 1);
+    if open_at_start.is_some() && open_now.is_some_and(|x| x > 2*open_at_start.unwrap() + 64) {
+	warn!("Open file descriptors grew from {} to {} while building graphs, consider a lower --max-concurrent-graphs or raising ulimit -n",
+	      open_at_start.unwrap(), open_now.unwrap());
+    }
+}
+
+pub fn estimate_graph_memory(input_seq_names: &[String]) -> f64 {
+    // Returns the estimate in GB
+    let total_length: f64 = input_seq_names.iter().map(fastx::approx_sequence_length).sum();
+    return GGCAT_BYTES_PER_BASE*total_length / 1e9;
+}
+
+fn try_build_pangenome_graph(input_seq_names: &[String], prefix: &String, instance: &GGCATInstance, params: &GGCATParams) -> Result<(), String> {
+    if params.strict {
+	build_pangenome_graph(input_seq_names, prefix, instance, params);
+	return Ok(());
+    }
+
+    // ggcat panics on errors, catch them so the remaining clusters are built
+    return std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+	build_pangenome_graph(input_seq_names, prefix, instance, params)
+    })).map_err(|e| {
+	let message = if let Some(x) = e.downcast_ref::<&str>() {
+	    x.to_string()
+	} else if let Some(x) = e.downcast_ref::<String>() {
+	    x.clone()
+	} else {
+	    "unknown error".to_string()
+	};
+	error!("Building graph {} failed: {}", prefix, message);
+	message
+    });
+}
+
+pub fn select_members(input_seq_names: &[String], members_per_cluster: Option<usize>) -> Vec<String> {
+    // Keep the largest inputs, ties are broken by name
+    if members_per_cluster.map_or(true, |x| input_seq_names.len() <= x) {
+	return input_seq_names.to_vec();
+    }
+    let mut members: Vec<(f64, &String)> = input_seq_names.iter().map(|x| (fastx::approx_sequence_length(x), x)).collect();
+    members.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(b.1)));
+    return members.into_iter().take(members_per_cluster.unwrap().max(1)).map(|x| x.1.clone()).collect();
+}
+
+fn count_shared_unitigs(graph_file: &String, n_members: usize, instance: &GGCATInstance, params: &GGCATParams) -> (usize, usize, usize) {
+    // Unitigs in all members (core), in more than one but not all (accessory) and in one (unique)
+    let core = AtomicUsize::new(0);
+    let accessory = AtomicUsize::new(0);
+    let unique = AtomicUsize::new(0);
+
+    let mut buf = gag::BufferRedirect::stdout().unwrap();
+    instance.dump_unitigs(
+	graph_file,
+	params.kmer_size as usize,
+	params.minimizer_length,
+	true,
+	params.threads as usize,
+	false,
+	|_seq: &[u8], colors: &[ggcat_api::ColorIndexType], _same_colors: bool| {
+	    let n_colors = colors.iter().unique().count();
+	    if n_colors >= n_members {
+		core.fetch_add(1, AtomicOrdering::Relaxed);
+	    } else if n_colors > 1 {
+		accessory.fetch_add(1, AtomicOrdering::Relaxed);
+	    } else {
+		unique.fetch_add(1, AtomicOrdering::Relaxed);
+	    }
+	},
+    );
+    let mut output = String::new();
+    buf.read_to_string(&mut output).unwrap();
+    drop(buf);
+    for line in output.lines() {
+	trace!("{}", line);
+    }
+
+    return (core.into_inner(), accessory.into_inner(), unique.into_inner());
+}
+
+pub fn pangenome_summary(
     files_in_cluster: &HashMap<String, Vec<String>>,
     opt: &Option<GGCATParams>,
+    out_file: &String,
 ) {
+    // One line per built graph, the core/accessory split needs colored graphs
+    let params = opt.clone().unwrap_or(GGCATParams::default());
+    let instance = init_ggcat(opt);
+
+    let mut writer = BufWriter::new(std::fs::File::create(out_file).unwrap());
+    writeln!(writer, "cluster\tmembers\tunitigs\ttotal_length\tcore\taccessory\tunique").unwrap();
+    files_in_cluster.iter().sorted_by(|a, b| a.0.cmp(b.0)).for_each(|(cluster, seqs)| {
+	let graph_file = params.out_prefix.clone() + cluster;
+	if !std::path::Path::new(&graph_file).exists() {
+	    return;
+	}
+	let n_members = select_members(seqs, params.members_per_cluster).len();
+	let lengths = fastx::contig_lengths(&graph_file);
+	let colored = params.colors && std::path::Path::new(&(graph_file.clone() + ".colors.tsv")).exists();
+	let split = if colored {
+	    let (core, accessory, unique) = count_shared_unitigs(&graph_file, n_members, instance, &params);
+	    format!("{}\t{}\t{}", core, accessory, unique)
+	} else {
+	    "NA\tNA\tNA".to_string()
+	};
+	writeln!(writer, "{}\t{}\t{}\t{}\t{}", cluster, n_members, lengths.len(), lengths.iter().sum::<usize>(), split).unwrap();
+    });
+    writer.flush().unwrap();
+}
+
+fn clusters_to_build<'a>(
+    files_in_cluster: &'a HashMap<String, Vec<String>>,
+    params: &GGCATParams,
+) -> Vec<(&'a String, &'a Vec<String>)> {
+    // Clusters within the size bounds sorted by name, up to build_limit of them
+    let too_small = files_in_cluster.iter().filter(|x| x.1.len() < params.min_cluster_size).count();
+    let too_large = files_in_cluster.iter().filter(|x| params.max_cluster_size.is_some_and(|y| x.1.len() > y)).count();
+    if too_small > 0 {
+	info!("Skipped {} clusters with fewer than {} sequences", too_small, params.min_cluster_size);
+    }
+    if too_large > 0 {
+	info!("Skipped {} clusters with more than {} sequences", too_large, params.max_cluster_size.unwrap());
+    }
+
+    let mut to_build: Vec<(&String, &Vec<String>)> = files_in_cluster
+        .iter()
+	.filter(|x| x.1.len() >= params.min_cluster_size && params.max_cluster_size.map_or(true, |y| x.1.len() <= y))
+	.sorted_by(|a, b| a.0.cmp(b.0))
+	.collect();
+    if params.build_limit.is_some_and(|y| to_build.len() > y) {
+	info!("Reached --build-limit {}, skipping {} remaining graphs", params.build_limit.unwrap(), to_build.len() - params.build_limit.unwrap());
+	to_build.truncate(params.build_limit.unwrap());
+    }
+    return to_build;
+}
+
+pub fn build_pangenome_representations(
+    files_in_cluster: &HashMap<String, Vec<String>>,
+    opt: &Option<GGCATParams>,
+) -> (Vec<(String, f64)>, Vec<(String, String)>) {
     let params = opt.clone().unwrap_or(GGCATParams::default());
 
     let wrapped_params = Some(params.clone());
 
     let instance = init_ggcat(&wrapped_params);
 
-    let progress = if params.progress { ProgressBar::new(files_in_cluster.len() as u64) } else { ProgressBar::hidden() };
+    let to_build = clusters_to_build(files_in_cluster, &params);
+
+    let progress = if params.progress { ProgressBar::new(to_build.len() as u64) } else { ProgressBar::hidden() };
     progress.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}").unwrap());
     progress.set_message("graphs built");
 
-    files_in_cluster
-        .iter()
-	.filter(|x| x.1.len() > 1)
-        .for_each(|x| {
-	    build_pangenome_graph(x.1, x.0, &instance, &params);
+    // Clusters that were too large to build and their estimated memory use
+    let mut skipped: Vec<(String, f64)> = Vec::new();
+    // Clusters whose graph failed to build and the error
+    let mut failed: Vec<(String, String)> = Vec::new();
+    // Large runs have thousands of clusters, build them in chunks so that
+    // open files are released before they reach the ulimit
+    let open_at_start = count_open_descriptors();
+    to_build
+        .chunks(params.max_concurrent_graphs.max(1))
+	.enumerate()
+        .for_each(|(chunk_nr, chunk)| {
+	chunk.iter().for_each(|x| {
+	    let members = select_members(x.1, params.members_per_cluster);
+	    let estimate = estimate_graph_memory(&members);
+	    if params.max_ram_per_graph.is_some_and(|y| estimate > y) {
+		match params.oversized_graph.as_str() {
+		    "raise-multiplicity" => {
+			warn!("Building {} with --kmer-min-multiplicity {}, estimated {:.2} GB exceeds --max-ram-per-graph {}",
+			      x.0, params.fallback_min_multiplicity, estimate, params.max_ram_per_graph.unwrap());
+			let mut fallback_params = params.clone();
+			fallback_params.kmer_min_multiplicity = params.fallback_min_multiplicity;
+			if let Err(e) = try_build_pangenome_graph(&members, x.0, &instance, &fallback_params) {
+			    failed.push((x.0.clone(), e));
+			}
+		    },
+		    &_ => {
+			warn!("Skipped {}, estimated {:.2} GB exceeds --max-ram-per-graph {}",
+			      x.0, estimate, params.max_ram_per_graph.unwrap());
+			skipped.push((x.0.clone(), estimate));
+		    },
+		}
+	    } else if let Err(e) = try_build_pangenome_graph(&members, x.0, &instance, &params) {
+		failed.push((x.0.clone(), e));
+	    }
 	    progress.inc(1)
 	});
+	release_chunk(chunk_nr, open_at_start);
+    });
     progress.finish_and_clear();
+
+    return (skipped, failed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clusters_to_build_size_bounds() {
+	let files_in_cluster: HashMap<String, Vec<String>> = [
+	    ("single".to_string(), vec!["a.fa".to_string()]),
+	    ("small".to_string(), (0..3).map(|i| format!("{}.fa", i)).collect()),
+	    ("large".to_string(), (0..50).map(|i| format!("{}.fa", i)).collect()),
+	].into_iter().collect();
+	let params = GGCATParams { min_cluster_size: 2, max_cluster_size: Some(10), ..Default::default() };
+
+	let to_build = clusters_to_build(&files_in_cluster, &params);
+	assert_eq!(to_build.len(), 1);
+	assert_eq!(to_build[0].0, "small");
+	assert_eq!(to_build[0].1.len(), 3);
+    }
+
+    #[test]
+    fn clusters_to_build_two_genomes() {
+	// Two genomes in one cluster get a graph, as singletons they get none
+	let params = GGCATParams::default();
+	let merged: HashMap<String, Vec<String>> = [("a.fa".to_string(), vec!["a.fa".to_string(), "b.fa".to_string()])].into_iter().collect();
+	assert_eq!(clusters_to_build(&merged, &params).len(), 1);
+
+	let separate: HashMap<String, Vec<String>> = [
+	    ("a.fa".to_string(), vec!["a.fa".to_string()]),
+	    ("b.fa".to_string(), vec!["b.fa".to_string()]),
+	].into_iter().collect();
+	assert!(clusters_to_build(&separate, &params).is_empty());
+    }
+
+    #[test]
+    fn graph_is_complete_compares_inputs() {
+	let dir = std::env::temp_dir().join(format!("panaani-test-{}-graph", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let graph_file = dir.join("cluster.dbg.fasta");
+	let marker_file = dir.join("cluster.dbg.fasta.building");
+	let inputs_file = dir.join("cluster.dbg.fasta.inputs.tsv");
+	std::fs::write(&graph_file, ">0\nACGT\n").unwrap();
+
+	let params = GGCATParams::default();
+	let members: Vec<String> = vec!["a.fa".to_string(), "b.fa".to_string()];
+	let record = graph_inputs_record(&members, &params);
+	// Graphs from before the record was written are rebuilt
+	assert!(!graph_is_complete(&graph_file, &marker_file, &inputs_file, &record));
+
+	std::fs::write(&inputs_file, &record).unwrap();
+	let complete = graph_is_complete(&graph_file, &marker_file, &inputs_file, &record);
+	let other_members = graph_inputs_record(&["a.fa".to_string(), "c.fa".to_string()], &params);
+	let other_params = graph_inputs_record(&members, &GGCATParams { kmer_size: 21, ..Default::default() });
+	let changed = graph_is_complete(&graph_file, &marker_file, &inputs_file, &other_members)
+	    || graph_is_complete(&graph_file, &marker_file, &inputs_file, &other_params);
+	std::fs::remove_dir_all(&dir).unwrap();
+
+	assert!(complete);
+	assert!(!changed);
+    }
 }
@@ -6,7 +6,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(version)]
@@ -14,24 +14,295 @@ use clap::{Parser, Subcommand};
 pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Commands>,
+
+    // Provenance line at the top of results
+    #[arg(long = "header", default_value_t = false, global = true)]
+    pub header: bool,
+
+    // Resolve relative paths in input lists against the list's directory
+    #[arg(long = "paths-relative-to-list", default_value_t = false, global = true)]
+    pub paths_relative_to_list: bool,
+}
+
+// skani sketching parameters
+#[derive(Args, Debug)]
+pub struct SketchArgs {
+    #[arg(
+        long = "skani-kmer-size",
+        default_value_t = 15,
+        help_heading = "ANI estimation"
+    )]
+    pub skani_kmer_size: u8,
+
+    #[arg(
+        long = "kmer-subsampling-rate",
+        default_value_t = 30,
+        help_heading = "ANI estimation"
+    )]
+    pub kmer_subsampling_rate: u16,
+
+    #[arg(
+        long = "marker-compression-factor",
+        default_value_t = 1000,
+        help_heading = "ANI estimation"
+    )]
+    pub marker_compression_factor: u16,
+
+    // Contigs shorter than this are ignored
+    #[arg(
+        long = "min-contig-length",
+        default_value_t = 0,
+        help_heading = "ANI estimation"
+    )]
+    pub min_contig_length: usize,
+}
+
+// skani ANI estimation parameters
+#[derive(Args, Debug)]
+pub struct AniArgs {
+    #[command(flatten)]
+    pub sketch: SketchArgs,
+
+    #[arg(
+        long = "min-af",
+        default_value_t = 0.15,
+        help_heading = "ANI estimation"
+    )]
+    pub min_aligned_frac: f64,
+
+    // Override --min-af for the reference or the query of each pair. The
+    // reference is the lexicographically first file of the pair
+    #[arg(long = "min-af-ref", required = false, help_heading = "ANI estimation")]
+    pub min_aligned_frac_ref: Option<f64>,
+
+    #[arg(long = "min-af-query", required = false, help_heading = "ANI estimation")]
+    pub min_aligned_frac_query: Option<f64>,
+
+    #[arg(
+        long = "af-combine",
+        default_value = "any",
+        value_parser = ["any", "both", "mean"],
+        help_heading = "ANI estimation"
+    )]
+    pub af_combine: String,
+
+    #[arg(
+        long = "max-ani",
+        default_value_t = 1.0,
+        help_heading = "ANI estimation"
+    )]
+    pub max_ani: f32,
+
+    // Skip pairs whose markers show an ANI clearly below this, reported as 0
+    #[arg(long = "screen", required = false, help_heading = "ANI estimation")]
+    pub screen: Option<f64>,
+
+    #[arg(
+        long = "rescue-small",
+        default_value_t = false,
+        help_heading = "ANI estimation"
+    )]
+    pub rescue_small: bool,
+
+    #[arg(
+        long = "min-genome-length",
+        default_value_t = 0,
+        help_heading = "ANI estimation"
+    )]
+    pub min_genome_length: usize,
+
+    // Save sketches here and reuse them in later calls and runs
+    #[arg(
+        long = "sketch-cache",
+        required = false,
+        help_heading = "ANI estimation"
+    )]
+    pub sketch_cache_dir: Option<String>,
+
+    #[arg(
+        long = "clip-tails",
+        default_value_t = false,
+        help_heading = "ANI estimation"
+    )]
+    pub clip_tails: bool,
+
+    #[arg(
+        long = "median",
+        default_value_t = false,
+        help_heading = "ANI estimation"
+    )]
+    pub median: bool,
+
+    #[arg(
+        long = "adjust-ani",
+        default_value_t = false,
+        help_heading = "ANI estimation"
+    )]
+    pub adjust_ani: bool,
+}
+
+// Hierarchical clustering parameters
+#[derive(Args, Debug)]
+pub struct ClusteringArgs {
+    #[arg(
+        long = "ani-threshold",
+        conflicts_with = "num_clusters",
+        default_value_t = 0.97,
+        help_heading = "ANI clustering"
+    )]
+    pub ani_threshold: f32,
+
+    // Pairs exactly at --ani-threshold are not merged
+    #[arg(long = "exclusive-threshold", default_value_t = false, help_heading = "ANI clustering")]
+    pub exclusive_threshold: bool,
+
+    // Cut the final dendrogram into this many clusters instead
+    #[arg(long = "num-clusters", required = false, help_heading = "ANI clustering")]
+    pub num_clusters: Option<usize>,
+
+    #[arg(
+        long = "linkage-method",
+        required = false,
+        help_heading = "ANI clustering"
+    )]
+    pub linkage_method: Option<String>,
+
+    #[arg(
+        long = "distance-metric",
+        default_value = "ani",
+        value_parser = ["ani", "ani_af"],
+        help_heading = "ANI clustering"
+    )]
+    pub distance_metric: String,
+}
+
+// de Bruijn graph construction parameters
+#[derive(Args, Debug)]
+pub struct GraphArgs {
+    #[arg(
+        long = "ggcat-kmer-size",
+        default_value_t = 51,
+        help_heading = "Pangenome construction"
+    )]
+    pub ggcat_kmer_size: u32,
+
+    #[arg(
+        long = "min-kmer-count",
+        default_value_t = 1,
+        help_heading = "Pangenome construction"
+    )]
+    pub kmer_min_multiplicity: u64,
+
+    // Input and its own --min-kmer-count, applied as a pre-filter. Counts above 1 are for read sets
+    #[arg(
+        long = "multiplicity-map",
+        required = false,
+        help_heading = "Pangenome construction"
+    )]
+    pub multiplicity_map_file: Option<String>,
+
+    // Record which inputs each unitig is in, with a <graph>.colors.tsv of the input names
+    #[arg(long = "colors", default_value_t = false, help_heading = "Pangenome construction")]
+    pub colors: bool,
+
+    // Rebuild graphs that already exist from an earlier run
+    #[arg(long = "force-rebuild", default_value_t = false, help_heading = "Pangenome construction")]
+    pub force_rebuild: bool,
+
+    #[arg(
+        long = "minimzer-length",
+        required = false,
+        help_heading = "Pangenome construction"
+    )]
+    pub minimizer_length: Option<usize>,
+
+    #[arg(
+        long = "no-rc",
+        default_value_t = false,
+        help_heading = "Pangenome construction"
+    )]
+    pub no_reverse_complement: bool,
+
+    #[arg(
+        long = "unitig-type",
+        required = false,
+        help_heading = "Pangenome construction"
+    )]
+    pub unitig_type: Option<String>,
+
+    #[arg(
+        long = "intermediate-compression",
+        required = false,
+        help_heading = "Pangenome construction"
+    )]
+    pub intermediate_compression_level: Option<u32>,
 }
 
-#[derive(Subcommand)]
+#[derive(Subcommand, Debug)]
 pub enum Commands {
     // Print testing stuff
+    #[command(group(ArgGroup::new("input").required(true).multiple(true)))]
     Dereplicate {
         // Input files
-        #[arg(group = "input", required = true)]
+        #[arg(group = "input")]
         seq_files: Vec<String>,
 
 	// Input sequence list
-        #[arg(short = 'l', long = "input-list", group = "input", required = true)]
+        #[arg(short = 'l', long = "input-list", group = "input")]
         input_list: Option<String>,
 
+        // Inputs ending in .sketch are skani sketches of the sequences to cluster
+        #[arg(long = "refs-are-sketch", default_value_t = false)]
+        refs_are_sketch: bool,
+
 	// Outputs
         #[arg(short = 'o', long = "out-prefix", required = false, help_heading = "Output")]
         out_prefix: Option<String>,
 
+        // Results are printed to stdout if unset
+        #[arg(short = 'O', long = "output-file", required = false, help_heading = "Output")]
+        output_file: Option<String>,
+
+        #[arg(long = "cluster-prefix", default_value = "panANI-", help_heading = "Output")]
+        cluster_prefix: String,
+
+        #[arg(long = "format", default_value = "tsv", value_parser = ["tsv", "drep", "partition"], help_heading = "Output")]
+        output_format: String,
+
+        #[arg(long = "cluster-history", required = false, help_heading = "Output")]
+        cluster_history_file: Option<String>,
+
+        // Sequences whose cluster is unchanged for --finalized-after iterations and the
+        // first member of the cluster, appended while running
+        #[arg(long = "emit-finalized", required = false, help_heading = "Output")]
+        emit_finalized_file: Option<String>,
+
+        #[arg(long = "finalized-after", default_value_t = 2, requires = "emit_finalized_file", help_heading = "Output")]
+        finalized_after: usize,
+
+        // Lowest ANI within each final cluster
+        #[arg(long = "merge-heights", required = false, help_heading = "Output")]
+        merge_heights_file: Option<String>,
+
+        // Run summary for workflow managers
+        #[arg(long = "summary-json", required = false, help_heading = "Output")]
+        summary_json_file: Option<String>,
+
+        // Largest members used for each graph, all members if unset
+        #[arg(long = "members-per-cluster", required = false, help_heading = "Pangenome construction")]
+        members_per_cluster: Option<usize>,
+
+        // Only build the first k final graphs, for testing
+        #[arg(long = "build-limit", required = false, help_heading = "Pangenome construction")]
+        build_limit: Option<usize>,
+
+        // Graphs built before releasing open files, lower this on "Too many open files"
+        #[arg(long = "max-concurrent-graphs", default_value_t = 256, help_heading = "Pangenome construction")]
+        max_concurrent_graphs: usize,
+
+        #[arg(long = "used-members", required = false, help_heading = "Output")]
+        used_members_file: Option<String>,
+
         // Resources
         #[arg(short = 't', long = "threads", default_value_t = 1)]
         threads: u32,
@@ -42,6 +313,30 @@ pub enum Commands {
         #[arg(long = "tmp-dir", required = false)]
         temp_dir_path: Option<String>,
 
+        // Keep the intermediate graphs, named <iteration>_<batch>- instead of randomly
+        #[arg(long = "keep-temp", default_value_t = false)]
+        keep_temp: bool,
+
+        // Keep the decompressed copies of .gz and .zst inputs in --tmp-dir
+        #[arg(long = "keep-decompressed", default_value_t = false)]
+        keep_decompressed: bool,
+
+        // Sorted ANI results beyond this many pairs are spilled to --tmp-dir
+        #[arg(long = "max-pairs-in-memory", required = false)]
+        max_pairs_in_memory: Option<usize>,
+
+        // How the intermediate graphs are named, random avoids collisions between runs
+        #[arg(long = "temp-naming", default_value = "random", value_parser = ["random", "sequential", "cluster-based"])]
+        temp_naming: String,
+
+        // Fixed seed and sorted batches so that reruns produce identical output
+        #[arg(long = "reproducible", default_value_t = false)]
+        reproducible: bool,
+
+        // Like --reproducible with this seed for the intermediate graph names
+        #[arg(long = "seed", required = false)]
+        seed: Option<u64>,
+
         // Dereplicate parameters
         #[arg(
             short = 'b',
@@ -72,227 +367,361 @@ pub enum Commands {
         )]
         external_clustering_file: Option<String>,
 
-        #[arg(
-            long = "max-iters",
-            default_value_t = 10,
+	// The second column of --input-list is the initial cluster of each input instead of a sketch
+	#[arg(
+            long = "list-has-clusters",
+	    default_value_t = false,
+	    requires = "input_list",
+	    conflicts_with = "collapse_identical",
             help_heading = "Dereplication"
         )]
-        max_iters: usize,
+        list_has_clusters: bool,
 
+	// Replace inputs above this ANI and aligned fraction with the largest one
 	#[arg(
-            long = "guided",
-            default_value_t = false,
+            long = "collapse-identical",
+	    required = false,
+	    conflicts_with_all = ["external_clustering_file", "initial_batches_file"],
             help_heading = "Dereplication"
         )]
-        guided_batching: bool,
+        collapse_identical: Option<f32>,
 
-        #[arg(long = "verbose", default_value_t = false)]
-        verbose: bool,
-
-        // ANI estimation parameters
-        #[arg(
-            long = "skani-kmer-size",
-            default_value_t = 15,
-            help_heading = "ANI estimation"
-        )]
-        skani_kmer_size: u8,
-
-        #[arg(
-            long = "kmer-subsampling-rate",
-            default_value_t = 30,
-            help_heading = "ANI estimation"
+	#[arg(
+            long = "collapsed-out",
+	    required = false,
+	    requires = "collapse_identical",
+            help_heading = "Output"
         )]
-        kmer_subsampling_rate: u16,
+        collapsed_file: Option<String>,
 
         #[arg(
-            long = "marker-compression-factor",
-            default_value_t = 1000,
-            help_heading = "ANI estimation"
+            long = "max-iters",
+            default_value_t = 10,
+            help_heading = "Dereplication"
         )]
-        marker_compression_factor: u16,
+        max_iters: usize,
 
+        // Batched iterations to run before stopping on unchanged clusters
         #[arg(
-            long = "min-af",
-            default_value_t = 0.15,
-            help_heading = "ANI estimation"
+            long = "min-iters",
+            default_value_t = 2,
+            help_heading = "Dereplication"
         )]
-        min_aligned_frac: f64,
+        min_iters: usize,
 
+        // Write the clusters after each iteration to <dir>/iter_<n>.tsv
         #[arg(
-            long = "rescue-small",
-            default_value_t = false,
-            help_heading = "ANI estimation"
+            long = "checkpoint-dir",
+            required = false,
+            help_heading = "Dereplication"
         )]
-        rescue_small: bool,
+        checkpoint_dir: Option<String>,
 
+        // Continue an interrupted run from its --checkpoint-dir
         #[arg(
-            long = "clip-tails",
-            default_value_t = false,
-            help_heading = "ANI estimation"
+            long = "resume-from",
+            required = false,
+            help_heading = "Dereplication"
         )]
-        clip_tails: bool,
+        resume_from: Option<String>,
 
+        // Run the batched iterations even if the clusters stop changing
         #[arg(
-            long = "median",
+            long = "no-early-stop",
             default_value_t = false,
-            help_heading = "ANI estimation"
+            help_heading = "Dereplication"
         )]
-        median: bool,
+        no_early_stop: bool,
 
-        #[arg(
-            long = "adjust-ani",
+	#[arg(
+            long = "guided",
             default_value_t = false,
-            help_heading = "ANI estimation"
+            help_heading = "Dereplication"
         )]
-        adjust_ani: bool,
+        guided_batching: bool,
 
-        // Clustering parameters
-        #[arg(
-            long = "ani-threshold",
-            default_value_t = 0.97,
-            help_heading = "ANI clustering"
+        // Coarse clustering used by --guided, defaults to the main values
+	#[arg(
+            long = "guide-ani-threshold",
+            required = false,
+            help_heading = "Dereplication"
         )]
-        ani_threshold: f32,
+        guide_ani_threshold: Option<f32>,
 
-        #[arg(
-            long = "linkage-method",
+	#[arg(
+            long = "guide-linkage-method",
             required = false,
-            help_heading = "ANI clustering"
+            help_heading = "Dereplication"
         )]
-        linkage_method: Option<String>,
+        guide_linkage_method: Option<String>,
 
-        // de Bruijn graph construction parameters
-        #[arg(
-            long = "ggcat-kmer-size",
-            default_value_t = 51,
-            help_heading = "Pangenome construction"
+	// Threads for the guide ANI pass, defaults to --threads
+	#[arg(
+            long = "guide-threads",
+            required = false,
+            help_heading = "Dereplication"
         )]
-        ggcat_kmer_size: u32,
+        guide_threads: Option<usize>,
 
-        #[arg(
-            long = "min-kmer-count",
-            default_value_t = 1,
-            help_heading = "Pangenome construction"
+	// Coarse sketching resolution of the guide ANI pass, higher is faster and coarser
+	#[arg(
+            long = "guide-subsampling",
+            default_value_t = 2500,
+            help_heading = "Dereplication"
         )]
-        kmer_min_multiplicity: u64,
+        guide_subsampling: u16,
 
-        #[arg(
-            long = "minimzer-length",
-            required = false,
-            help_heading = "Pangenome construction"
+	#[arg(
+            long = "guide-compression",
+            default_value_t = 2500,
+            help_heading = "Dereplication"
         )]
-        minimizer_length: Option<usize>,
+        guide_compression: u16,
 
-        #[arg(
-            long = "no-rc",
+	#[arg(
+            long = "auto-screen",
             default_value_t = false,
-            help_heading = "Pangenome construction"
+	    conflicts_with = "screen",
+            help_heading = "Dereplication"
         )]
-        no_reverse_complement: bool,
+        auto_screen: bool,
 
-        #[arg(
-            long = "unitig-type",
-            required = false,
-            help_heading = "Pangenome construction"
-        )]
-        unitig_type: Option<String>,
+        #[arg(long = "verbose", default_value_t = false)]
+        verbose: bool,
+
+        #[arg(long = "no-progress", default_value_t = false)]
+        no_progress: bool,
+
+        #[command(flatten)]
+        ani: AniArgs,
+
+        #[command(flatten)]
+        clustering: ClusteringArgs,
 
         #[arg(
-            long = "intermediate-compression",
+            long = "threshold-sweep",
             required = false,
-            help_heading = "Pangenome construction"
+            value_delimiter = ',',
+            help_heading = "ANI clustering"
         )]
-        intermediate_compression_level: Option<u32>,
+        threshold_sweep: Option<Vec<f32>>,
+
+        #[command(flatten)]
+        graph: GraphArgs,
     },
 
-    Dist {
+    #[command(group(ArgGroup::new("input").required(true).multiple(true)))]
+    Refine {
         // Input files
-        #[arg(group = "input", required = true)]
+        #[arg(group = "input")]
         seq_files: Vec<String>,
 
 	// Input sequence list
-        #[arg(short = 'l', long = "input-list", group = "input", required = true)]
+        #[arg(short = 'l', long = "input-list", group = "input")]
         input_list: Option<String>,
 
+        // Inputs ending in .sketch are skani sketches of the sequences to cluster
+        #[arg(long = "refs-are-sketch", default_value_t = false)]
+        refs_are_sketch: bool,
+
+	// Outputs
+        #[arg(short = 'o', long = "out-prefix", required = false, help_heading = "Output")]
+        out_prefix: Option<String>,
+
+        // Results are printed to stdout if unset
+        #[arg(short = 'O', long = "output-file", required = false, help_heading = "Output")]
+        output_file: Option<String>,
+
+        #[arg(long = "cluster-prefix", default_value = "panANI-", help_heading = "Output")]
+        cluster_prefix: String,
+
+        #[arg(long = "format", default_value = "tsv", value_parser = ["tsv", "drep", "partition"], help_heading = "Output")]
+        output_format: String,
+
         // Resources
         #[arg(short = 't', long = "threads", default_value_t = 1)]
         threads: u32,
 
+        #[arg(short = 'm', long = "memory", default_value_t = 4)]
+        memory: u32,
+
+        #[arg(long = "tmp-dir", required = false)]
+        temp_dir_path: Option<String>,
+
+        // Dereplicate parameters
+	#[arg(
+            long = "external-clustering",
+	    required = true,
+            help_heading = "Dereplication"
+        )]
+        external_clustering_file: Option<String>,
+
         #[arg(long = "verbose", default_value_t = false)]
         verbose: bool,
 
-        // ANI estimation parameters
-        #[arg(
-            long = "skani-kmer-size",
-            default_value_t = 15,
-            help_heading = "ANI estimation"
-        )]
-        skani_kmer_size: u8,
+        #[arg(long = "no-progress", default_value_t = false)]
+        no_progress: bool,
 
-        #[arg(
-            long = "kmer-subsampling-rate",
-            default_value_t = 30,
-            help_heading = "ANI estimation"
-        )]
-        kmer_subsampling_rate: u16,
+        #[command(flatten)]
+        ani: AniArgs,
 
-        #[arg(
-            long = "marker-compression-factor",
-            default_value_t = 1000,
-            help_heading = "ANI estimation"
-        )]
-        marker_compression_factor: u16,
+        #[command(flatten)]
+        clustering: ClusteringArgs,
 
-        #[arg(
-            long = "min-af",
-            default_value_t = 0.15,
-            help_heading = "ANI estimation"
-        )]
-        min_aligned_frac: f64,
+        #[command(flatten)]
+        graph: GraphArgs,
+    },
 
-        #[arg(
-            long = "rescue-small",
-            default_value_t = false,
-            help_heading = "ANI estimation"
-        )]
-        rescue_small: bool,
+    #[command(group(ArgGroup::new("input").required(true).multiple(true)))]
+    Dist {
+        // Input files
+        #[arg(group = "input")]
+        seq_files: Vec<String>,
 
-        #[arg(
-            long = "clip-tails",
-            default_value_t = false,
-            help_heading = "ANI estimation"
-        )]
-        clip_tails: bool,
+	// Input sequence list
+        #[arg(short = 'l', long = "input-list", group = "input")]
+        input_list: Option<String>,
 
-        #[arg(
-            long = "median",
-            default_value_t = false,
-            help_heading = "ANI estimation"
-        )]
-        median: bool,
+        // Inputs ending in .sketch are skani sketches
+        #[arg(long = "refs-are-sketch", default_value_t = false)]
+        refs_are_sketch: bool,
 
-        #[arg(
-            long = "adjust-ani",
-            default_value_t = false,
-            help_heading = "ANI estimation"
-        )]
-        adjust_ani: bool,
+        // Results are printed to stdout if unset
+        #[arg(short = 'O', long = "output-file", required = false)]
+        output_file: Option<String>,
+
+        #[arg(long = "pairs", required = false)]
+        pairs_file: Option<String>,
+        // Estimate a->b and b->a and report both, NA if either could not be estimated
+        #[arg(long = "both-directions", default_value_t = false)]
+        both_directions: bool,
+
+        // Add a line with ANI 1.0 for each input against itself
+        #[arg(long = "include-self", default_value_t = false, conflicts_with_all = ["both_directions", "matrix_format"])]
+        include_self: bool,
+
+        // Condensed matrix for `cluster --binary-input`
+        #[arg(long = "binary-output", required = false, conflicts_with = "both_directions")]
+        binary_output_file: Option<String>,
+
+        // Print a square matrix instead of one line per pair
+        #[arg(long = "matrix-format", required = false, value_parser = ["phylip"], conflicts_with_all = ["both_directions", "with_header"])]
+        matrix_format: Option<String>,
+
+        // Write 1 - ANI in the matrix
+        #[arg(long = "matrix-distance", default_value_t = false, requires = "matrix_format")]
+        matrix_distance: bool,
+
+        // Value for pairs without an ANI, 0.0 or 1.0 with --matrix-distance if unset
+        #[arg(long = "matrix-fill", required = false, requires = "matrix_format")]
+        matrix_fill: Option<f32>,
+
+        // Column names on the first line, read back with `cluster --has-header`
+        #[arg(long = "with-header", default_value_t = false)]
+        with_header: bool,
+
+        // Print the aligned fractions and confidence interval after the ANI
+        #[arg(long = "detailed", default_value_t = false, conflicts_with_all = ["matrix_format", "both_directions", "binary_output_file"])]
+        detailed: bool,
+
+        // Bootstrap a confidence interval for the ANI, printed with --detailed
+        #[arg(long = "bootstrap-ci", default_value_t = false)]
+        bootstrap_ci: bool,
+
+        // skani's detailed alignment statistics, slower and only for diagnostics
+        #[arg(long = "detailed-out", default_value_t = false, requires = "detailed_file")]
+        detailed_out: bool,
+
+        #[arg(long = "detailed-file", required = false, requires = "detailed_out")]
+        detailed_file: Option<String>,
+
+        // Resources
+        #[arg(short = 't', long = "threads", default_value_t = 1)]
+        threads: u32,
+
+        #[arg(short = 'm', long = "memory", default_value_t = 4)]
+        memory: u32,
+
+        // Contigs filtered with --min-contig-length and decompressed inputs are written here
+        #[arg(long = "tmp-dir", required = false)]
+        temp_dir_path: Option<String>,
+
+        #[arg(long = "keep-temp", default_value_t = false)]
+        keep_temp: bool,
+
+        // Keep the decompressed copies of .gz and .zst inputs in --tmp-dir
+        #[arg(long = "keep-decompressed", default_value_t = false)]
+        keep_decompressed: bool,
+
+        // Sorted ANI results beyond this many pairs are spilled to --tmp-dir
+        #[arg(long = "max-pairs-in-memory", required = false)]
+        max_pairs_in_memory: Option<usize>,
+
+        #[arg(long = "verbose", default_value_t = false)]
+        verbose: bool,
+
+        #[arg(long = "no-progress", default_value_t = false)]
+        no_progress: bool,
+
+        #[command(flatten)]
+        ani: AniArgs,
     },
+    #[command(group(ArgGroup::new("input").multiple(true)))]
     Build {
-        // Input files
-        #[arg(group = "input", required = true)]
+        // Input files, all sequences in --external-clustering are used if empty
+        #[arg(group = "input")]
         seq_files: Vec<String>,
 	
 	// Input sequence list
-        #[arg(short = 'l', long = "input-list", group = "input", required = true)]
+        #[arg(short = 'l', long = "input-list", group = "input")]
         input_list: Option<String>,
 
+        // Accepts `dereplicate` output as-is
         #[arg(long = "external-clustering", required = true, help_heading = "Input")]
         external_clustering_file: Option<String>,
 
 	#[arg(long = "target", required = false, help_heading = "Input")]
         target_cluster: Option<String>,
 
+        #[arg(long = "min-cluster-size", default_value_t = 2, help_heading = "Pangenome construction")]
+        min_cluster_size: usize,
+
+        #[arg(long = "max-cluster-size", required = false, help_heading = "Pangenome construction")]
+        max_cluster_size: Option<usize>,
+
+        // Estimated from the input sizes, in GB
+        #[arg(long = "max-ram-per-graph", required = false, help_heading = "Pangenome construction")]
+        max_ram_per_graph: Option<f64>,
+
+        #[arg(long = "oversized-graph", default_value = "skip", value_parser = ["skip", "raise-multiplicity"], help_heading = "Pangenome construction")]
+        oversized_graph: String,
+
+        #[arg(long = "fallback-min-multiplicity", default_value_t = 2, help_heading = "Pangenome construction")]
+        fallback_min_multiplicity: u64,
+
+        // Clusters skipped by --max-ram-per-graph
+        #[arg(long = "skipped-graphs", required = false, help_heading = "Output")]
+        skipped_graphs_file: Option<String>,
+
+        // Stop at the first failed graph instead of writing failed_clusters.tsv
+        #[arg(long = "strict", default_value_t = false)]
+        strict: bool,
+
+        // Largest members used for each graph, all members if unset
+        #[arg(long = "members-per-cluster", required = false, help_heading = "Pangenome construction")]
+        members_per_cluster: Option<usize>,
+
+        // Only build the first k final graphs, for testing
+        #[arg(long = "build-limit", required = false, help_heading = "Pangenome construction")]
+        build_limit: Option<usize>,
+
+        // Graphs built before releasing open files, lower this on "Too many open files"
+        #[arg(long = "max-concurrent-graphs", default_value_t = 256, help_heading = "Pangenome construction")]
+        max_concurrent_graphs: usize,
+
+        #[arg(long = "used-members", required = false, help_heading = "Output")]
+        used_members_file: Option<String>,
+
 	// Outputs
         #[arg(short = 'o', long = "out-prefix", required = false, help_heading = "Output")]
         out_prefix: Option<String>,
@@ -307,90 +736,207 @@ pub enum Commands {
         #[arg(long = "tmp-dir", required = false)]
         temp_dir_path: Option<String>,
 
+        // Keep the decompressed copies of .gz and .zst inputs in --tmp-dir
+        #[arg(long = "keep-decompressed", default_value_t = false)]
+        keep_decompressed: bool,
+
         #[arg(long = "verbose", default_value_t = false)]
         verbose: bool,
 
-        // de Bruijn graph construction parameters
-        #[arg(
-            long = "ggcat-kmer-size",
-            default_value_t = 51,
-            help_heading = "Pangenome construction"
-        )]
-        ggcat_kmer_size: u32,
+        #[arg(long = "no-progress", default_value_t = false)]
+        no_progress: bool,
 
-        #[arg(
-            long = "min-kmer-count",
-            default_value_t = 1,
-            help_heading = "Pangenome construction"
-        )]
-        kmer_min_multiplicity: u64,
+        // Number of unitigs, total length, N50 and longest unitig in <graph>.stats.tsv
+        #[arg(long = "graph-stats", default_value_t = false, help_heading = "Pangenome construction")]
+        graph_stats: bool,
 
-        #[arg(
-            long = "minimzer-length",
-            required = false,
-            help_heading = "Pangenome construction"
-        )]
-        minimizer_length: Option<usize>,
+        // Also write the unitigs and the links between them as <graph>.gfa
+        #[arg(long = "gfa", default_value_t = false, conflicts_with = "unitig_type", help_heading = "Pangenome construction")]
+        gfa: bool,
 
-        #[arg(
-            long = "no-rc",
-            default_value_t = false,
-            help_heading = "Pangenome construction"
-        )]
-        no_reverse_complement: bool,
+        // Number of core, accessory and unique unitigs per cluster in <out_prefix>pangenome_summary.tsv
+        #[arg(long = "pangenome-summary", default_value_t = false, help_heading = "Pangenome construction")]
+        pangenome_summary: bool,
 
-        #[arg(
-            long = "unitig-type",
-            required = false,
-            help_heading = "Pangenome construction"
-        )]
-        unitig_type: Option<String>,
-
-        #[arg(
-            long = "intermediate-compression",
-            required = false,
-            help_heading = "Pangenome construction"
-        )]
-        intermediate_compression_level: Option<u32>,
+        #[command(flatten)]
+        graph: GraphArgs,
     },
     Cluster {
-        #[arg(group = "input")]
-        dist_file: String,
+        #[arg(group = "input", required = true)]
+        dist_file: Option<String>,
+
+        #[arg(long = "fastani-input", group = "input", required = true, help_heading = "Input")]
+        fastani_file: Option<String>,
+
+        #[arg(long = "mash-input", group = "input", required = true, help_heading = "Input")]
+        mash_file: Option<String>,
+
+        // Output from `dist --binary-output`
+        #[arg(long = "binary-input", group = "input", required = true, help_heading = "Input")]
+        binary_file: Option<String>,
+
+        // Output from --save-dendrogram, skips the linkage step
+        #[arg(long = "dendrogram", group = "input", required = true, help_heading = "Input")]
+        dendrogram_file: Option<String>,
+
+        // Directory of .sketch files, ANI is computed before clustering
+        #[arg(long = "from-sketches", group = "input", required = true, help_heading = "Input")]
+        sketch_dir: Option<String>,
+
+        // Third column of the distance file is a distance, --ani-threshold is the maximum distance
+        #[arg(long = "input-is-distance", default_value_t = false, conflicts_with_all = ["fastani_file", "mash_file", "binary_file", "sketch_dir"], help_heading = "Input")]
+        input_is_distance: bool,
+
+        // First line of the distance file has column names
+        #[arg(long = "has-header", default_value_t = false, conflicts_with_all = ["fastani_file", "mash_file", "binary_file", "sketch_dir", "dendrogram_file"], help_heading = "Input")]
+        has_header: bool,
+
+        // Distance (1 - ANI) of pairs missing from a sparse distance or fastANI file
+        #[arg(long = "missing-distance", default_value_t = 1.0, conflicts_with_all = ["mash_file", "binary_file", "sketch_dir", "dendrogram_file"], help_heading = "Input")]
+        missing_distance: f32,
 
 	// Outputs
         #[arg(short = 'o', long = "out-prefix", required = false, help_heading = "Output")]
         out_prefix: Option<String>,
 
+        // Results are printed to stdout if unset
+        #[arg(short = 'O', long = "output-file", required = false, help_heading = "Output")]
+        output_file: Option<String>,
+
+        #[arg(long = "cluster-prefix", default_value = "panANI-", help_heading = "Output")]
+        cluster_prefix: String,
+
+        #[arg(long = "format", default_value = "tsv", value_parser = ["tsv", "drep", "partition"], help_heading = "Output")]
+        output_format: String,
+
+        #[arg(long = "save-dendrogram", required = false, help_heading = "Output")]
+        save_dendrogram_file: Option<String>,
+
+        // Linkage tree in Newick format with the sequence names as leaves
+        #[arg(long = "newick", required = false, help_heading = "Output")]
+        newick_file: Option<String>,
+
+        // Resources
+        #[arg(short = 't', long = "threads", default_value_t = 1)]
+        threads: u32,
+
         #[arg(long = "verbose", default_value_t = false)]
         verbose: bool,
 
+        #[arg(long = "no-progress", default_value_t = false)]
+        no_progress: bool,
+
         // Clustering parameters
         #[arg(
             long = "ani-threshold",
+            conflicts_with = "num_clusters",
             default_value_t = 0.97,
             help_heading = "ANI estimation"
         )]
         ani_threshold: f32,
 
+        // Pairs exactly at --ani-threshold are not merged
+        #[arg(long = "exclusive-threshold", default_value_t = false, help_heading = "ANI clustering")]
+        exclusive_threshold: bool,
+
+        // Cut the dendrogram into this many clusters instead
+        #[arg(long = "num-clusters", required = false, conflicts_with_all = ["primary_ani", "secondary_ani"], help_heading = "ANI clustering")]
+        num_clusters: Option<usize>,
+
+        // Two-level clustering, prints primary and secondary clusters instead
+        #[arg(long = "primary-ani", required = false, requires = "secondary_ani", help_heading = "ANI clustering")]
+        primary_ani: Option<f32>,
+
+        #[arg(long = "secondary-ani", required = false, requires = "primary_ani", help_heading = "ANI clustering")]
+        secondary_ani: Option<f32>,
+
         #[arg(
             long = "linkage-method",
             required = false,
             help_heading = "ANI estimation"
         )]
         linkage_method: Option<String>,
+
+        // ANI estimation parameters for --from-sketches
+        #[arg(
+            long = "skani-kmer-size",
+            default_value_t = 15,
+            help_heading = "ANI estimation"
+        )]
+        skani_kmer_size: u8,
+
+        #[arg(
+            long = "kmer-subsampling-rate",
+            default_value_t = 30,
+            help_heading = "ANI estimation"
+        )]
+        kmer_subsampling_rate: u16,
+
+        #[arg(
+            long = "marker-compression-factor",
+            default_value_t = 1000,
+            help_heading = "ANI estimation"
+        )]
+        marker_compression_factor: u16,
+
+        #[arg(
+            long = "min-af",
+            default_value_t = 0.15,
+            help_heading = "ANI estimation"
+        )]
+        min_aligned_frac: f64,
+
+        // Override --min-af for the reference or the query of each pair
+        #[arg(long = "min-af-ref", required = false, help_heading = "ANI estimation")]
+        min_aligned_frac_ref: Option<f64>,
+
+        #[arg(long = "min-af-query", required = false, help_heading = "ANI estimation")]
+        min_aligned_frac_query: Option<f64>,
+
+        #[arg(
+            long = "af-combine",
+            default_value = "any",
+            value_parser = ["any", "both", "mean"],
+            help_heading = "ANI estimation"
+        )]
+        af_combine: String,
     },
+    #[command(group(ArgGroup::new("input").required(true).multiple(true)))]
     Assign {
         // Input files
-        #[arg(group = "input", required = true)]
+        #[arg(group = "input")]
         query_files: Vec<String>,
 
 	// Input sequence list
-        #[arg(short = 'l', long = "input-list", group = "input", required = true, help_heading = "Input")]
+        #[arg(short = 'l', long = "input-list", group = "input", help_heading = "Input")]
         query_files_list: Option<String>,
 
         #[arg(short = 'r', long = "ref-list", required = true, help_heading = "Input")]
         ref_files_list: Option<String>,
 
+        // Reference and its ANI threshold, overrides --ani-threshold
+        #[arg(long = "ref-thresholds", required = false, help_heading = "Input")]
+        ref_thresholds_file: Option<String>,
+
+        #[arg(long = "query-batch-size", required = false, help_heading = "Input")]
+        query_batch_size: Option<usize>,
+
+        // Outputs
+        #[arg(long = "dedup-queries", required = false, help_heading = "Output")]
+        dedup_queries_file: Option<String>,
+
+        // Results are printed to stdout if unset
+        #[arg(short = 'O', long = "output-file", required = false, help_heading = "Output")]
+        output_file: Option<String>,
+
+        // All query-reference ANIs in `dist` format
+        #[arg(long = "dist-out", required = false, help_heading = "Output")]
+        dist_out_file: Option<String>,
+
+        // Only the closest references of each query in --dist-out
+        #[arg(long = "top-k", required = false, requires = "dist_out_file", help_heading = "Output")]
+        top_k: Option<usize>,
+
         // Resources
         #[arg(short = 't', long = "threads", default_value_t = 1)]
         threads: u32,
@@ -398,6 +944,9 @@ pub enum Commands {
         #[arg(long = "verbose", default_value_t = false)]
         verbose: bool,
 
+        #[arg(long = "no-progress", default_value_t = false)]
+        no_progress: bool,
+
         // ANI estimation parameters
         #[arg(
             long = "skani-kmer-size",
@@ -427,6 +976,21 @@ pub enum Commands {
         )]
         min_aligned_frac: f64,
 
+        // Override --min-af for the reference or the query of each pair
+        #[arg(long = "min-af-ref", required = false, help_heading = "ANI estimation")]
+        min_aligned_frac_ref: Option<f64>,
+
+        #[arg(long = "min-af-query", required = false, help_heading = "ANI estimation")]
+        min_aligned_frac_query: Option<f64>,
+
+        #[arg(
+            long = "af-combine",
+            default_value = "any",
+            value_parser = ["any", "both", "mean"],
+            help_heading = "ANI estimation"
+        )]
+        af_combine: String,
+
         #[arg(
             long = "rescue-small",
             default_value_t = false,
@@ -455,6 +1019,86 @@ pub enum Commands {
         )]
         adjust_ani: bool,
 
+        #[arg(
+            long = "keep-refs",
+            default_value_t = false,
+            help_heading = "ANI estimation"
+        )]
+        keep_refs: bool,
+
+	// Clustering parameters
+	#[arg(
+            long = "ani-threshold",
+            default_value_t = 0.97,
+            help_heading = "ANI clustering"
+	)]
+	ani_threshold: f32,
+
+    },
+    #[command(group(ArgGroup::new("input").required(true).multiple(true)))]
+    Sketch {
+        // Input files
+        #[arg(group = "input")]
+        seq_files: Vec<String>,
+
+	// Input sequence list
+        #[arg(short = 'l', long = "input-list", group = "input")]
+        input_list: Option<String>,
+
+        // One <input file name>.sketch per input is written here
+        #[arg(short = 'o', long = "out-dir", required = true)]
+        out_dir: String,
+
+        // Resources
+        #[arg(short = 't', long = "threads", default_value_t = 1)]
+        threads: u32,
+
+        // Decompressed inputs are written here
+        #[arg(long = "tmp-dir", required = false)]
+        temp_dir_path: Option<String>,
+
+        #[arg(long = "verbose", default_value_t = false)]
+        verbose: bool,
+
+        #[arg(long = "no-progress", default_value_t = false)]
+        no_progress: bool,
+
+        #[command(flatten)]
+        sketch: SketchArgs,
+    },
+    #[command(group(ArgGroup::new("input").required(true).multiple(true)))]
+    Seqstats {
+        // Input files
+        #[arg(group = "input")]
+        seq_files: Vec<String>,
+
+	// Input sequence list
+        #[arg(short = 'l', long = "input-list", group = "input")]
+        input_list: Option<String>,
+
+        // Resources
+        #[arg(short = 't', long = "threads", default_value_t = 1)]
+        threads: u32,
+
+        #[arg(long = "verbose", default_value_t = false)]
+        verbose: bool,
+    },
+    Validate {
+        // Output from `dereplicate`, `refine`, or `cluster`
+        #[arg(long = "clusters", required = true, help_heading = "Input")]
+        clusters_file: String,
+
+        // Output from `dist` or `skani dist`
+        #[arg(long = "dist", required = true, help_heading = "Input")]
+        dist_file: String,
+
+        // First line of the distance file has column names, as in `skani dist` or `dist --with-header`
+        #[arg(long = "has-header", default_value_t = false, help_heading = "Input")]
+        has_header: bool,
+
+        #[arg(long = "verbose", default_value_t = false)]
+        verbose: bool,
+
 	// Clustering parameters
 	#[arg(
             long = "ani-threshold",
@@ -462,6 +1106,54 @@ pub enum Commands {
             help_heading = "ANI clustering"
 	)]
 	ani_threshold: f32,
+    },
+    Merge {
+        // Outputs from `dereplicate`, `refine`, or `cluster`
+        #[arg(required = true, help_heading = "Input")]
+        assignment_files: Vec<String>,
+
+        // Prefix added to the cluster names from each file, defaults to
+        // the file name without extension followed by '_'
+        #[arg(long = "prefixes", value_delimiter = ',', required = false, help_heading = "Output")]
+        prefixes: Option<Vec<String>>,
+
+        // Name the merged clusters <cluster-prefix>0, <cluster-prefix>1, ...
+        #[arg(long = "renumber", default_value_t = false, help_heading = "Output")]
+        renumber: bool,
+
+        #[arg(long = "cluster-prefix", default_value = "panANI-", help_heading = "Output")]
+        cluster_prefix: String,
+
+        // Results are printed to stdout if unset
+        #[arg(short = 'O', long = "output-file", required = false, help_heading = "Output")]
+        output_file: Option<String>,
+
+        #[arg(long = "format", default_value = "tsv", value_parser = ["tsv", "drep", "partition"], help_heading = "Output")]
+        output_format: String,
+
+        // Merge clusters from different files whose largest members are
+        // above --ani-threshold
+        #[arg(long = "merge-close", default_value_t = false, help_heading = "ANI clustering")]
+        merge_close: bool,
+
+	#[arg(
+            long = "ani-threshold",
+            default_value_t = 0.97,
+            help_heading = "ANI clustering"
+	)]
+	ani_threshold: f32,
+
+        // Resources
+        #[arg(short = 't', long = "threads", default_value_t = 1)]
+        threads: u32,
+
+        #[arg(long = "verbose", default_value_t = false)]
+        verbose: bool,
+
+        #[arg(long = "no-progress", default_value_t = false)]
+        no_progress: bool,
 
+        #[command(flatten)]
+        sketch: SketchArgs,
     }
 }
@@ -6,11 +6,45 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+
+use itertools::Itertools;
+use log::info;
+use log::warn;
+
+// Thresholds used to report how fine-grained the chosen cutoff is
+pub const GRANULARITY_THRESHOLDS: [f32; 5] = [0.95, 0.96, 0.97, 0.98, 0.99];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CutoffMode {
+    // Cut at a fixed height, the ANI or distance in KodamaParams::cutoff
+    Height,
+    // Cut at the height that gives this many clusters
+    NumClusters(usize),
+}
+
 #[derive(Clone)]
 pub struct KodamaParams {
     // Hierarchical clustering
     pub method: kodama::Method,
     pub cutoff: f32,
+    pub cutoff_mode: CutoffMode,
+
+    // Distance calculation
+    pub distance_metric: String,
+
+    // Log cluster counts at GRANULARITY_THRESHOLDS
+    pub report_granularity: bool,
+
+    // Values are distances rather than ANI, cutoff is a maximum distance
+    pub input_is_distance: bool,
+
+    // Pairs exactly at the cutoff are merged
+    pub inclusive_cutoff: bool,
 }
 
 impl Default for KodamaParams {
@@ -18,21 +52,34 @@ impl Default for KodamaParams {
         KodamaParams {
             method: kodama::Method::Single,
             cutoff: 0.97,
+            cutoff_mode: CutoffMode::Height,
+
+            distance_metric: "ani".to_string(),
+
+            report_granularity: false,
+
+            input_is_distance: false,
+
+            inclusive_cutoff: true,
         }
     }
 }
 
-fn cut_dendrogram(dendr: &kodama::Dendrogram<f32>, height: f32) -> Vec<usize> {
-    let cutoff = 1.0 - height;
+fn cut_dendrogram(dendr: &kodama::Dendrogram<f32>, height: f32, input_is_distance: bool, inclusive_cutoff: bool) -> Vec<usize> {
+    let cutoff = if input_is_distance { height } else { 1.0 - height };
+    return cut_at_dissimilarity(dendr, cutoff, inclusive_cutoff);
+}
+
+fn cut_at_dissimilarity(dendr: &kodama::Dendrogram<f32>, cutoff: f32, inclusive_cutoff: bool) -> Vec<usize> {
     let num_seqs = dendr.observations();
-    let num_nodes = 2 * num_seqs - 1;
+    let num_nodes = (2 * num_seqs).max(1) - 1;
 
     let mut num_groups = 0;
     let mut membership = vec![None; num_nodes];
 
     for (cluster_index, step) in dendr.steps().iter().enumerate().rev() {
         let cluster = cluster_index + num_seqs;
-        if step.dissimilarity <= cutoff {
+        if step.dissimilarity < cutoff || (inclusive_cutoff && step.dissimilarity == cutoff) {
             if membership[cluster].is_none() {
                 membership[cluster] = Some(num_groups);
                 num_groups += 1;
@@ -53,18 +100,710 @@ fn cut_dendrogram(dendr: &kodama::Dendrogram<f32>, height: f32) -> Vec<usize> {
         }
     }
 
+    return canonicalize_labels(&groups);
+}
+
+fn cut_to_num_clusters(dendr: &kodama::Dendrogram<f32>, num_clusters: usize, input_is_distance: bool) -> Vec<usize> {
+    // Loosest cut at a merge height that leaves at least num_clusters
+    // clusters, ties can merge several clusters at once so the exact number
+    // is not always reachable
+    let mut heights: Vec<f32> = dendr.steps().iter().map(|x| x.dissimilarity).collect();
+    heights.sort_by(|a, b| a.total_cmp(b));
+    heights.dedup();
+
+    let n_clusters_at = |cutoff: f32| cut_at_dissimilarity(dendr, cutoff, true).iter().max().map_or(0, |x| x + 1);
+    let mut lo: usize = 0;
+    let mut hi: usize = heights.len();
+    while lo < hi {
+	let mid = (lo + hi)/2;
+	if n_clusters_at(heights[mid]) >= num_clusters {
+	    lo = mid + 1;
+	} else {
+	    hi = mid;
+	}
+    }
+
+    // Nothing is merged if even the lowest merge gives too few clusters
+    let cutoff = if lo == 0 { f32::NEG_INFINITY } else { heights[lo - 1] };
+    let groups = cut_at_dissimilarity(dendr, cutoff, true);
+    let n_clusters = groups.iter().max().map_or(0, |x| x + 1);
+    if lo == 0 {
+	info!("Cut the dendrogram below the lowest merge into {} clusters", n_clusters);
+    } else {
+	info!("Cut the dendrogram at {} {} into {} clusters", if input_is_distance { "distance" } else { "ANI" }, if input_is_distance { cutoff } else { 1.0 - cutoff }, n_clusters);
+    }
+    if n_clusters != num_clusters {
+	warn!("Requested {} clusters, cutting at a merge height gives {} instead", num_clusters, n_clusters);
+    }
     return groups;
 }
 
+fn count_clusters(dendr: &kodama::Dendrogram<f32>, thresholds: &[f32], inclusive_cutoff: bool) -> Vec<(f32, usize)> {
+    return thresholds
+	.iter()
+	.map(|x| {
+	    let groups = cut_dendrogram(dendr, *x, false, inclusive_cutoff);
+	    (*x, groups.iter().max().map_or(0, |y| y + 1))
+	})
+	.collect();
+}
+
+fn report_granularity(dendr: &kodama::Dendrogram<f32>, cutoff: Option<f32>, inclusive_cutoff: bool) {
+    // Species-level clusters are typically around 0.95 ANI
+    info!("Clusters at standard ANI thresholds:");
+    info!("\tthreshold\tclusters");
+    count_clusters(dendr, &GRANULARITY_THRESHOLDS, inclusive_cutoff).iter().for_each(|x| {
+	info!("\t{:.2}\t{}", x.0, x.1);
+    });
+    if cutoff.is_some_and(|x| !GRANULARITY_THRESHOLDS.contains(&x)) {
+	info!("\t{}\t{} (chosen)", cutoff.unwrap(), cut_dendrogram(dendr, cutoff.unwrap(), false, inclusive_cutoff).iter().max().map_or(0, |y| y + 1));
+    }
+}
+
+pub fn canonicalize_labels(groups: &[usize]) -> Vec<usize> {
+    // Relabel clusters in the order of their first member so that the labels
+    // don't depend on the order kodama merged equally distant clusters in.
+    // Observations are sorted by name so this is the smallest member name.
+    let mut new_labels: HashMap<usize, usize> = HashMap::new();
+    return groups
+	.iter()
+	.map(|x| {
+	    let next_label = new_labels.len();
+	    *new_labels.entry(*x).or_insert(next_label)
+	})
+	.collect();
+}
+
+pub fn ani_to_similarity(
+    ani_result: &[(String, String, f32, f32, f32)],
+    opt: &Option<KodamaParams>,
+) -> Vec<(String, String, f32)> {
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    ani_result
+	.iter()
+	.map(|x| (x.0.clone(), x.1.clone(), similarity(x, &params)))
+	.collect()
+}
+
+fn similarity(
+    ani_result: &(String, String, f32, f32, f32),
+    params: &KodamaParams,
+) -> f32 {
+    match params.distance_metric.as_str() {
+	// Penalize pairs that only align over a fraction of their length
+	"ani_af" => ani_result.2 * f32::min(ani_result.3, ani_result.4),
+	&_ => ani_result.2,
+    }
+}
+
+fn linkage(
+    flattened_dissimilarity_matrix: &mut [f32],
+    num_seqs: usize,
+    method: kodama::Method,
+) -> kodama::Dendrogram<f32> {
+    // kodama needs at least two observations, with fewer there is nothing to merge
+    if flattened_dissimilarity_matrix.len() != num_seqs*(num_seqs.max(1) - 1)/2 {
+	panic!("{} pairwise values do not form a complete distance matrix of {} sequences, expected {}",
+	       flattened_dissimilarity_matrix.len(), num_seqs, num_seqs*(num_seqs.max(1) - 1)/2);
+    }
+    if num_seqs < 2 {
+	return kodama::Dendrogram::new(num_seqs);
+    }
+    return kodama::linkage(flattened_dissimilarity_matrix, num_seqs, method);
+}
+
+fn weighted_linkage(
+    flattened_dissimilarity_matrix: &[f32],
+    weights: &[usize],
+    method: kodama::Method,
+) -> kodama::Dendrogram<f32> {
+    // Nearest-neighbour chain where observation i starts as a cluster of
+    // weights[i] members, so a merged graph counts as all of its genomes.
+    // Only average and Ward linkage depend on cluster sizes.
+    let num_seqs = weights.len();
+    let is_ward = method == kodama::Method::Ward;
+    let mut dis: Vec<f32> = flattened_dissimilarity_matrix.iter().map(|x| if is_ward { x*x } else { *x }).collect();
+    let mut size: Vec<f32> = weights.iter().map(|x| *x as f32).collect();
+    let mut active: Vec<bool> = vec![true; num_seqs];
+    let index = |i: usize, j: usize| condensed_index(i.min(j), i.max(j), num_seqs);
+
+    let mut merges: Vec<(usize, usize, f32)> = Vec::with_capacity(num_seqs.max(1) - 1);
+    let mut chain: Vec<usize> = Vec::new();
+    while merges.len() + 1 < num_seqs {
+	if chain.is_empty() {
+	    chain.push(active.iter().position(|x| *x).unwrap());
+	}
+	let a = *chain.last().unwrap();
+	// Prefer the previous chain element on ties so the chain terminates
+	let prev = if chain.len() > 1 { Some(chain[chain.len() - 2]) } else { None };
+	let mut b = prev.unwrap_or(usize::MAX);
+	let mut min_dis = prev.map_or(f32::INFINITY, |x| dis[index(a, x)]);
+	(0..num_seqs).filter(|x| active[*x] && *x != a).for_each(|x| {
+	    if dis[index(a, x)] < min_dis {
+		min_dis = dis[index(a, x)];
+		b = x;
+	    }
+	});
+	if prev != Some(b) {
+	    chain.push(b);
+	    continue;
+	}
+	chain.truncate(chain.len() - 2);
+
+	// b survives as the merged cluster
+	merges.push((a, b, min_dis));
+	active[a] = false;
+	(0..num_seqs).filter(|x| active[*x] && *x != b).for_each(|x| {
+	    let (d_a, d_b) = (dis[index(a, x)], dis[index(b, x)]);
+	    dis[index(b, x)] = if is_ward {
+		((size[x] + size[a])*d_a + (size[x] + size[b])*d_b - size[x]*min_dis)/(size[a] + size[b] + size[x])
+	    } else {
+		(size[a]*d_a + size[b]*d_b)/(size[a] + size[b])
+	    };
+	});
+	size[b] += size[a];
+    }
+
+    // Merges come out of order, relabel them in the order kodama uses
+    merges.sort_by(|x, y| x.2.partial_cmp(&y.2).unwrap());
+    let mut parent: Vec<usize> = (0..num_seqs).collect();
+    let mut label: Vec<usize> = (0..num_seqs).collect();
+    let mut members: Vec<usize> = vec![1; num_seqs];
+    let find = |parent: &mut Vec<usize>, mut x: usize| {
+	while parent[x] != x {
+	    parent[x] = parent[parent[x]];
+	    x = parent[x];
+	}
+	x
+    };
+    let mut dend = kodama::Dendrogram::new(num_seqs);
+    merges.iter().enumerate().for_each(|(i, x)| {
+	let root_a = find(&mut parent, x.0);
+	let root_b = find(&mut parent, x.1);
+	let height = if is_ward { x.2.max(0.0).sqrt() } else { x.2 };
+	dend.push(kodama::Step::new(label[root_a], label[root_b], height, members[root_a] + members[root_b]));
+	parent[root_a] = root_b;
+	label[root_b] = num_seqs + i;
+	members[root_b] += members[root_a];
+    });
+    return dend;
+}
+
+pub fn build_dendrogram(
+    seq_names: &[String],
+    ani_result: &Vec<(String, String, f32)>,
+    weights: Option<&[usize]>,
+    opt: &Option<KodamaParams>,
+) -> (kodama::Dendrogram<f32>, Vec<String>) {
+    // Observations are the sorted sequence names, including those without any
+    // pairs. Pairs that are not in condensed order of the names, or are missing
+    // altogether, are placed in a dense matrix where missing pairs are unrelated.
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    let labels: Vec<String> = seq_names.iter().unique().sorted().cloned().collect();
+    let num_seqs = labels.len();
+    let mut name_to_index: HashMap<&str, usize> = HashMap::new();
+    labels.iter().enumerate().for_each(|x| { name_to_index.insert(x.1.as_str(), x.0); });
+
+    let n_pairs = num_seqs*(num_seqs.max(1) - 1)/2;
+    let in_condensed_order = ani_result.len() == n_pairs && ani_result.iter().enumerate().all(|(k, x)| {
+	let i = *name_to_index.get(x.0.as_str()).unwrap();
+	let j = *name_to_index.get(x.1.as_str()).unwrap();
+	i < j && condensed_index(i, j, num_seqs) == k
+    });
+    let flattened_dissimilarity_matrix: Vec<f32> = if in_condensed_order {
+	ani_result.iter().map(|x| if params.input_is_distance { x.2 } else { 1.0 - x.2 }).collect()
+    } else {
+	fill_dissimilarity_matrix(&labels, ani_result.iter().map(|x| (&x.0, &x.1, x.2)), &params)
+    };
+    return (dendrogram_from_matrix(flattened_dissimilarity_matrix, num_seqs, weights, &params), labels);
+}
+
+fn fill_dissimilarity_matrix<I, S>(
+    labels: &[String],
+    ani_result: I,
+    params: &KodamaParams,
+) -> Vec<f32>
+where
+    I: Iterator<Item = (S, S, f32)>,
+    S: AsRef<str>,
+{
+    // Condensed matrix of the sorted labels, missing pairs are unrelated
+    let num_seqs = labels.len();
+    let mut name_to_index: HashMap<&str, usize> = HashMap::new();
+    labels.iter().enumerate().for_each(|x| { name_to_index.insert(x.1.as_str(), x.0); });
+
+    let n_pairs = num_seqs*(num_seqs.max(1) - 1)/2;
+    let dissimilarity = |x: f32| if params.input_is_distance { x } else { 1.0 - x };
+    let mut matrix: Vec<f32> = vec![1.0; n_pairs];
+    let mut present: Vec<bool> = vec![false; n_pairs];
+    ani_result.for_each(|x| {
+	let i = *name_to_index.get(x.0.as_ref()).unwrap();
+	let j = *name_to_index.get(x.1.as_ref()).unwrap();
+	if i != j {
+	    let k = condensed_index(i.min(j), i.max(j), num_seqs);
+	    matrix[k] = dissimilarity(x.2);
+	    present[k] = true;
+	}
+    });
+    let n_missing = present.iter().filter(|x| !**x).count();
+    if n_missing > 0 {
+	warn!("{}/{} pairs between {} sequences are missing, treating them as unrelated", n_missing, n_pairs, num_seqs);
+    }
+    return matrix;
+}
+
+pub fn ani_dissimilarity_matrix<I>(
+    seq_names: &[String],
+    ani_result: I,
+    opt: &Option<KodamaParams>,
+) -> Vec<f32>
+where
+    I: Iterator<Item = (String, String, f32, f32, f32)>,
+{
+    // Condensed matrix of the sorted seq_names, filled as the pairs arrive so
+    // that they are never all in memory
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    let labels: Vec<String> = seq_names.iter().unique().sorted().cloned().collect();
+    return fill_dissimilarity_matrix(&labels, ani_result.map(|x| { let ani = similarity(&x, &params); (x.0, x.1, ani) }), &params);
+}
+
+fn dendrogram_from_matrix(
+    mut flattened_dissimilarity_matrix: Vec<f32>,
+    num_seqs: usize,
+    weights: Option<&[usize]>,
+    params: &KodamaParams,
+) -> kodama::Dendrogram<f32> {
+    let size_aware = params.method == kodama::Method::Average || params.method == kodama::Method::Ward;
+    return match weights {
+	Some(weights) if size_aware && num_seqs > 1 => {
+	    if weights.len() != num_seqs {
+		panic!("{} weights given for {} observations", weights.len(), num_seqs);
+	    }
+	    weighted_linkage(&flattened_dissimilarity_matrix, weights, params.method)
+	},
+	_ => linkage(&mut flattened_dissimilarity_matrix, num_seqs, params.method),
+    };
+}
+
+pub fn cut_at(
+    dend: &kodama::Dendrogram<f32>,
+    cutoff: f32,
+    opt: &Option<KodamaParams>,
+) -> Vec<usize> {
+    // Cutoff replaces the one in opt so the same tree can be cut repeatedly
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    return cut_dendrogram(dend, cutoff, params.input_is_distance, params.inclusive_cutoff);
+}
+
+pub fn condensed_index(i: usize, j: usize, num_seqs: usize) -> usize {
+    // Position of the pair (i, j), i < j, in the row-major upper triangle
+    return num_seqs*i - i*(i + 1)/2 + j - i - 1;
+}
+
+pub fn check_dissimilarity_range(
+    flattened_dissimilarity_matrix: &[f32],
+    input_is_distance: bool,
+    source: &str,
+) {
+    // Related genomes are above 0.5 ANI and below 0.5 distance, so mostly
+    // large dissimilarities mean the values use the other convention
+    let n_out_of_range = flattened_dissimilarity_matrix.iter().filter(|x| **x < 0.0 || **x > 1.0).count();
+    let informative: Vec<&f32> = flattened_dissimilarity_matrix.iter().filter(|x| **x > 0.0 && **x < 1.0).collect();
+    let n_implausible = informative.iter().filter(|x| ***x > 0.5).count();
+    if n_out_of_range > 0 {
+	warn!("{} has {} values outside [0, 1]", source, n_out_of_range);
+    }
+    if 2*n_implausible > informative.len() {
+	if input_is_distance {
+	    warn!("{}/{} values in {} are above 0.5, they look like ANI rather than distances, check --input-is-distance", n_implausible, informative.len(), source);
+	} else {
+	    warn!("{}/{} values in {} are below 0.5, they look like distances rather than ANI, use --input-is-distance if so", n_implausible, informative.len(), source);
+	}
+    }
+}
+
+pub fn condensed_dissimilarity_matrix(
+    seq_names: &[String],
+    ani_result: &[(String, String, f32)],
+    missing_dissimilarity: f32,
+) -> Vec<f32> {
+    // seq_names must be sorted, missing pairs are filled with missing_dissimilarity
+    let mut name_to_index: HashMap<&str, usize> = HashMap::new();
+    seq_names.iter().enumerate().for_each(|x| { name_to_index.insert(x.1.as_str(), x.0); });
+
+    let num_seqs = seq_names.len();
+    let mut flattened_dissimilarity_matrix: Vec<f32> = vec![missing_dissimilarity; num_seqs*(num_seqs.max(1) - 1)/2];
+    ani_result.iter().for_each(|x| {
+	let i = *name_to_index.get(x.0.as_str()).unwrap();
+	let j = *name_to_index.get(x.1.as_str()).unwrap();
+	if i != j {
+	    flattened_dissimilarity_matrix[condensed_index(i.min(j), i.max(j), num_seqs)] = 1.0 - x.2;
+	}
+    });
+    return flattened_dissimilarity_matrix;
+}
+
+pub fn single_linkage_cluster_condensed(
+    flattened_dissimilarity_matrix: &mut [f32],
+    num_seqs: usize,
+    opt: &Option<KodamaParams>,
+) -> Vec<usize> {
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    let dend = linkage(flattened_dissimilarity_matrix, num_seqs, params.method);
+
+    return cluster_dendrogram(&dend, opt);
+}
+
+pub fn condensed_dendrogram(
+    flattened_dissimilarity_matrix: &mut [f32],
+    num_seqs: usize,
+    opt: &Option<KodamaParams>,
+) -> kodama::Dendrogram<f32> {
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    return linkage(flattened_dissimilarity_matrix, num_seqs, params.method);
+}
+
+pub fn cluster_dendrogram(
+    dend: &kodama::Dendrogram<f32>,
+    opt: &Option<KodamaParams>,
+) -> Vec<usize> {
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    if params.report_granularity && !params.input_is_distance && dend.observations() > 0 {
+	let chosen = if params.cutoff_mode == CutoffMode::Height { Some(params.cutoff) } else { None };
+	report_granularity(dend, chosen, params.inclusive_cutoff);
+    }
+
+    return match params.cutoff_mode {
+	CutoffMode::NumClusters(num_clusters) => cut_to_num_clusters(dend, num_clusters, params.input_is_distance),
+	CutoffMode::Height => cut_at(dend, params.cutoff, opt),
+    };
+}
+
+pub fn two_level_clusters(
+    dend: &kodama::Dendrogram<f32>,
+    primary_cutoff: f32,
+    secondary_cutoff: f32,
+    opt: &Option<KodamaParams>,
+) -> Vec<(usize, usize)> {
+    // Cuts of the same tree are nested so each secondary cluster is inside
+    // one primary cluster, secondary labels are numbered within the primary
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    let tighter = if params.input_is_distance { secondary_cutoff <= primary_cutoff } else { secondary_cutoff >= primary_cutoff };
+    if !tighter {
+	panic!("Secondary threshold {} must be tighter than primary threshold {}", secondary_cutoff, primary_cutoff);
+    }
+    let primary = canonicalize_labels(&cut_dendrogram(dend, primary_cutoff, params.input_is_distance, params.inclusive_cutoff));
+    let secondary = cut_dendrogram(dend, secondary_cutoff, params.input_is_distance, params.inclusive_cutoff);
+
+    let mut secondary_labels: HashMap<usize, usize> = HashMap::new();
+    let mut n_secondary: HashMap<usize, usize> = HashMap::new();
+    return primary
+	.iter()
+	.zip(secondary.iter())
+	.map(|x| {
+	    let label = *secondary_labels.entry(*x.1).or_insert_with(|| {
+		let n = n_secondary.entry(*x.0).or_insert(0);
+		*n += 1;
+		*n - 1
+	    });
+	    (*x.0, label)
+	})
+	.collect();
+}
+
+pub fn write_dendrogram(
+    dend: &kodama::Dendrogram<f32>,
+    seq_names: &[String],
+    out_file: &String,
+) {
+    // Number of observations, their names in order, and one
+    // `cluster1 cluster2 dissimilarity size` line per linkage step
+    let f = std::fs::File::create(out_file).unwrap();
+    let mut writer = BufWriter::new(f);
+    writeln!(writer, "{}", dend.observations()).unwrap();
+    seq_names.iter().for_each(|x| { writeln!(writer, "{}", x).unwrap() });
+    dend.steps().iter().for_each(|x| {
+	writeln!(writer, "{}\t{}\t{}\t{}", x.cluster1, x.cluster2, x.dissimilarity, x.size).unwrap()
+    });
+}
+
+fn newick_label(name: &str) -> String {
+    // Names with characters that have a meaning in Newick are quoted
+    if name.chars().any(|x| x.is_whitespace() || "()[]':;,".contains(x)) {
+	return "'".to_string() + &name.replace('\'', "''") + "'";
+    }
+    return name.to_string();
+}
+
+enum NewickToken {
+    // Node index and the height of its parent
+    Node(usize, f32),
+    Text(String),
+}
+
+pub fn dendrogram_to_newick(
+    dend: &kodama::Dendrogram<f32>,
+    seq_names: &[String],
+) -> String {
+    // Nodes are at the height they were merged at, so the branch lengths add
+    // up to the dissimilarity (1 - ANI) between the leaves. Written without
+    // recursion since single linkage trees can be as deep as there are leaves.
+    let num_seqs = dend.observations();
+    if num_seqs == 0 {
+	return ";".to_string();
+    }
+    let height = |node: usize| if node < num_seqs { 0.0 } else { dend.steps()[node - num_seqs].dissimilarity };
+    let root = 2*num_seqs - 2;
+
+    let mut newick = String::new();
+    let mut stack: Vec<NewickToken> = vec![NewickToken::Node(root, height(root))];
+    while let Some(token) = stack.pop() {
+	match token {
+	    NewickToken::Text(text) => newick.push_str(&text),
+	    NewickToken::Node(node, parent_height) => {
+		// Non-monotonic linkages (centroid, median) can merge below the children
+		let branch_length = if node == root { "".to_string() } else { ":".to_string() + &f32::max(parent_height - height(node), 0.0).to_string() };
+		if node < num_seqs {
+		    newick.push_str(&newick_label(&seq_names[node]));
+		    newick.push_str(&branch_length);
+		} else {
+		    let step = &dend.steps()[node - num_seqs];
+		    newick.push('(');
+		    stack.push(NewickToken::Text(")".to_string() + &branch_length));
+		    stack.push(NewickToken::Node(step.cluster2, step.dissimilarity));
+		    stack.push(NewickToken::Text(",".to_string()));
+		    stack.push(NewickToken::Node(step.cluster1, step.dissimilarity));
+		}
+	    },
+	}
+    }
+    newick.push(';');
+    return newick;
+}
+
+pub fn read_dendrogram(
+    in_file: &String,
+) -> (Vec<String>, kodama::Dendrogram<f32>) {
+    let f = std::fs::File::open(in_file).unwrap();
+    let mut lines = BufReader::new(f).lines().map_while(Result::ok);
+
+    let num_seqs: usize = lines.next().unwrap().trim().parse().unwrap();
+    let seq_names: Vec<String> = lines.by_ref().take(num_seqs).collect();
+    if seq_names.len() != num_seqs {
+	panic!("Dendrogram file {} has {} sequence names, expected {}", in_file, seq_names.len(), num_seqs);
+    }
+
+    let mut dend = kodama::Dendrogram::new(num_seqs);
+    lines.filter(|x| !x.is_empty()).for_each(|x| {
+	let fields: Vec<&str> = x.split('\t').collect();
+	dend.push(kodama::Step::new(
+	    fields[0].parse().unwrap(),
+	    fields[1].parse().unwrap(),
+	    fields[2].parse().unwrap(),
+	    fields[3].parse().unwrap(),
+	));
+    });
+    if dend.len() != num_seqs.max(1) - 1 {
+	panic!("Dendrogram file {} has {} steps, expected {}", in_file, dend.len(), num_seqs.max(1) - 1);
+    }
+
+    return (seq_names, dend);
+}
+
 pub fn single_linkage_cluster(
+    seq_names: &[String],
     ani_result: &Vec<(String, String, f32)>,
+    weights: Option<&[usize]>,
     opt: &Option<KodamaParams>,
 ) -> Vec<usize> {
+    if seq_names.is_empty() {
+	return Vec::new();
+    }
+
+    let (dend, _) = build_dendrogram(seq_names, ani_result, weights, opt);
+
+    return cluster_dendrogram(&dend, opt);
+}
 
+fn merge_heights(
+    dend: &kodama::Dendrogram<f32>,
+    groups: &[usize],
+    input_is_distance: bool,
+) -> Vec<f32> {
+    // Loosest link within each group, as ANI unless the input was distances
+    let num_seqs = dend.observations();
+    let num_groups = groups.iter().max().map_or(0, |x| x + 1);
+    let mut max_dissimilarity: Vec<f32> = vec![0.0; num_groups];
+
+    // Any observation under a node identifies the group the node belongs to
+    let mut node_to_seq: Vec<usize> = (0..num_seqs).collect();
+    dend.steps().iter().for_each(|x| {
+	let group1 = groups[node_to_seq[x.cluster1]];
+	let group2 = groups[node_to_seq[x.cluster2]];
+	if group1 == group2 {
+	    max_dissimilarity[group1] = max_dissimilarity[group1].max(x.dissimilarity);
+	}
+	node_to_seq.push(node_to_seq[x.cluster1]);
+    });
+
+    return max_dissimilarity
+	.iter()
+	.map(|x| if input_is_distance { *x } else { 1.0 - x })
+	.collect();
+}
+
+pub fn single_linkage_cluster_with_heights(
+    flattened_dissimilarity_matrix: Vec<f32>,
+    num_seqs: usize,
+    weights: Option<&[usize]>,
+    opt: &Option<KodamaParams>,
+) -> (Vec<usize>, Vec<f32>) {
+    // Matrix from ani_dissimilarity_matrix
+    let params = opt.clone().unwrap_or(KodamaParams::default());
+    let dend = dendrogram_from_matrix(flattened_dissimilarity_matrix, num_seqs, weights, &params);
+    let groups = cluster_dendrogram(&dend, opt);
+    let heights = merge_heights(&dend, &groups, params.input_is_distance);
+
+    return (groups, heights);
+}
+
+pub fn threshold_sweep(
+    flattened_dissimilarity_matrix: Vec<f32>,
+    num_seqs: usize,
+    thresholds: &[f32],
+    opt: &Option<KodamaParams>,
+) -> Vec<(f32, usize)> {
+    // Cut the same dendrogram at each threshold and count the clusters
     let params = opt.clone().unwrap_or(KodamaParams::default());
-    let mut flattened_similarity_matrix: Vec<f32> = ani_result.into_iter().map(|x| 1.0 - x.2).collect();
-    let num_seqs = (0.5*(f64::sqrt((8*flattened_similarity_matrix.len() + 1) as f64) + 1.0)).round() as usize;
-    let dend = kodama::linkage(&mut flattened_similarity_matrix, num_seqs, params.method);
+    let dend = dendrogram_from_matrix(flattened_dissimilarity_matrix, num_seqs, None, &params);
+
+    return count_clusters(&dend, thresholds, params.inclusive_cutoff);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(x: &str, y: &str, ani: f32) -> (String, String, f32) {
+	(x.to_string(), y.to_string(), ani)
+    }
+
+    fn steps(dend: &kodama::Dendrogram<f32>) -> Vec<(usize, usize, f32, usize)> {
+	dend.steps().iter().map(|x| (x.cluster1.min(x.cluster2), x.cluster1.max(x.cluster2), x.dissimilarity, x.size)).collect()
+    }
+
+    #[test]
+    fn weighted_linkage_unit_weights() {
+	// With every weight 1 the sizes are the member counts kodama uses
+	let matrix: Vec<f32> = vec![0.02, 0.30, 0.35, 0.60,
+				    0.25, 0.40, 0.55,
+				    0.05, 0.45,
+				    0.50];
+	[kodama::Method::Average, kodama::Method::Ward].into_iter().for_each(|method| {
+	    let expected = steps(&kodama::linkage(&mut matrix.clone(), 5, method));
+	    let got = steps(&weighted_linkage(&matrix, &[1; 5], method));
+	    assert_eq!(got.len(), expected.len());
+	    got.iter().zip(expected.iter()).for_each(|(x, y)| {
+		assert_eq!((x.0, x.1, x.3), (y.0, y.1, y.3));
+		assert!((x.2 - y.2).abs() < 1e-5);
+	    });
+	});
+    }
+
+    #[test]
+    fn build_dendrogram_keeps_inputs_without_pairs() {
+	// d.fa failed sketching and is in no pairs, it should still be its own cluster
+	let seq_names: Vec<String> = ["d.fa", "c.fa", "b.fa", "a.fa"].iter().map(|x| x.to_string()).collect();
+	let ani_result = vec![pair("a.fa", "b.fa", 0.99), pair("a.fa", "c.fa", 0.5), pair("b.fa", "c.fa", 0.5)];
 
-    return cut_dendrogram(&dend, params.cutoff);
+	let (dend, labels) = build_dendrogram(&seq_names, &ani_result, None, &None);
+	assert_eq!(labels, vec!["a.fa", "b.fa", "c.fa", "d.fa"]);
+	assert_eq!(dend.observations(), 4);
+
+	let groups = single_linkage_cluster(&seq_names, &ani_result, None, &None);
+	assert_eq!(groups.len(), 4);
+	assert_eq!(groups[0], groups[1]);
+	assert_ne!(groups[0], groups[2]);
+	assert_ne!(groups[0], groups[3]);
+	assert_ne!(groups[2], groups[3]);
+    }
+
+    #[test]
+    fn single_linkage_cluster_distance_orientation() {
+	// The same pairs as ANI and as 1 - ANI distances, with matching cutoffs
+	let seq_names: Vec<String> = ["a.fa", "b.fa", "c.fa", "d.fa"].iter().map(|x| x.to_string()).collect();
+	let ani_result = vec![pair("a.fa", "b.fa", 0.99), pair("a.fa", "c.fa", 0.80), pair("a.fa", "d.fa", 0.85),
+			      pair("b.fa", "c.fa", 0.82), pair("b.fa", "d.fa", 0.84), pair("c.fa", "d.fa", 0.96)];
+	let dist_result: Vec<(String, String, f32)> = ani_result.iter().map(|x| (x.0.clone(), x.1.clone(), 1.0 - x.2)).collect();
+
+	[kodama::Method::Single, kodama::Method::Average].into_iter().for_each(|method| {
+	    let ani_params = KodamaParams { method, cutoff: 0.95, ..Default::default() };
+	    let dist_params = KodamaParams { method, cutoff: 0.05, input_is_distance: true, ..Default::default() };
+	    let from_ani = single_linkage_cluster(&seq_names, &ani_result, None, &Some(ani_params));
+	    let from_dist = single_linkage_cluster(&seq_names, &dist_result, None, &Some(dist_params));
+	    assert_eq!(from_ani, from_dist);
+	    assert_eq!(from_ani[0], from_ani[1]);
+	    assert_eq!(from_ani[2], from_ani[3]);
+	    assert_ne!(from_ani[0], from_ani[2]);
+	});
+    }
+
+    #[test]
+    fn single_linkage_cluster_two_genomes() {
+	// A one-element matrix, merged at or above the cutoff and two singletons below it
+	let seq_names: Vec<String> = ["a.fa", "b.fa"].iter().map(|x| x.to_string()).collect();
+	let params = Some(KodamaParams { cutoff: 0.97, ..Default::default() });
+
+	let merged = single_linkage_cluster(&seq_names, &vec![pair("a.fa", "b.fa", 0.98)], None, &params);
+	assert_eq!(merged, vec![0, 0]);
+
+	let separate = single_linkage_cluster(&seq_names, &vec![pair("a.fa", "b.fa", 0.90)], None, &params);
+	assert_eq!(separate.len(), 2);
+	assert_ne!(separate[0], separate[1]);
+    }
+
+    #[test]
+    fn cut_at_exact_threshold() {
+	// A pair exactly at the threshold only merges with an inclusive cutoff
+	let seq_names: Vec<String> = ["a.fa", "b.fa", "c.fa"].iter().map(|x| x.to_string()).collect();
+	let ani_result = vec![pair("a.fa", "b.fa", 0.97), pair("a.fa", "c.fa", 0.80), pair("b.fa", "c.fa", 0.80)];
+	let dist_result: Vec<(String, String, f32)> = ani_result.iter().map(|x| (x.0.clone(), x.1.clone(), 1.0 - x.2)).collect();
+
+	let inclusive = Some(KodamaParams { cutoff: 0.97, ..Default::default() });
+	let exclusive = Some(KodamaParams { cutoff: 0.97, inclusive_cutoff: false, ..Default::default() });
+	assert_eq!(single_linkage_cluster(&seq_names, &ani_result, None, &inclusive), vec![0, 0, 1]);
+	assert_eq!(single_linkage_cluster(&seq_names, &ani_result, None, &exclusive), vec![0, 1, 2]);
+
+	let inclusive = Some(KodamaParams { cutoff: dist_result[0].2, input_is_distance: true, ..Default::default() });
+	let exclusive = Some(KodamaParams { cutoff: dist_result[0].2, input_is_distance: true, inclusive_cutoff: false, ..Default::default() });
+	assert_eq!(single_linkage_cluster(&seq_names, &dist_result, None, &inclusive), vec![0, 0, 1]);
+	assert_eq!(single_linkage_cluster(&seq_names, &dist_result, None, &exclusive), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn single_linkage_cluster_condensed_few_sequences() {
+	assert_eq!(single_linkage_cluster_condensed(&mut [], 0, &None), Vec::<usize>::new());
+	assert_eq!(single_linkage_cluster_condensed(&mut [], 1, &None), vec![0]);
+	assert_eq!(single_linkage_cluster_condensed(&mut [0.01], 2, &None), vec![0, 0]);
+	assert_eq!(single_linkage_cluster_condensed(&mut [0.5], 2, &None), vec![0, 1]);
+	assert_eq!(single_linkage_cluster_condensed(&mut [0.01, 0.5, 0.5], 3, &None), vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn single_linkage_cluster_few_sequences() {
+	let names = |n: usize| (0..n).map(|x| format!("seq{}", x)).collect::<Vec<String>>();
+	let pair = |a: &str, b: &str, ani: f32| (a.to_string(), b.to_string(), ani);
+
+	assert_eq!(single_linkage_cluster(&names(0), &vec![], None, &None), Vec::<usize>::new());
+	assert_eq!(single_linkage_cluster(&names(1), &vec![], None, &None), vec![0]);
+	assert_eq!(single_linkage_cluster(&names(2), &vec![pair("seq0", "seq1", 0.99)], None, &None), vec![0, 0]);
+	assert_eq!(single_linkage_cluster(&names(2), &vec![pair("seq0", "seq1", 0.5)], None, &None), vec![0, 1]);
+	assert_eq!(single_linkage_cluster(&names(3), &vec![pair("seq0", "seq1", 0.99), pair("seq0", "seq2", 0.5), pair("seq1", "seq2", 0.5)], None, &None), vec![0, 0, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "do not form a complete distance matrix")]
+    fn single_linkage_cluster_condensed_malformed() {
+	single_linkage_cluster_condensed(&mut [0.01, 0.5], 3, &None);
+    }
 }
@@ -7,14 +7,36 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
 use std::cmp::Ordering;
-use std::sync::mpsc::channel;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::collections::BinaryHeap;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::sync_channel;
 
 use indicatif::ProgressBar;
 use indicatif::ProgressStyle;
 use itertools::Itertools;
+use log::debug;
+use log::info;
+use log::warn;
+use rayon::iter::IndexedParallelIterator;
+use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelBridge;
 use rayon::iter::ParallelIterator;
 
+use crate::fastx;
+
+// Approximate size of a sketched k-mer in skani, in bytes
+const BYTES_PER_SEED: f64 = 16.0;
+const BYTES_PER_MARKER: f64 = 16.0;
+
+// Identifies `dist --binary-output` files, last byte is the format version
+const BINARY_MATRIX_MAGIC: &[u8; 8] = b"PANANIM\x01";
+
 #[derive(Clone)]
 pub struct SkaniParams {
     // k-mer sketching
@@ -22,20 +44,66 @@ pub struct SkaniParams {
     pub kmer_subsampling_rate: u16,
     pub marker_compression_factor: u16,
     pub rescue_small: bool,
+    pub min_genome_length: usize,
+    // Contigs shorter than this are removed before sketching, 0 keeps all
+    pub min_contig_length: usize,
+    // Where the filtered inputs are written and whether they are kept
+    pub temp_dir: String,
+    pub keep_temp: bool,
+    // Compressed inputs are decompressed to temp_dir before sketching
+    pub keep_decompressed: bool,
+    // Precomputed sketches (input file -> sketch file)
+    pub sketch_files: HashMap<String, String>,
+    // Sketches are saved here and reused by later calls with the same parameters
+    pub sketch_cache_dir: Option<String>,
+    // Inputs ending in .sketch are loaded instead of sketched
+    pub refs_are_sketch: bool,
+
+    // Prefiltering, 0.0 disables screening
+    pub screen_val: f64,
 
     // ANI estimation
     pub clip_tails: bool,
     pub median: bool,
     pub adjust_ani: bool,
 
+    // Reference handling
+    pub keep_refs: bool,
+
     // Results reporting
     pub min_aligned_frac: f64,
+    // Override min_aligned_frac for the reference or the query of each pair
+    pub min_aligned_frac_ref: Option<f64>,
+    pub min_aligned_frac_query: Option<f64>,
+    pub af_combine: String,
+    // Pairs above this are too similar to be informative
+    pub max_ani: f32,
     pub bootstrap_ci: bool,
+    // Sorted results are spilled to temp_dir beyond this many pairs
+    pub max_pairs_in_memory: Option<usize>,
+    // Add each input against itself with ANI 1.0, not computed
+    pub include_self: bool,
+    // skani's diagnostic fields for each pair, written to detailed_file
+    pub detailed_out: bool,
+    pub detailed_file: Option<String>,
 
     // Print progress
     pub progress: bool,
 }
 
+#[derive(Clone, Debug)]
+pub struct AniRecord {
+    pub ref_file: String,
+    pub query_file: String,
+    // After the --min-af and --max-ani filters
+    pub ani: f32,
+    pub af_ref: f32,
+    pub af_query: f32,
+    // Only estimated with bootstrap_ci
+    pub ci_low: Option<f32>,
+    pub ci_high: Option<f32>,
+}
+
 impl Default for SkaniParams {
     fn default() -> SkaniParams {
         SkaniParams {
@@ -43,22 +111,53 @@ impl Default for SkaniParams {
             kmer_subsampling_rate: 30,
             marker_compression_factor: 1000,
             rescue_small: false,
+            min_genome_length: 0,
+            min_contig_length: 0,
+            temp_dir: "/tmp".to_string(),
+            keep_temp: false,
+            keep_decompressed: false,
+            sketch_files: HashMap::new(),
+            sketch_cache_dir: None,
+            refs_are_sketch: false,
+
+            screen_val: 0.0,
 
             clip_tails: false,
             median: false,
             adjust_ani: false,
 
+            keep_refs: false,
+
             min_aligned_frac: 0.15,
+            min_aligned_frac_ref: None,
+            min_aligned_frac_query: None,
+            af_combine: "any".to_string(),
+            max_ani: 1.0,
             bootstrap_ci: false,
+            max_pairs_in_memory: None,
+            include_self: false,
+            detailed_out: false,
+            detailed_file: None,
 
 	    progress: false,
         }
     }
 }
 
+fn min_aligned_fracs(skani_params: &SkaniParams) -> (f32, f32) {
+    let min_af_ref = skani_params.min_aligned_frac_ref.unwrap_or(skani_params.min_aligned_frac);
+    let min_af_query = skani_params.min_aligned_frac_query.unwrap_or(skani_params.min_aligned_frac);
+    return (min_af_ref as f32, min_af_query as f32);
+}
+
 pub fn filter_ani(ani: f32, ref_align_frac: f32, query_align_frac: f32,
-	      ref_min_align_frac: f32, query_min_align_frac: f32) -> f32 {
-    if ani > 0.0 && ani < 1.0 && !ani.is_nan() && (ref_align_frac > ref_min_align_frac || query_align_frac > query_min_align_frac) {
+	      ref_min_align_frac: f32, query_min_align_frac: f32, af_combine: &str, max_ani: f32) -> f32 {
+    let af_passes = match af_combine {
+	"both" => ref_align_frac > ref_min_align_frac && query_align_frac > query_min_align_frac,
+	"mean" => 0.5*(ref_align_frac + query_align_frac) > 0.5*(ref_min_align_frac + query_min_align_frac),
+	&_ => ref_align_frac > ref_min_align_frac || query_align_frac > query_min_align_frac,
+    };
+    if ani > 0.0 && ani < 1.0 && ani <= max_ani && !ani.is_nan() && af_passes {
         ani
     } else {
         0.0
@@ -69,31 +168,329 @@ pub fn sketch_fastx_files(
     fastx_files: &Vec<String>,
     opt: Option<skani::params::SketchParams>,
 ) -> Vec<skani::types::Sketch> {
+    // One file per task, collect keeps the sketches in input order
     let sketch_params = opt.unwrap_or(skani::params::SketchParams::default());
-    return skani::file_io::fastx_to_sketches(&fastx_files.iter().map(|x| x.clone()).collect(), &sketch_params, true);
+    return fastx_files
+	.par_iter()
+	.map(|x| skani::file_io::fastx_to_sketches(&vec![x.clone()], &sketch_params, true))
+	.flatten()
+	.collect();
 }
 
-pub fn ani_from_fastx_files(
+fn filtered_copy(
+    index: usize,
+    fastx_file: &String,
+    skani_params: &SkaniParams,
+) -> String {
+    // skani has no contig length filter so the inputs are copied without the
+    // short contigs, compressed inputs are copied decompressed
+    let file_name = fastx::decompressed_name(fastx_file);
+    if skani_params.min_contig_length > 0 {
+	let out_file = skani_params.temp_dir.clone() + "/" + &index.to_string() + "-" + &file_name + ".filtered.fasta";
+	let (total_length, removed_length) = fastx::filter_short_contigs(fastx_file, skani_params.min_contig_length, &out_file);
+	if removed_length > 0 {
+	    info!("Removed {}/{} bp in contigs shorter than {} bp from {}", removed_length, total_length, skani_params.min_contig_length, fastx_file);
+	}
+	out_file
+    } else if fastx::is_compressed(fastx_file) {
+	let out_file = skani_params.temp_dir.clone() + "/" + &index.to_string() + "-" + &file_name;
+	fastx::decompress(fastx_file, &out_file);
+	out_file
+    } else {
+	fastx_file.clone()
+    }
+}
+
+fn remove_filtered_copy(
+    filtered_file: &String,
+    fastx_file: &String,
+    skani_params: &SkaniParams,
+) {
+    // --keep-decompressed only keeps plain decompressed copies, not ones without the short contigs
+    let decompressed_only = skani_params.min_contig_length == 0 && fastx::is_compressed(fastx_file);
+    if filtered_file != fastx_file && !skani_params.keep_temp && !(skani_params.keep_decompressed && decompressed_only) {
+	let _ = std::fs::remove_file(filtered_file);
+    }
+}
+
+fn sketch_filtered_fastx_files(
+    fastx_files: &[String],
+    sketch_params: &skani::params::SketchParams,
+    skani_params: &SkaniParams,
+) -> Vec<skani::types::Sketch> {
+    let filtered_files: Vec<String> = fastx_files
+	.iter()
+	.enumerate()
+	.map(|(i, x)| filtered_copy(i, x, skani_params))
+	.collect();
+
+    let mut sketches = sketch_fastx_files(&filtered_files, Some(sketch_params.clone()));
+    // Results are reported using the original input names
+    let original_name: HashMap<&String, &String> = filtered_files.iter().zip(fastx_files.iter()).collect();
+    sketches.iter_mut().for_each(|x| { x.file_name = (*original_name.get(&x.file_name).unwrap()).clone(); });
+
+    filtered_files
+	.iter()
+	.zip(fastx_files.iter())
+	.for_each(|x| remove_filtered_copy(x.0, x.1, skani_params));
+    return sketches;
+}
+
+fn sketch_inputs(
+    fastx_files: &[String],
+    sketch_params: &skani::params::SketchParams,
+    skani_params: &SkaniParams,
+) -> Vec<skani::types::Sketch> {
+    // Inputs are only copied if they need decompressing or filtering
+    if fastx_files.is_empty() {
+	return Vec::new();
+    } else if skani_params.min_contig_length > 0 || fastx_files.iter().any(|x| fastx::is_compressed(x)) {
+	return sketch_filtered_fastx_files(fastx_files, sketch_params, skani_params);
+    }
+    return sketch_fastx_files(&fastx_files.to_vec(), Some(sketch_params.clone()));
+}
+
+pub fn check_compression_factors(kmer_subsampling_rate: u16, marker_compression_factor: u16) {
+    // skani keeps a k-mer as a seed if its hash is below max/c and as a marker
+    // if it is below max/m, so the markers are only a subset of the seeds if m >= c
+    if kmer_subsampling_rate == 0 || marker_compression_factor == 0 {
+	panic!("--kmer-subsampling-rate and --marker-compression-factor must be positive, got c={} and m={}", kmer_subsampling_rate, marker_compression_factor);
+    }
+    if marker_compression_factor < kmer_subsampling_rate {
+	panic!("--marker-compression-factor {} is smaller than --kmer-subsampling-rate {}. skani requires the marker compression factor (-m) to be at least the subsampling rate (-c), and recommends m=1000 with c<=200 for bacterial genomes, or m around 200-300 for small genomes",
+	       marker_compression_factor, kmer_subsampling_rate);
+    }
+}
+
+pub fn screen_val(screen: Option<f64>) -> f64 {
+    // skani screens by the ANI estimated from shared markers, 0.0 disables it
+    let screen_val = screen.unwrap_or(0.0);
+    if !(0.0..=1.0).contains(&screen_val) {
+	panic!("--screen takes an ANI between 0 and 1, got {}", screen_val);
+    }
+    return screen_val;
+}
+
+fn check_sketch_params(
+    loaded_params: &skani::params::SketchParams,
+    sketch_params: &skani::params::SketchParams,
+    source: &str,
+) {
+    if loaded_params.k != sketch_params.k || loaded_params.c != sketch_params.c || loaded_params.marker_c != sketch_params.marker_c {
+	panic!("{} was built with k={}, c={}, m={} but current parameters are k={}, c={}, m={}",
+	       source,
+	       loaded_params.k, loaded_params.c, loaded_params.marker_c,
+	       sketch_params.k, sketch_params.c, sketch_params.marker_c);
+    }
+}
+
+pub fn sketches_from_dir(
+    sketch_dir: &String,
+    sketch_params: &skani::params::SketchParams,
+) -> Vec<skani::types::Sketch> {
+    let sketch_files: Vec<String> = std::fs::read_dir(sketch_dir)
+	.unwrap()
+	.filter_map(|x| x.ok())
+	.map(|x| x.path().to_string_lossy().to_string())
+	.filter(|x| x.ends_with(".sketch"))
+	.sorted()
+	.collect();
+    if sketch_files.is_empty() {
+	panic!("No .sketch files found in {}", sketch_dir);
+    }
+
+    let (loaded_params, sketches) = skani::file_io::sketches_from_sketch(&sketch_files);
+    check_sketch_params(&loaded_params, sketch_params, &format!("Sketches in {}", sketch_dir));
+    info!("Loaded {} sketches from {}", sketches.len(), sketch_dir);
+    return sketches;
+}
+
+pub fn is_sketch_file(file: &str) -> bool {
+    return file.ends_with(".sketch");
+}
+
+pub fn sketch_source(sketch_file: &String) -> String {
+    // skani records the sequence file that was sketched
+    let (_, loaded) = skani::file_io::sketches_from_sketch(&vec![sketch_file.clone()]);
+    if loaded.len() != 1 {
+	panic!("Sketch file {} should contain exactly one sketch, found {}", sketch_file, loaded.len());
+    }
+    return loaded[0].file_name.clone();
+}
+
+pub fn load_or_sketch_fastx_files(
     fastx_files: &Vec<String>,
+    sketch_params: &skani::params::SketchParams,
+    sketch_files: &HashMap<String, String>,
+    skani_params: &SkaniParams,
+) -> Vec<skani::types::Sketch> {
+    let (precomputed, to_sketch): (Vec<String>, Vec<String>) = fastx_files
+	.iter()
+	.cloned()
+	.partition(|x| sketch_files.contains_key(x));
+
+    let mut sketches: Vec<skani::types::Sketch> = Vec::with_capacity(fastx_files.len());
+    precomputed.iter().for_each(|fastx_file| {
+	let sketch_file = sketch_files.get(fastx_file).unwrap();
+	let (loaded_params, loaded) = skani::file_io::sketches_from_sketch(&vec![sketch_file.clone()]);
+	check_sketch_params(&loaded_params, sketch_params, &format!("Sketch {} for {}", sketch_file, fastx_file));
+	if loaded.len() != 1 {
+	    panic!("Sketch file {} should contain exactly one sketch, found {}", sketch_file, loaded.len());
+	}
+	// Results are reported using the name in the input list
+	let mut sketch = loaded.into_iter().next().unwrap();
+	sketch.file_name = fastx_file.clone();
+	sketches.push(sketch);
+    });
+    if !precomputed.is_empty() {
+	debug!("Loaded {} precomputed sketches", precomputed.len());
+    }
+
+    sketches.append(&mut sketch_inputs(&to_sketch, sketch_params, skani_params));
+    return sketches;
+}
+
+fn sketch_cache_file(
+    fastx_file: &String,
+    skani_params: &SkaniParams,
+) -> String {
+    // FNV-1a of the file contents and the parameters that change the sketch.
+    // The contents are hashed because intermediate graphs reuse their names.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut fnv = |bytes: &[u8]| { bytes.iter().for_each(|x| { hash = (hash ^ *x as u64).wrapping_mul(0x100000001b3) }) };
+    let mut reader = BufReader::new(std::fs::File::open(fastx_file).unwrap());
+    loop {
+	let buf = reader.fill_buf().unwrap();
+	if buf.is_empty() {
+	    break;
+	}
+	fnv(buf);
+	let n = buf.len();
+	reader.consume(n);
+    }
+    fnv(format!("k={},c={},m={},min_contig_length={}",
+		skani_params.kmer_size, skani_params.kmer_subsampling_rate,
+		skani_params.marker_compression_factor, skani_params.min_contig_length).as_bytes());
+    return format!("{}/{:016x}.sketch", skani_params.sketch_cache_dir.as_ref().unwrap(), hash);
+}
+
+fn load_cached_sketches(
+    fastx_files: &[String],
+    sketch_params: &skani::params::SketchParams,
+    skani_params: &SkaniParams,
+) -> (Vec<skani::types::Sketch>, Vec<String>) {
+    // Returns the cached sketches and the files that still need sketching
+    let mut sketches: Vec<skani::types::Sketch> = Vec::new();
+    let mut uncached: Vec<String> = Vec::new();
+    fastx_files.iter().for_each(|fastx_file| {
+	let cache_file = sketch_cache_file(fastx_file, skani_params);
+	if std::path::Path::new(&cache_file).exists() {
+	    let (loaded_params, loaded) = skani::file_io::sketches_from_sketch(&vec![cache_file.clone()]);
+	    check_sketch_params(&loaded_params, sketch_params, &format!("Cached sketch {} for {}", cache_file, fastx_file));
+	    let mut sketch = loaded.into_iter().next().unwrap();
+	    sketch.file_name = fastx_file.clone();
+	    sketches.push(sketch);
+	} else {
+	    uncached.push(fastx_file.clone());
+	}
+    });
+    debug!("Loaded {}/{} sketches from {}", sketches.len(), fastx_files.len(), skani_params.sketch_cache_dir.as_ref().unwrap());
+    return (sketches, uncached);
+}
+
+fn write_sketch_file(
+    sketch: &skani::types::Sketch,
+    sketch_params: &skani::params::SketchParams,
+    out_file: &String,
+) {
+    // Same layout as `skani sketch` so the files can be read with sketches_from_sketch.
+    // Written under a temporary name so that concurrent runs never read a partial sketch
+    let tmp_file = out_file.clone() + "." + &std::process::id().to_string() + ".tmp";
+    let mut f = BufWriter::new(std::fs::File::create(&tmp_file).unwrap());
+    bincode::serialize_into(&mut f, &(sketch_params, sketch)).unwrap();
+    f.flush().unwrap();
+    std::fs::rename(&tmp_file, out_file).unwrap();
+}
+
+fn write_cached_sketches(
+    sketches: &[skani::types::Sketch],
+    sketch_params: &skani::params::SketchParams,
+    skani_params: &SkaniParams,
+) {
+    sketches.iter().for_each(|sketch| {
+	write_sketch_file(sketch, sketch_params, &sketch_cache_file(&sketch.file_name, skani_params));
+    });
+}
+
+pub fn write_sketches(
+    fastx_files: &[String],
     opt: &Option<SkaniParams>,
-) -> Vec<(String, String, f32)> {
+    out_dir: &String,
+) -> Vec<(String, String)> {
+    // One <out_dir>/<file name>.sketch per input, returns the input and
+    // sketch paths. Inputs with the same file name are prefixed with their index.
     let skani_params = opt.clone().unwrap_or(SkaniParams::default());
-    let sketch_params = skani::params::SketchParams::new(
+    let sketch_params = sketch_params(&skani_params);
+    std::fs::create_dir_all(out_dir).unwrap();
+
+    let progress = if skani_params.progress { ProgressBar::new(fastx_files.len() as u64) } else { ProgressBar::hidden() };
+    progress.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg} ({per_sec}, {eta} remaining)").unwrap());
+    progress.set_message("inputs sketched");
+
+    // Each sketch is written as soon as it is done so they are never all in memory
+    let name_counts = fastx_files.iter().map(|x| fastx::decompressed_name(x)).counts();
+    let written: Vec<(String, String)> = fastx_files
+	.par_iter()
+	.enumerate()
+	.filter_map(|(i, x)| {
+	    let file_name = fastx::decompressed_name(x);
+	    let out_file = if *name_counts.get(&file_name).unwrap() > 1 {
+		format!("{}/{}-{}.sketch", out_dir, i, file_name)
+	    } else {
+		format!("{}/{}.sketch", out_dir, file_name)
+	    };
+	    let to_sketch = filtered_copy(i, x, &skani_params);
+	    let sketch = skani::file_io::fastx_to_sketches(&vec![to_sketch.clone()], &sketch_params, true).into_iter().next();
+	    remove_filtered_copy(&to_sketch, x, &skani_params);
+	    progress.inc(1);
+	    match sketch {
+		Some(mut sketch) => {
+		    // Sketches record the original input so --refs-are-sketch finds it
+		    sketch.file_name = x.clone();
+		    write_sketch_file(&sketch, &sketch_params, &out_file);
+		    Some((x.clone(), out_file))
+		},
+		None => {
+		    warn!("Could not sketch {}", x);
+		    None
+		},
+	    }
+	})
+	.collect();
+    progress.finish();
+    return written;
+}
+
+pub fn sketch_params(skani_params: &SkaniParams) -> skani::params::SketchParams {
+    return skani::params::SketchParams::new(
         skani_params.marker_compression_factor as usize,
         skani_params.kmer_subsampling_rate as usize,
         skani_params.kmer_size as usize,
         false,
         false,
     );
-    let cmd_params = skani::params::CommandParams {
-        screen: false,
-        screen_val: 0.00,
+}
+
+pub fn command_params(skani_params: &SkaniParams) -> skani::params::CommandParams {
+    return skani::params::CommandParams {
+        screen: skani_params.screen_val > 0.0,
+        screen_val: skani_params.screen_val,
         mode: skani::params::Mode::Dist,
         out_file_name: "".to_string(),
         ref_files: vec![],
         query_files: vec![],
-        refs_are_sketch: false,
-        queries_are_sketch: false,
+        refs_are_sketch: skani_params.refs_are_sketch,
+        queries_are_sketch: skani_params.refs_are_sketch,
         robust: skani_params.clip_tails,
         median: skani_params.median,
         sparse: false,
@@ -102,61 +499,675 @@ pub fn ani_from_fastx_files(
         individual_contig_q: false,
         individual_contig_r: false,
         min_aligned_frac: 0.0,
-        keep_refs: false,
+        keep_refs: skani_params.keep_refs,
         est_ci: skani_params.bootstrap_ci,
         learned_ani: skani_params.adjust_ani,
-        detailed_out: false,
+        detailed_out: skani_params.detailed_out,
         rescue_small: skani_params.rescue_small,
         distance: true,
     };
+}
+
+pub fn ani_from_sketches(
+    ref_db: &[skani::types::Sketch],
+    query_db: &[skani::types::Sketch],
+    opt: &Option<SkaniParams>,
+) -> Vec<(String, String, f32)> {
+    let skani_params = opt.clone().unwrap_or(SkaniParams::default());
+    let cmd_params = command_params(&skani_params);
+    let adjust_ani = skani::regression::get_model(skani_params.kmer_subsampling_rate.into(), false);
+    let (min_af_ref, min_af_query) = min_aligned_fracs(&skani_params);
+
+    return ref_db
+	.iter()
+	.map(|r| { query_db
+		   .par_iter()
+		   .map(|q| {
+		       (q.file_name.clone(),
+			r.file_name.clone(),
+			skani::chain::chain_seeds(
+			    r,
+			    q,
+			    skani::chain::map_params_from_sketch(
+				r,
+				false,
+				&cmd_params,
+				&adjust_ani,
+			    ),
+			)
+		       )
+		   })
+		   .collect::<Vec<(String, String, skani::types::AniEstResult)>>()
+	})
+	.flatten()
+	.map(|x| {
+	    (x.0,
+	     x.1,
+	     filter_ani(x.2.ani, x.2.align_fraction_ref, x.2.align_fraction_query, min_af_ref, min_af_query, &skani_params.af_combine, skani_params.max_ani)
+	    )
+	})
+	.collect::<Vec<(String, String, f32)>>();
+}
+
+pub fn pairwise_ani_from_sketches(
+    sketches: &[skani::types::Sketch],
+    opt: &Option<SkaniParams>,
+) -> Vec<(String, String, f32)> {
+    let skani_params = opt.clone().unwrap_or(SkaniParams::default());
+    let cmd_params = command_params(&skani_params);
+    let adjust_ani = skani::regression::get_model(skani_params.kmer_subsampling_rate.into(), false);
+    let (min_af_ref, min_af_query) = min_aligned_fracs(&skani_params);
+
+    return sketches
+	.iter()
+	.combinations(2)
+	.par_bridge()
+	.map(|pair| {
+	    let res = skani::chain::chain_seeds(
+		pair.first().unwrap(),
+		pair.last().unwrap(),
+		skani::chain::map_params_from_sketch(
+		    pair.first().unwrap(),
+		    false,
+		    &cmd_params,
+		    &adjust_ani,
+		),
+	    );
+	    (pair.first().unwrap().file_name.clone(),
+	     pair.last().unwrap().file_name.clone(),
+	     filter_ani(res.ani, res.align_fraction_ref, res.align_fraction_query, min_af_ref, min_af_query, &skani_params.af_combine, skani_params.max_ani))
+	})
+	.collect::<Vec<(String, String, f32)>>();
+}
+
+fn screen_pairs<'a>(
+    sketch_pairs: Vec<(&'a skani::types::Sketch, &'a skani::types::Sketch)>,
+    sketches: &Vec<skani::types::Sketch>,
+    sketch_params: &skani::params::SketchParams,
+    screen_val: f64,
+) -> (Vec<(&'a skani::types::Sketch, &'a skani::types::Sketch)>, Vec<(String, String, f32, f32, f32)>) {
+    if screen_val <= 0.0 {
+	return (sketch_pairs, Vec::new());
+    }
+
+    // Find the sketches that share enough marker k-mers with each sketch
+    let kmer_to_sketch = skani::screen::kmer_to_sketch_from_refs(sketches);
+    let mut name_to_index: HashMap<&String, usize> = HashMap::new();
+    sketches.iter().enumerate().for_each(|x| { name_to_index.insert(&x.1.file_name, x.0); });
+    let candidates: Vec<HashSet<usize>> = sketches
+	.par_iter()
+	.map(|x| skani::screen::screen_refs(screen_val, &kmer_to_sketch, x, sketch_params, sketches).into_iter().collect())
+	.collect();
+
+    let (kept, screened): (Vec<_>, Vec<_>) = sketch_pairs
+	.into_iter()
+	.partition(|x| {
+	    let i = *name_to_index.get(&x.0.file_name).unwrap();
+	    let j = *name_to_index.get(&x.1.file_name).unwrap();
+	    candidates[i].contains(&j) || candidates[j].contains(&i)
+	});
+    return (kept, screened.iter().map(|x| (x.0.file_name.clone(), x.1.file_name.clone(), 0.0, 0.0, 0.0)).collect());
+}
+
+pub fn too_small_inputs(
+    fastx_files: &[String],
+    min_genome_length: usize,
+) -> HashSet<&String> {
+    if min_genome_length > 0 {
+	fastx_files
+	    .iter()
+	    .filter(|x| !is_sketch_file(x) && fastx::contig_lengths(x).iter().sum::<usize>() < min_genome_length)
+	    .collect()
+    } else {
+	HashSet::new()
+    }
+}
+
+pub fn estimate_sketch_memory(
+    fastx_files: &[String],
+    opt: &Option<SkaniParams>,
+) -> f64 {
+    // Returns the estimate in GB
+    let skani_params = opt.clone().unwrap_or(SkaniParams::default());
+    let total_length: f64 = fastx_files.iter().map(fastx::approx_sequence_length).sum();
+
+    let n_seeds = total_length / skani_params.kmer_subsampling_rate as f64;
+    let n_markers = total_length / skani_params.marker_compression_factor as f64;
+    return (BYTES_PER_SEED*n_seeds + BYTES_PER_MARKER*n_markers) / 1e9;
+}
+
+pub fn ani_from_fastx_files(
+    fastx_files: &Vec<String>,
+    opt: &Option<SkaniParams>,
+) -> Vec<(String, String, f32, f32, f32)> {
+    return ani_from_fastx_pairs(fastx_files, &None, opt);
+}
+
+pub fn ani_from_sketch_files(
+    sketch_files: &Vec<String>,
+    opt: &Option<SkaniParams>,
+) -> Vec<(String, String, f32, f32, f32)> {
+    // Inputs not ending in .sketch are still sketched
+    let mut skani_params = opt.clone().unwrap_or(SkaniParams::default());
+    skani_params.refs_are_sketch = true;
+    return ani_from_fastx_files(sketch_files, &Some(skani_params));
+}
+
+pub fn ani_from_fastx_pairs(
+    fastx_files: &Vec<String>,
+    fastx_pairs: &Option<Vec<(String, String)>>,
+    opt: &Option<SkaniParams>,
+) -> Vec<(String, String, f32, f32, f32)> {
+    return ani_records_from_fastx_pairs(fastx_files, fastx_pairs, opt)
+	.into_iter()
+	.map(|x| (x.ref_file, x.query_file, x.ani, x.af_ref, x.af_query))
+	.collect();
+}
+
+pub fn ani_stream_from_fastx_files(
+    fastx_files: &Vec<String>,
+    opt: &Option<SkaniParams>,
+) -> impl Iterator<Item = (String, String, f32, f32, f32)> {
+    return ani_stream_from_fastx_pairs(fastx_files, &None, opt);
+}
+
+pub fn ani_stream_from_fastx_pairs(
+    fastx_files: &Vec<String>,
+    fastx_pairs: &Option<Vec<(String, String)>>,
+    opt: &Option<SkaniParams>,
+) -> impl Iterator<Item = (String, String, f32, f32, f32)> {
+    return ani_record_stream_from_fastx_pairs(fastx_files, fastx_pairs, opt)
+	.map(|x| (x.ref_file, x.query_file, x.ani, x.af_ref, x.af_query));
+}
+
+pub fn ani_records_from_fastx_pairs(
+    fastx_files: &Vec<String>,
+    fastx_pairs: &Option<Vec<(String, String)>>,
+    opt: &Option<SkaniParams>,
+) -> Vec<AniRecord> {
+    return ani_record_stream_from_fastx_pairs(fastx_files, fastx_pairs, opt).collect();
+}
+
+pub fn ani_record_stream_from_fastx_pairs(
+    fastx_files: &Vec<String>,
+    fastx_pairs: &Option<Vec<(String, String)>>,
+    opt: &Option<SkaniParams>,
+) -> SortedAniRecords {
+    // Records in (reference, query) order. With max_pairs_in_memory they are
+    // merged from the spill files as they are read.
+    // Only the files in fastx_pairs are sketched if it is given
+    let fastx_files: &Vec<String> = &if fastx_pairs.is_some() {
+	let involved: HashSet<&String> = fastx_pairs.as_ref().unwrap().iter().map(|x| [&x.0, &x.1]).flatten().collect();
+	fastx_files.iter().filter(|x| involved.contains(x)).cloned().collect()
+    } else {
+	fastx_files.clone()
+    };
+    // The lexicographically first file of each pair is the reference, unless given in fastx_pairs
+    let pair_names: Vec<(String, String)> = if fastx_pairs.is_some() {
+	fastx_pairs.as_ref().unwrap().clone()
+    } else {
+	fastx_files.iter().cloned().sorted().tuple_combinations().collect()
+    };
+
+    let skani_params = opt.clone().unwrap_or(SkaniParams::default());
+    let sketch_params = sketch_params(&skani_params);
+    let cmd_params = command_params(&skani_params);
+
+    // Genomes that are too small to produce meaningful markers are not
+    // sketched and get the filtered ANI value against everything
+    let excluded: HashSet<&String> = too_small_inputs(fastx_files, skani_params.min_genome_length);
+    if !excluded.is_empty() {
+	warn!("Excluded {}/{} inputs shorter than {} bp from ANI estimation:", excluded.len(), fastx_files.len(), skani_params.min_genome_length);
+	fastx_files.iter().filter(|x| excluded.contains(x)).for_each(|x| { warn!("\t{}", x) });
+    }
+    let excluded_pairs: Vec<(String, String, f32, f32, f32)> = pair_names
+	.iter()
+	.filter(|x| excluded.contains(&x.0) || excluded.contains(&x.1))
+	.map(|x| (x.0.clone(), x.1.clone(), 0.0, 0.0, 0.0))
+	.collect();
+    let kept_files: Vec<String> = fastx_files.iter().filter(|x| !excluded.contains(x)).cloned().collect();
+
+    // Inputs that are sketches are their own precomputed sketch
+    let mut sketch_files: HashMap<String, String> = skani_params.sketch_files.clone();
+    if skani_params.refs_are_sketch {
+	kept_files.iter().filter(|x| is_sketch_file(x)).for_each(|x| { sketch_files.insert(x.clone(), x.clone()); });
+    }
+
+    // Precomputed sketches are used as is
+    let (precomputed, to_sketch): (Vec<String>, Vec<String>) = kept_files.iter().cloned().partition(|x| sketch_files.contains_key(x));
+    let mut sketches = load_or_sketch_fastx_files(&precomputed, &sketch_params, &sketch_files, &skani_params);
+    let (mut cached, to_sketch) = if skani_params.sketch_cache_dir.is_some() {
+	std::fs::create_dir_all(skani_params.sketch_cache_dir.as_ref().unwrap()).unwrap();
+	load_cached_sketches(&to_sketch, &sketch_params, &skani_params)
+    } else {
+	(Vec::new(), to_sketch)
+    };
+    sketches.append(&mut cached);
 
-    let sketches = sketch_fastx_files(fastx_files, Some(sketch_params));
+    let mut new_sketches = sketch_inputs(&to_sketch, &sketch_params, &skani_params);
+    if skani_params.sketch_cache_dir.is_some() {
+	write_cached_sketches(&new_sketches, &sketch_params, &skani_params);
+    }
+    sketches.append(&mut new_sketches);
     let adjust_ani = skani::regression::get_model(skani_params.kmer_subsampling_rate.into(), false);
+    let (min_af_ref, min_af_query) = min_aligned_fracs(&skani_params);
+
+    let mut name_to_sketch: HashMap<&String, &skani::types::Sketch> = HashMap::new();
+    sketches.iter().for_each(|x| { name_to_sketch.insert(&x.file_name, x); });
+
+    // skani leaves out the inputs it can't read, they are treated like the
+    // excluded inputs
+    let unreadable: HashSet<&String> = kept_files.iter().filter(|x| !name_to_sketch.contains_key(x)).collect();
+    if !unreadable.is_empty() {
+	warn!("Could not sketch {}/{} inputs:", unreadable.len(), fastx_files.len());
+	kept_files.iter().filter(|x| unreadable.contains(x)).unique().for_each(|x| { warn!("\t{}", x) });
+    }
+    let unreadable_pairs: Vec<(String, String, f32, f32, f32)> = pair_names
+	.iter()
+	.filter(|x| !excluded.contains(&x.0) && !excluded.contains(&x.1))
+	.filter(|x| unreadable.contains(&x.0) || unreadable.contains(&x.1))
+	.map(|x| (x.0.clone(), x.1.clone(), 0.0, 0.0, 0.0))
+	.collect();
+    let sketch_pairs: Vec<(&skani::types::Sketch, &skani::types::Sketch)> = pair_names
+	.iter()
+	.filter_map(|x| Some((*name_to_sketch.get(&x.0)?, *name_to_sketch.get(&x.1)?)))
+	.collect();
+
+    // Pairs that can't pass the screen get the filtered ANI value
+    let (sketch_pairs, screened_pairs) = screen_pairs(sketch_pairs, &sketches, &sketch_params, skani_params.screen_val);
+    if !screened_pairs.is_empty() {
+	info!("Screened out {}/{} pairs with ANI clearly below {}", screened_pairs.len(), screened_pairs.len() + sketch_pairs.len(), skani_params.screen_val);
+    }
 
-    let pairs: u64 = (sketches.len() as u64 - 1) * (sketches.len() as u64);
-    let progress = if skani_params.progress { ProgressBar::new(pairs/2) } else { ProgressBar::hidden() };
-    progress.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}").unwrap());
+    let progress = if skani_params.progress { ProgressBar::new(sketch_pairs.len() as u64) } else { ProgressBar::hidden() };
+    // Long ANI passes can take hours, show the rate and the time remaining
+    progress.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg} ({per_sec}, {eta} remaining)").unwrap());
     progress.set_message("pairs done");
 
-    let (sender, receiver) = channel();
-    sketches
-        .iter()
-        .combinations(2)
-        .par_bridge()
-        .for_each_with(sender, |s, pair| {
-	    let _ = s.send(
-		(pair.first().unwrap().file_name.clone(),
-		 pair.last().unwrap().file_name.clone(),
-		 skani::chain::chain_seeds(
-                     pair.first().unwrap(),
-                     pair.last().unwrap(),
-                     skani::chain::map_params_from_sketch(
-			 pair.first().unwrap(),
-			 false,
-			 &cmd_params,
-			 &adjust_ani,
-                     ),
-		 )));
-	    progress.inc(1);
-        });
+    // Results are converted as they arrive so that skani's full estimates
+    // never pile up, the bound keeps the workers from outrunning this
+    let (sender, receiver) = sync_channel::<(String, String, skani::types::AniEstResult)>(4096);
+    let (mut spill, raw_result, n_capped) = std::thread::scope(|scope| {
+	let consumer = scope.spawn(|| collect_ani_results(receiver, &skani_params, min_af_ref, min_af_query));
+
+	sketch_pairs
+	    .par_iter()
+	    .for_each_with(sender, |s, pair| {
+		let _ = s.send(
+		    (pair.0.file_name.clone(),
+		     pair.1.file_name.clone(),
+		     skani::chain::chain_seeds(
+			 pair.0,
+			 pair.1,
+			 skani::chain::map_params_from_sketch(
+			     pair.0,
+			     false,
+			     &cmd_params,
+			     &adjust_ani,
+			 ),
+		     )));
+		progress.inc(1);
+	    });
+	consumer.join().unwrap()
+    });
 
     progress.finish();
+    let elapsed = progress.elapsed().as_secs_f64();
+    if !sketch_pairs.is_empty() {
+	debug!("Compared {} pairs in {:.1}s ({:.1} pairs/s)", sketch_pairs.len(), elapsed, sketch_pairs.len() as f64 / elapsed.max(1e-6));
+    }
+
+    if skani_params.detailed_out && skani_params.detailed_file.is_some() {
+	write_detailed_results(&raw_result, skani_params.detailed_file.as_ref().unwrap());
+    }
+    if n_capped > 0 {
+	info!("Capped {} pairs with ANI above --max-ani {}", n_capped, skani_params.max_ani);
+    }
+
+    excluded_pairs.into_iter().chain(unreadable_pairs).chain(screened_pairs).for_each(|x| spill.push(AniRecord {
+	ref_file: x.0, query_file: x.1, ani: x.2, af_ref: x.3, af_query: x.4, ci_low: None, ci_high: None,
+    }));
+    if skani_params.include_self {
+	fastx_files.iter().unique().for_each(|x| spill.push(AniRecord {
+	    ref_file: x.clone(), query_file: x.clone(), ani: 1.0, af_ref: 1.0, af_query: 1.0, ci_low: None, ci_high: None,
+	}));
+    }
+
+    // Ensure output order is same regardless of parallelization
+    let mut records = spill.into_sorted();
+    records.skipped_inputs = fastx_files.iter().filter(|x| excluded.contains(x) || unreadable.contains(x)).unique().cloned().collect();
+    return records;
+}
+
+fn collect_ani_results(
+    receiver: Receiver<(String, String, skani::types::AniEstResult)>,
+    skani_params: &SkaniParams,
+    min_af_ref: f32,
+    min_af_query: f32,
+) -> (AniSpill, Vec<(String, String, skani::types::AniEstResult)>, usize) {
+    // Only the detailed output needs skani's full estimates
+    let mut spill = AniSpill::new(skani_params);
+    let mut raw_result: Vec<(String, String, skani::types::AniEstResult)> = Vec::new();
+    let mut n_capped: usize = 0;
+    for x in receiver.iter() {
+	n_capped += (x.2.ani > skani_params.max_ani && x.2.ani < 1.0) as usize;
+	spill.push(AniRecord {
+	    ani: filter_ani(x.2.ani, x.2.align_fraction_ref, x.2.align_fraction_query, min_af_ref, min_af_query, &skani_params.af_combine, skani_params.max_ani),
+	    af_ref: x.2.align_fraction_ref,
+	    af_query: x.2.align_fraction_query,
+	    ci_low: if skani_params.bootstrap_ci { Some(x.2.ci_lower) } else { None },
+	    ci_high: if skani_params.bootstrap_ci { Some(x.2.ci_upper) } else { None },
+	    ref_file: x.0.clone(),
+	    query_file: x.1.clone(),
+	});
+	if skani_params.detailed_out && skani_params.detailed_file.is_some() {
+	    raw_result.push(x);
+	}
+    }
+    return (spill, raw_result, n_capped);
+}
 
-    let ani_result: Vec<(String, String, f32)> = receiver
-        .iter()
-        .sorted_by(|k1, k2| match k1.0.cmp(&k2.0) {
+fn cmp_records(k1: &AniRecord, k2: &AniRecord) -> Ordering {
+    return match k1.ref_file.cmp(&k2.ref_file) {
+        Ordering::Equal => k1.query_file.cmp(&k2.query_file),
+        other => other,
+    };
+}
+
+struct AniSpill {
+    records: Vec<AniRecord>,
+    max_records: Option<usize>,
+    spill_prefix: String,
+    spill_files: Vec<String>,
+}
+
+impl AniSpill {
+    // Keeps at most max_records in memory, full buffers are sorted and
+    // written to temp_dir to be merged back in order at the end
+    fn new(skani_params: &SkaniParams) -> AniSpill {
+	AniSpill {
+	    records: Vec::new(),
+	    max_records: skani_params.max_pairs_in_memory.map(|x| x.max(1)),
+	    spill_prefix: skani_params.temp_dir.clone() + "/ani_pairs-" + &std::process::id().to_string() + "-" + &rand::random::<u32>().to_string(),
+	    spill_files: Vec::new(),
+	}
+    }
+
+    fn push(&mut self, record: AniRecord) {
+	self.records.push(record);
+	if self.max_records.is_some_and(|x| self.records.len() >= x) {
+	    self.spill();
+	}
+    }
+
+    fn spill(&mut self) {
+	let out_file = self.spill_prefix.clone() + "-" + &self.spill_files.len().to_string() + ".tsv";
+	let mut writer = BufWriter::new(std::fs::File::create(&out_file).unwrap());
+	self.records.sort_by(cmp_records);
+	self.records.drain(..).for_each(|x| {
+	    writeln!(writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}", x.ref_file, x.query_file, x.ani, x.af_ref, x.af_query,
+		     x.ci_low.map_or("NA".to_string(), |y| y.to_string()),
+		     x.ci_high.map_or("NA".to_string(), |y| y.to_string())).unwrap();
+	});
+	writer.flush().unwrap();
+	debug!("Spilled ANI results to {}", out_file);
+	self.spill_files.push(out_file);
+    }
+
+    fn into_sorted(mut self) -> SortedAniRecords {
+	if self.spill_files.is_empty() {
+	    self.records.sort_by(cmp_records);
+	    return SortedAniRecords {
+		in_memory: std::mem::take(&mut self.records).into_iter(),
+		readers: Vec::new(),
+		heads: Vec::new(),
+		heap: BinaryHeap::new(),
+		spill_files: Vec::new(),
+		skipped_inputs: Vec::new(),
+	    };
+	}
+	if !self.records.is_empty() {
+	    self.spill();
+	}
+
+	let mut readers: Vec<std::io::Lines<BufReader<std::fs::File>>> = self.spill_files
+	    .iter()
+	    .map(|x| BufReader::new(std::fs::File::open(x).unwrap()).lines())
+	    .collect();
+	let heads: Vec<Option<AniRecord>> = readers.iter_mut().map(|x| x.next().map(|y| parse_spilled(y.unwrap()))).collect();
+	let heap: BinaryHeap<std::cmp::Reverse<(String, String, usize)>> = heads
+	    .iter()
+	    .enumerate()
+	    .filter_map(|(i, x)| x.as_ref().map(|y| std::cmp::Reverse((y.ref_file.clone(), y.query_file.clone(), i))))
+	    .collect();
+	return SortedAniRecords {
+	    in_memory: Vec::new().into_iter(),
+	    readers,
+	    heads,
+	    heap,
+	    spill_files: std::mem::take(&mut self.spill_files),
+	    skipped_inputs: Vec::new(),
+	};
+    }
+}
+
+fn parse_spilled(line: String) -> AniRecord {
+    let fields: Vec<&str> = line.split('\t').collect();
+    AniRecord {
+	ref_file: fields[0].to_string(),
+	query_file: fields[1].to_string(),
+	ani: fields[2].parse().unwrap(),
+	af_ref: fields[3].parse().unwrap(),
+	af_query: fields[4].parse().unwrap(),
+	ci_low: fields[5].parse().ok(),
+	ci_high: fields[6].parse().ok(),
+    }
+}
+
+pub struct SortedAniRecords {
+    // Either the records that were never spilled, or a k-way merge of the
+    // sorted spill files that holds one record per file at a time
+    in_memory: std::vec::IntoIter<AniRecord>,
+    readers: Vec<std::io::Lines<BufReader<std::fs::File>>>,
+    heads: Vec<Option<AniRecord>>,
+    heap: BinaryHeap<std::cmp::Reverse<(String, String, usize)>>,
+    spill_files: Vec<String>,
+    // Inputs that were too small or could not be sketched
+    pub skipped_inputs: Vec<String>,
+}
+
+impl Iterator for SortedAniRecords {
+    type Item = AniRecord;
+
+    fn next(&mut self) -> Option<AniRecord> {
+	if self.readers.is_empty() {
+	    return self.in_memory.next();
+	}
+	// Smallest (reference, query) first
+	let std::cmp::Reverse((_, _, i)) = self.heap.pop()?;
+	let record = self.heads[i].take().unwrap();
+	self.heads[i] = self.readers[i].next().map(|y| parse_spilled(y.unwrap()));
+	if let Some(y) = self.heads[i].as_ref() {
+	    self.heap.push(std::cmp::Reverse((y.ref_file.clone(), y.query_file.clone(), i)));
+	}
+	return Some(record);
+    }
+}
+
+impl Drop for SortedAniRecords {
+    fn drop(&mut self) {
+	self.spill_files.iter().for_each(|x| { let _ = std::fs::remove_file(x); });
+    }
+}
+
+fn write_detailed_results(
+    results: &[(String, String, skani::types::AniEstResult)],
+    out_file: &String,
+) {
+    // Unfiltered values straight from skani, only the pairs that were chained
+    let f = std::fs::File::create(out_file).unwrap();
+    let mut writer = BufWriter::new(f);
+    writeln!(writer, "reference\tquery\tani\talign_fraction_ref\talign_fraction_query\tci_lower\tci_upper\taligned_reference_length\taligned_query_length\tavg_chain_int_len\ttotal_bases_covered\tstd").unwrap();
+    results
+	.iter()
+	.sorted_by(|k1, k2| match k1.0.cmp(&k2.0) {
             Ordering::Equal => k1.1.cmp(&k2.1),
             other => other,
-        })
-	.map(|x| {
-            (
-		x.0,
-		x.1,
-		filter_ani(x.2.ani, x.2.align_fraction_ref, x.2.align_fraction_query, skani_params.min_aligned_frac as f32, skani_params.min_aligned_frac as f32),
-            )
 	})
-        .collect();
+	.for_each(|x| {
+	    writeln!(writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+		     x.0, x.1, x.2.ani, x.2.align_fraction_ref, x.2.align_fraction_query,
+		     x.2.ci_lower, x.2.ci_upper, x.2.aligned_reference_length, x.2.aligned_query_length,
+		     x.2.avg_chain_int_len, x.2.total_bases_covered, x.2.std).unwrap();
+	});
+}
 
-    // Ensure output order is same regardless of parallelization
-    return ani_result;
+pub fn write_binary_matrix(
+    seq_names: &[String],
+    flattened_dissimilarity_matrix: &[f32],
+    skani_params: &SkaniParams,
+    out_file: &String,
+) {
+    // Little-endian layout: magic, sketch parameters, number of sequences,
+    // length-prefixed names, and the condensed dissimilarity matrix
+    let f = std::fs::File::create(out_file).unwrap();
+    let mut writer = BufWriter::new(f);
+    writer.write_all(BINARY_MATRIX_MAGIC).unwrap();
+    writer.write_all(&[skani_params.kmer_size]).unwrap();
+    writer.write_all(&skani_params.kmer_subsampling_rate.to_le_bytes()).unwrap();
+    writer.write_all(&skani_params.marker_compression_factor.to_le_bytes()).unwrap();
+    writer.write_all(&(skani_params.min_aligned_frac as f32).to_le_bytes()).unwrap();
+    writer.write_all(&(seq_names.len() as u64).to_le_bytes()).unwrap();
+    seq_names.iter().for_each(|x| {
+	writer.write_all(&(x.len() as u32).to_le_bytes()).unwrap();
+	writer.write_all(x.as_bytes()).unwrap();
+    });
+    flattened_dissimilarity_matrix.iter().for_each(|x| {
+	writer.write_all(&x.to_le_bytes()).unwrap();
+    });
+}
+
+pub fn write_phylip_matrix<W: Write>(
+    ani_result: &[(String, String, f32)],
+    seq_names: &[String],
+    as_distance: bool,
+    fill_value: f32,
+    writer: &mut W,
+) {
+    // Relaxed PHYLIP: number of sequences, then one row per sequence with its
+    // name and values. Pairs that are missing or filtered to 0.0 get fill_value.
+    let mut name_to_index: HashMap<&str, usize> = HashMap::new();
+    seq_names.iter().enumerate().for_each(|x| { name_to_index.insert(x.1.as_str(), x.0); });
+
+    let num_seqs = seq_names.len();
+    let mut matrix: Vec<Vec<f32>> = vec![vec![fill_value; num_seqs]; num_seqs];
+    (0..num_seqs).for_each(|i| { matrix[i][i] = if as_distance { 0.0 } else { 1.0 } });
+    ani_result.iter().filter(|x| x.2 > 0.0).for_each(|x| {
+	let i = *name_to_index.get(x.0.as_str()).unwrap();
+	let j = *name_to_index.get(x.1.as_str()).unwrap();
+	if i != j {
+	    let value = if as_distance { 1.0 - x.2 } else { x.2 };
+	    matrix[i][j] = value;
+	    matrix[j][i] = value;
+	}
+    });
+
+    writeln!(writer, "{}", num_seqs).unwrap();
+    seq_names.iter().zip(matrix.iter()).for_each(|x| {
+	writeln!(writer, "{}\t{}", x.0, x.1.iter().map(|y| y.to_string()).join("\t")).unwrap();
+    });
+    writer.flush().unwrap();
+}
+
+pub fn read_binary_matrix(in_file: &String) -> (Vec<String>, Vec<f32>) {
+    let bytes = std::fs::read(in_file).unwrap();
+    if bytes.len() < 25 || &bytes[0..8] != BINARY_MATRIX_MAGIC {
+	panic!("{} is not a panaani binary distance matrix", in_file);
+    }
+    let kmer_size = bytes[8];
+    let kmer_subsampling_rate = u16::from_le_bytes(bytes[9..11].try_into().unwrap());
+    let marker_compression_factor = u16::from_le_bytes(bytes[11..13].try_into().unwrap());
+    let min_aligned_frac = f32::from_le_bytes(bytes[13..17].try_into().unwrap());
+    let num_seqs = u64::from_le_bytes(bytes[17..25].try_into().unwrap()) as usize;
+    info!("Reading {} sequences computed with --skani-kmer-size {} --kmer-subsampling-rate {} --marker-compression-factor {} --min-af {}",
+	  num_seqs, kmer_size, kmer_subsampling_rate, marker_compression_factor, min_aligned_frac);
+
+    let mut pos: usize = 25;
+    let mut seq_names: Vec<String> = Vec::with_capacity(num_seqs);
+    for _ in 0..num_seqs {
+	if pos + 4 > bytes.len() {
+	    panic!("{} is truncated", in_file);
+	}
+	let name_len = u32::from_le_bytes(bytes[pos..(pos + 4)].try_into().unwrap()) as usize;
+	pos += 4;
+	if pos + name_len > bytes.len() {
+	    panic!("{} is truncated", in_file);
+	}
+	seq_names.push(String::from_utf8(bytes[pos..(pos + name_len)].to_vec()).unwrap());
+	pos += name_len;
+    }
+
+    let num_pairs = num_seqs*(num_seqs.max(1) - 1)/2;
+    if bytes.len() - pos != 4*num_pairs {
+	panic!("{} should contain {} distances but has {} bytes left", in_file, num_pairs, bytes.len() - pos);
+    }
+    let flattened_dissimilarity_matrix: Vec<f32> = bytes[pos..]
+	.chunks_exact(4)
+	.map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+	.collect();
+
+    return (seq_names, flattened_dissimilarity_matrix);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_ani_af_combine_any() {
+	// Either fraction passing is enough
+	assert_eq!(filter_ani(0.98, 0.6, 0.1, 0.5, 0.5, "any", 1.0), 0.98);
+	assert_eq!(filter_ani(0.98, 0.1, 0.6, 0.5, 0.5, "any", 1.0), 0.98);
+	assert_eq!(filter_ani(0.98, 0.1, 0.1, 0.5, 0.5, "any", 1.0), 0.0);
+    }
+
+    #[test]
+    fn filter_ani_af_combine_both() {
+	assert_eq!(filter_ani(0.98, 0.6, 0.6, 0.5, 0.5, "both", 1.0), 0.98);
+	assert_eq!(filter_ani(0.98, 0.6, 0.1, 0.5, 0.5, "both", 1.0), 0.0);
+	assert_eq!(filter_ani(0.98, 0.1, 0.6, 0.5, 0.5, "both", 1.0), 0.0);
+    }
+
+    #[test]
+    fn filter_ani_af_combine_mean() {
+	// Mean of 0.7 and 0.4 passes a mean minimum of 0.5, 0.6 and 0.1 does not
+	assert_eq!(filter_ani(0.98, 0.7, 0.4, 0.5, 0.5, "mean", 1.0), 0.98);
+	assert_eq!(filter_ani(0.98, 0.6, 0.1, 0.5, 0.5, "mean", 1.0), 0.0);
+    }
+
+    #[test]
+    fn ani_spill_merges_in_order() {
+	let skani_params = SkaniParams {
+	    max_pairs_in_memory: Some(2),
+	    temp_dir: std::env::temp_dir().to_string_lossy().to_string(),
+	    ..Default::default()
+	};
+	let mut spill = AniSpill::new(&skani_params);
+	[("c", "d"), ("a", "c"), ("b", "c"), ("a", "b"), ("b", "d")].iter().for_each(|x| spill.push(AniRecord {
+	    ref_file: x.0.to_string(), query_file: x.1.to_string(), ani: 0.9, af_ref: 0.5, af_query: 0.5, ci_low: None, ci_high: Some(0.95),
+	}));
+	let spill_files = spill.spill_files.clone();
+	assert_eq!(spill_files.len(), 2);
+
+	let merged: Vec<(String, String)> = spill.into_sorted().map(|x| (x.ref_file, x.query_file)).collect();
+	let expected: Vec<(String, String)> = [("a", "b"), ("a", "c"), ("b", "c"), ("b", "d"), ("c", "d")].iter().map(|x| (x.0.to_string(), x.1.to_string())).collect();
+	assert_eq!(merged, expected);
+	assert!(spill_files.iter().all(|x| !std::path::Path::new(x).exists()));
+    }
+
+    #[test]
+    fn filter_ani_max_ani() {
+	assert_eq!(filter_ani(0.999, 0.9, 0.9, 0.5, 0.5, "any", 0.995), 0.0);
+	assert_eq!(filter_ani(0.99, 0.9, 0.9, 0.5, 0.5, "any", 0.995), 0.99);
+    }
 }
@@ -0,0 +1,227 @@
+// panaani: Pangenome-aware dereplication of bacterial genomes into ANI clusters
+//
+// Copyright (c) Tommi Mäklin <tommi 'at' maklin.fi>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+
+use flate2::read::MultiGzDecoder;
+use itertools::Itertools;
+use log::debug;
+use log::warn;
+
+// Typical ratio of sequence length to compressed file size
+const GZIP_RATIO: f64 = 4.0;
+
+pub fn is_compressed(fastx_file: &str) -> bool {
+    return fastx_file.ends_with(".gz") || fastx_file.ends_with(".zst");
+}
+
+pub fn open_fastx(fastx_file: &String) -> std::io::Result<Box<dyn BufRead>> {
+    // Compression is detected from the extension
+    let f = std::fs::File::open(fastx_file)?;
+    let reader: Box<dyn BufRead> = if fastx_file.ends_with(".gz") {
+	Box::new(BufReader::new(MultiGzDecoder::new(f)))
+    } else if fastx_file.ends_with(".zst") {
+	Box::new(BufReader::new(zstd::stream::read::Decoder::new(f)?))
+    } else {
+	Box::new(BufReader::new(f))
+    };
+    return Ok(reader);
+}
+
+pub fn decompressed_name(fastx_file: &str) -> String {
+    // File name without the directory and the compression extension
+    let file_name = std::path::Path::new(fastx_file).file_name().map_or("input".to_string(), |x| x.to_string_lossy().to_string());
+    return file_name.strip_suffix(".gz").or(file_name.strip_suffix(".zst")).unwrap_or(&file_name).to_string();
+}
+
+pub fn decompress(fastx_file: &String, out_file: &String) {
+    let mut reader = open_fastx(fastx_file).unwrap();
+    let mut writer = BufWriter::new(std::fs::File::create(out_file).unwrap());
+    std::io::copy(&mut reader, &mut writer).unwrap();
+    writer.flush().unwrap();
+}
+
+pub fn contig_lengths(fastx_file: &String) -> Vec<usize> {
+    // Only read the lengths, sequence contents are not needed
+    let reader = match open_fastx(fastx_file) {
+	Ok(reader) => reader,
+	Err(_) => return Vec::new(),
+    };
+
+    let mut lengths: Vec<usize> = Vec::new();
+    let mut is_fastq = false;
+    let mut line_in_record: usize = 0;
+    for (line_nr, line) in reader.lines().map_while(Result::ok).enumerate() {
+	if line_nr == 0 {
+	    is_fastq = line.starts_with('@');
+	}
+	if is_fastq {
+	    // Sequence is always on the second line of a fastq record
+	    if line_in_record == 1 {
+		lengths.push(line.trim_end().len());
+	    }
+	    line_in_record = (line_in_record + 1) % 4;
+	} else if line.starts_with('>') {
+	    lengths.push(0);
+	} else if !lengths.is_empty() {
+	    *lengths.last_mut().unwrap() += line.trim_end().len();
+	}
+    }
+    return lengths;
+}
+
+pub fn approx_sequence_length(fastx_file: &String) -> f64 {
+    // File size stands in for the sequence length to avoid reading the input
+    let file_size = std::fs::metadata(fastx_file).map_or(0, |x| x.len()) as f64;
+    if is_compressed(fastx_file) { GZIP_RATIO*file_size } else { file_size }
+}
+
+pub fn check_kmer_size(
+    fastx_files: &[String],
+    kmer_size: usize,
+    tool: &str,
+) {
+    let too_short: Vec<&String> = fastx_files
+	.iter()
+	.filter(|x| {
+	    let lengths = contig_lengths(x);
+	    let n_short = lengths.iter().filter(|y| **y < kmer_size).count();
+	    if n_short > 0 {
+		debug!("{} contains {}/{} sequences shorter than {} k-mer size {}", x, n_short, lengths.len(), tool, kmer_size);
+	    }
+	    lengths.iter().all(|y| *y < kmer_size)
+	})
+	.collect();
+
+    if !too_short.is_empty() {
+	warn!("{} inputs contain no sequences longer than {} k-mer size {}:", too_short.len(), tool, kmer_size);
+	too_short.iter().for_each(|x| { warn!("\t{}", x) });
+    }
+}
+
+pub fn sequence_stats(fastx_file: &String) -> (usize, usize, usize, f64) {
+    // Total length, number of contigs, N50, and GC content
+    let reader = open_fastx(fastx_file).unwrap();
+
+    let mut lengths: Vec<usize> = Vec::new();
+    let mut n_gc: usize = 0;
+    let mut n_acgt: usize = 0;
+    let mut is_fastq = false;
+    let mut line_in_record: usize = 0;
+    for (line_nr, line) in reader.lines().map_while(Result::ok).enumerate() {
+	if line_nr == 0 {
+	    is_fastq = line.starts_with('@');
+	}
+	let is_sequence = if is_fastq {
+	    let is_seq_line = line_in_record == 1;
+	    line_in_record = (line_in_record + 1) % 4;
+	    if is_seq_line {
+		lengths.push(0);
+	    }
+	    is_seq_line
+	} else if line.starts_with('>') {
+	    lengths.push(0);
+	    false
+	} else {
+	    !lengths.is_empty()
+	};
+
+	if is_sequence {
+	    let seq = line.trim_end();
+	    *lengths.last_mut().unwrap() += seq.len();
+	    seq.bytes().for_each(|x| {
+		match x {
+		    b'G' | b'C' | b'g' | b'c' => { n_gc += 1; n_acgt += 1; },
+		    b'A' | b'T' | b'a' | b't' => { n_acgt += 1; },
+		    _ => (),
+		}
+	    });
+	}
+    }
+
+    let total_length: usize = lengths.iter().sum();
+    let gc_content = if n_acgt > 0 { n_gc as f64 / n_acgt as f64 } else { 0.0 };
+
+    return (total_length, lengths.len(), n50(&lengths), gc_content);
+}
+
+pub fn n50(lengths: &[usize]) -> usize {
+    let total_length: usize = lengths.iter().sum();
+    let mut cumulative_length: usize = 0;
+    return lengths
+	.iter()
+	.sorted_unstable_by(|a, b| b.cmp(a))
+	.find(|x| {
+	    cumulative_length += **x;
+	    2*cumulative_length >= total_length
+	})
+	.copied()
+	.unwrap_or(0);
+}
+
+pub fn filter_short_contigs(fastx_file: &String, min_contig_length: usize, out_file: &String) -> (usize, usize) {
+    // Writes the contigs of at least min_contig_length as fasta, returns the
+    // total length and the length of the removed contigs
+    let reader = open_fastx(fastx_file).unwrap();
+    let mut writer = BufWriter::new(std::fs::File::create(out_file).unwrap());
+
+    let mut total_length: usize = 0;
+    let mut removed_length: usize = 0;
+    let mut header: Option<String> = None;
+    let mut sequence: Vec<String> = Vec::new();
+    let mut write_record = |header: &Option<String>, sequence: &Vec<String>| {
+	if header.is_none() {
+	    return;
+	}
+	let length: usize = sequence.iter().map(|x| x.len()).sum();
+	total_length += length;
+	if length < min_contig_length {
+	    removed_length += length;
+	} else {
+	    writeln!(writer, ">{}", header.as_ref().unwrap()).unwrap();
+	    sequence.iter().for_each(|x| { writeln!(writer, "{}", x).unwrap() });
+	}
+    };
+
+    let mut is_fastq = false;
+    let mut line_in_record: usize = 0;
+    for (line_nr, line) in reader.lines().map_while(Result::ok).enumerate() {
+	if line_nr == 0 {
+	    is_fastq = line.starts_with('@');
+	}
+	let line = line.trim_end().to_string();
+	if is_fastq {
+	    match line_in_record {
+		0 => header = Some(line[1..].to_string()),
+		1 => {
+		    sequence.push(line);
+		    write_record(&header, &sequence);
+		    sequence.clear();
+		},
+		_ => (),
+	    }
+	    line_in_record = (line_in_record + 1) % 4;
+	} else if let Some(name) = line.strip_prefix('>') {
+	    write_record(&header, &sequence);
+	    header = Some(name.to_string());
+	    sequence.clear();
+	} else if header.is_some() {
+	    sequence.push(line);
+	}
+    }
+    if !is_fastq {
+	write_record(&header, &sequence);
+    }
+    drop(write_record);
+    writer.flush().unwrap();
+
+    return (total_length, removed_length);
+}
@@ -8,25 +8,75 @@
 //
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::time::Instant;
 
 use itertools::Itertools;
+use log::debug;
 use log::info;
 use log::trace;
+use log::warn;
 use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
 pub mod build;
 pub mod clust;
 pub mod dist;
+pub mod fastx;
+
+// How far below the ANI threshold --auto-screen sets skani's screen value.
+// The screen estimates ANI from marker k-mers so it needs some slack.
+const AUTO_SCREEN_MARGIN: f64 = 0.1;
+
+// Seed for the intermediate graph names with --reproducible
+const REPRODUCIBLE_SEED: u64 = 26203;
 
 #[derive(Clone)]
 pub struct PanaaniParams {
     pub batch_step: usize,
     pub batch_step_strategy: String,
     pub max_iters: usize,
+    // Stop the batched iterations once an iteration leaves the clusters
+    // unchanged, but not before min_iters of them have run
+    pub early_stop: bool,
+    pub min_iters: usize,
     pub temp_dir: String,
+    // Keep the intermediate graphs in temp_dir, they are removed after the final iteration otherwise
+    pub keep_temp: bool,
+    // random, sequential, or cluster-based names for the intermediate graphs
+    pub temp_naming: String,
     pub guided: bool,
+    pub guide_ani_threshold: Option<f32>,
+    pub guide_linkage_method: Option<kodama::Method>,
+    // Threads for the guide ANI pass, global thread count if None
+    pub guide_threads: Option<usize>,
+    // Coarse sketching resolution of the guide ANI pass
+    pub guide_subsampling: u16,
+    pub guide_compression: u16,
     pub external_clustering: Option<Vec<String>>,
     pub initial_batches: Option<Vec<String>>,
+    pub threshold_sweep: Option<Vec<f32>>,
+    pub cluster_prefix: String,
+    pub auto_screen: bool,
+    // Append clusters unchanged for finalized_after iterations to this file
+    pub emit_finalized_file: Option<String>,
+    pub finalized_after: usize,
+    // Collapse inputs above this ANI and aligned fraction before dereplicating
+    pub collapse_identical: Option<f32>,
+    // Fixed seed and sorted batches so that reruns produce the same output
+    pub reproducible: bool,
+    // Seeds the intermediate graph names and sorts the batches, overrides
+    // the seed used by reproducible
+    pub seed: Option<u64>,
+    // Write the clusters after each iteration to <dir>/iter_<n>.tsv
+    pub checkpoint_dir: Option<String>,
+    // Continue from the latest checkpoint in this directory
+    pub resume_from: Option<String>,
 }
 
 impl Default for PanaaniParams {
@@ -35,20 +85,110 @@ impl Default for PanaaniParams {
 	    batch_step: 50,
 	    batch_step_strategy: "linear".to_string(),
 	    max_iters: 10,
+	    early_stop: true,
+	    min_iters: 2,
 	    temp_dir: "./".to_string(),
+	    keep_temp: false,
+	    temp_naming: "random".to_string(),
 	    guided: false,
+	    guide_ani_threshold: None,
+	    guide_linkage_method: None,
+	    guide_threads: None,
+	    guide_subsampling: 2500,
+	    guide_compression: 2500,
 	    external_clustering: None,
 	    initial_batches: None,
+	    threshold_sweep: None,
+	    cluster_prefix: "panANI-".to_string(),
+	    auto_screen: false,
+	    emit_finalized_file: None,
+	    finalized_after: 2,
+	    collapse_identical: None,
+	    reproducible: false,
+	    seed: None,
+	    checkpoint_dir: None,
+	    resume_from: None,
         }
     }
 }
 
+pub struct DereplicateResult {
+    // Sequence and the final cluster it was assigned to
+    pub clusters: Vec<(String, String)>,
+    // Final cluster and the iteration where its membership last changed
+    pub stabilized_in: HashMap<String, usize>,
+    // Inputs that were unreadable or excluded from ANI estimation
+    pub skipped_inputs: Vec<String>,
+    // Whether the batches grew to cover all clusters within max_iters
+    pub converged: bool,
+    // Final cluster and the lowest ANI at which its members were merged
+    pub merge_heights: HashMap<String, f32>,
+    // Collapsed input and the representative it was replaced by
+    pub collapsed: HashMap<String, String>,
+    // Iterations run including the final one
+    pub iterations: usize,
+    // Seconds spent in ANI estimation, and in clustering and graph building
+    pub ani_secs: f64,
+    pub clustering_secs: f64,
+}
+
+pub struct IterationStats {
+    // Iteration that just finished, starting from 1
+    pub iteration: usize,
+    pub batch_size: usize,
+    // Clusters going into and coming out of the iteration
+    pub n_clusters_in: usize,
+    pub n_clusters_out: usize,
+    // Sequences whose cluster changed in the iteration
+    pub n_changed: usize,
+    // Seconds spent in ANI estimation, and in clustering and graph building
+    pub ani_secs: f64,
+    pub clustering_secs: f64,
+}
+
+// Called after each batched iteration, Break skips the remaining ones
+pub type IterationCallback<'a> = &'a mut dyn FnMut(&IterationStats, &HashMap<String, Vec<String>>) -> ControlFlow<()>;
+
+#[derive(Debug)]
+pub enum PanaaniError {
+    // Input or intermediate graph that skani could not sketch
+    SketchFailed(String),
+    // Sequence without a cluster in the assignments
+    MissingAssignment(String),
+    // Checkpoint that is missing or could not be parsed
+    InvalidCheckpoint(String),
+    // Intermediate graph that ggcat failed to build and the error
+    GraphFailed(String, String),
+    // Writing a checkpoint or progress file failed
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for PanaaniError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    PanaaniError::SketchFailed(x) => write!(f, "{} failed skani sketching, check the log for 'WARN - File {} is not a valid fasta/fastq file'", x, x),
+	    PanaaniError::MissingAssignment(x) => write!(f, "{} has no cluster assignment", x),
+	    PanaaniError::InvalidCheckpoint(x) => write!(f, "invalid checkpoint: {}", x),
+	    PanaaniError::GraphFailed(x, e) => write!(f, "building graph {} failed: {}", x, e),
+	    PanaaniError::Io(e) => write!(f, "{}", e),
+	}
+    }
+}
+
+impl std::error::Error for PanaaniError {}
+
+impl From<std::io::Error> for PanaaniError {
+    fn from(e: std::io::Error) -> PanaaniError {
+	PanaaniError::Io(e)
+    }
+}
+
 pub fn match_clustering_results(
     fastx_files: &[String],
     old_clusters: &[String],
     hclust_res: &[usize],
     out_prefix: &String,
-) -> Vec<String> {
+) -> Result<Vec<String>, PanaaniError> {
     let mut old_cluster_to_new_cluster: HashMap<&String, usize> = HashMap::new();
     fastx_files
         .iter()
@@ -58,12 +198,12 @@ pub fn match_clustering_results(
             old_cluster_to_new_cluster.insert(x.0, x.1.clone());
         });
 
-    let new_clusters: Vec<String> = old_clusters
+    let new_clusters: Result<Vec<String>, PanaaniError> = old_clusters
         .iter()
         .map(|x| {
-            out_prefix.to_owned()
-                + &old_cluster_to_new_cluster.get(&x).unwrap_or_else(|| { panic!("A fasta/fastq failed skani sketching!\nCheck log for records containing the message: 'WARN - File <path> is not a valid fasta/fastq file'.") } ).to_string()
-                + ".dbg.fasta"
+            Ok(out_prefix.to_owned()
+                + &old_cluster_to_new_cluster.get(&x).ok_or_else(|| PanaaniError::SketchFailed(x.clone()))?.to_string()
+                + ".dbg.fasta")
         })
         .collect();
 
@@ -87,30 +227,61 @@ pub fn assign_seqs(seqs: &[String], clusters: &[String]) -> HashMap::<String, Ve
     return cluster_contents;
 }
 
+fn cluster_ani(
+    prev_assignments: &HashMap<String, Vec<String>>,
+    skani_params: &Option<dist::SkaniParams>,
+    kodama_params: &Option<clust::KodamaParams>,
+) -> (Vec<f32>, Vec<String>) {
+    // Condensed dissimilarity matrix of the sorted clusters, the pairs are
+    // added as they are merged back from the --max-pairs-in-memory files.
+    // Also returns the clusters that were too small or could not be sketched.
+    info!("Calculating ANIs...");
+    let fastx_files: Vec<String> = prev_assignments.iter().map(|x| x.0.clone()).collect();
+    let records = dist::ani_record_stream_from_fastx_pairs(&fastx_files, &None, skani_params);
+    let skipped = records.skipped_inputs.clone();
+    let matrix = clust::ani_dissimilarity_matrix(
+        &fastx_files,
+        records.map(|x| (x.ref_file, x.query_file, x.ani, x.af_ref, x.af_query)),
+        kodama_params,
+    );
+    return (matrix, skipped);
+}
+
 pub fn dereplicate_iter(
     prev_assignments: &HashMap<String, Vec<String>>,
     out_prefix: &String,
     skani_params: &Option<dist::SkaniParams>,
     kodama_params: &Option<clust::KodamaParams>,
     ggcat_params: &Option<build::GGCATParams>,
-) -> HashMap<String, Vec<String>> {
+) -> Result<HashMap<String, Vec<String>>, PanaaniError> {
+    let (ani_result, _) = cluster_ani(prev_assignments, skani_params, kodama_params);
+    return Ok(consolidate_clusters(prev_assignments, ani_result, out_prefix, kodama_params, ggcat_params)?.0);
+}
+
+fn consolidate_clusters(
+    prev_assignments: &HashMap<String, Vec<String>>,
+    flattened_dissimilarity_matrix: Vec<f32>,
+    out_prefix: &String,
+    kodama_params: &Option<clust::KodamaParams>,
+    ggcat_params: &Option<build::GGCATParams>,
+) -> Result<(HashMap<String, Vec<String>>, HashMap<String, f32>), PanaaniError> {
     let seq_files = prev_assignments.iter().map(|x| x.1.clone()).flatten().collect::<Vec<String>>();
     let old_clusters = prev_assignments.iter().map(|x| vec![x.0.clone(); x.1.len()]).flatten().collect::<Vec<String>>();
+    let fastx_files: Vec<String> = old_clusters.iter().cloned().unique().collect();
 
-    info!("Calculating ANIs...");
-    let fastx_files = old_clusters.iter().cloned().unique().collect();
-    let ani_result = dist::ani_from_fastx_files(
-        &fastx_files,
-        skani_params,
-    );
+    // Clusters count as all of their members in average and Ward linkage,
+    // observations are in sorted order
+    let weights: Vec<usize> = fastx_files.iter().sorted().map(|x| prev_assignments.get(x).unwrap().len()).collect();
 
     info!("Building dendrogram...");
-    let hclust_res = clust::single_linkage_cluster(
-        &ani_result,
+    let (hclust_res, heights) = clust::single_linkage_cluster_with_heights(
+        flattened_dissimilarity_matrix,
+        fastx_files.len(),
+        Some(&weights),
         kodama_params,
     );
 
-    let mut new_clusters: Vec<String> = match_clustering_results(&fastx_files, &old_clusters, &hclust_res, out_prefix);
+    let mut new_clusters: Vec<String> = match_clustering_results(&fastx_files, &old_clusters, &hclust_res, out_prefix)?;
     let mut new_assignments = assign_seqs(&seq_files, &new_clusters);
     // Singleton clusters should have the same name as in the previous round
     // dumb hack
@@ -124,30 +295,147 @@ pub fn dereplicate_iter(
 	});
     new_assignments = assign_seqs(&seq_files, &new_clusters);
 
+    // Singletons were not merged with anything
+    let mut merge_heights: HashMap<String, f32> = HashMap::new();
+    heights.iter().enumerate().for_each(|x| {
+	let cluster = out_prefix.to_owned() + &x.0.to_string() + ".dbg.fasta";
+	if new_assignments.contains_key(&cluster) {
+	    merge_heights.insert(cluster, *x.1);
+	}
+    });
+
     info!("Building pangenome graphs...");
-    build::build_pangenome_representations(
-	&new_assignments,
-        ggcat_params,
-    );
+    build_graphs(&new_assignments, ggcat_params)?;
+
+    return Ok((new_assignments, merge_heights));
+}
 
-    return new_assignments;
+fn remove_temp_files(graph_files: &[String]) -> usize {
+    // Only the intermediate graphs this run built and their side files
+    let mut n_removed = 0;
+    graph_files.iter().for_each(|x| {
+	std::iter::once("").chain(build::SIDE_FILE_SUFFIXES).for_each(|suffix| {
+	    if std::fs::remove_file(x.clone() + suffix).is_ok() {
+		n_removed += 1;
+	    }
+	});
+    });
+    return n_removed;
 }
 
-fn guide_batching(seq_files: &[String], kodama_params: &Option<clust::KodamaParams>) -> Vec<String> {
-    let guide_params = dist::SkaniParams {
+fn same_partition(
+    old_contents: &HashMap<String, Vec<String>>,
+    new_contents: &HashMap<String, Vec<String>>,
+) -> bool {
+    // Cluster names change between iterations so only the member sets are compared
+    let canonical = |contents: &HashMap<String, Vec<String>>| -> Vec<Vec<String>> {
+	contents.values().map(|x| x.iter().sorted().cloned().collect()).sorted().collect()
+    };
+    return canonical(old_contents) == canonical(new_contents);
+}
+
+fn update_cluster_history(
+    old_contents: &HashMap<String, Vec<String>>,
+    new_contents: &HashMap<String, Vec<String>>,
+    iter: usize,
+    last_changed: &mut HashMap<String, usize>,
+) {
+    // Compare the membership of each sequence's cluster before and after the iteration
+    let mut old_members: HashMap<&String, Vec<&String>> = HashMap::new();
+    old_contents
+	.iter()
+	.for_each(|x| {
+	    let members = x.1.iter().sorted().collect::<Vec<&String>>();
+	    x.1.iter().for_each(|y| { old_members.insert(y, members.clone()); });
+	});
+
+    new_contents
+	.iter()
+	.for_each(|x| {
+	    let members = x.1.iter().sorted().collect::<Vec<&String>>();
+	    x.1.iter().for_each(|y| {
+		if old_members.get(y) != Some(&members) {
+		    last_changed.insert(y.clone(), iter);
+		}
+	    });
+	});
+}
+
+fn emit_finalized(
+    cluster_contents: &HashMap<String, Vec<String>>,
+    last_changed: &HashMap<String, usize>,
+    iter: usize,
+    finalized_after: usize,
+    emitted: &mut HashSet<String>,
+    out_file: &String,
+) -> Result<(), PanaaniError> {
+    // Appended and flushed every iteration so the file can be followed while running
+    let mut f = std::fs::OpenOptions::new().append(true).create(true).open(out_file)?;
+    // Clusters are named by an intermediate graph until the final iteration,
+    // so the group is identified by its first member instead
+    for x in cluster_contents.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+	let last_changes = x.1.iter().map(|y| last_changed.get(y).copied().ok_or_else(|| PanaaniError::MissingAssignment(y.clone()))).collect::<Result<Vec<usize>, PanaaniError>>()?;
+	if !x.1.iter().zip(last_changes.iter()).all(|y| !emitted.contains(y.0) && y.1 + finalized_after <= iter) {
+	    continue;
+	}
+	let members: Vec<&String> = x.1.iter().sorted().collect();
+	for y in members.iter() {
+	    writeln!(f, "{}\t{}\t{}", y, members[0], iter)?;
+	    emitted.insert((*y).clone());
+	}
+    }
+    f.flush()?;
+    return Ok(());
+}
+
+fn coarse_skani_params() -> dist::SkaniParams {
+    // Heavily subsampled sketches are enough to tell which inputs go together
+    return dist::SkaniParams {
         kmer_subsampling_rate: 2500,
         marker_compression_factor: 2500,
         clip_tails: true,
         ..Default::default()
     };
+}
 
-    let fastx_files: Vec<String> = seq_files.iter().cloned().collect();
-    let ani_result = dist::ani_from_fastx_files(
-        &fastx_files,
-        &Some(guide_params),
-    );
+fn guide_skani_params(my_params: &PanaaniParams, skani_params: &Option<dist::SkaniParams>) -> dist::SkaniParams {
+    // Sketches of the inputs and unchanged clusters are reused by later
+    // iterations. The cache is keyed by the sketching parameters so the
+    // coarse sketches can share a --sketch-cache with the regular ones.
+    let mut guide_params = coarse_skani_params();
+    guide_params.kmer_subsampling_rate = my_params.guide_subsampling;
+    guide_params.marker_compression_factor = my_params.guide_compression;
+    dist::check_compression_factors(guide_params.kmer_subsampling_rate, guide_params.marker_compression_factor);
+    guide_params.sketch_cache_dir = Some(skani_params
+	.as_ref()
+	.and_then(|x| x.sketch_cache_dir.clone())
+	.unwrap_or(my_params.temp_dir.to_string() + "/guide_sketches-" + &std::process::id().to_string()));
+    return guide_params;
+}
+
+fn guide_batching(seq_files: &[String], guide_params: &dist::SkaniParams, kodama_params: &Option<clust::KodamaParams>, threads: Option<usize>) -> Vec<String> {
+    let guide_params = guide_params.clone();
+
+    // Sorted to match the order of the clustering results
+    let fastx_files: Vec<String> = seq_files.iter().cloned().sorted().collect();
+    let ani_result = if threads.is_some() {
+	// Scoped pool so the guide pass can use fewer threads than the main passes
+	rayon::ThreadPoolBuilder::new()
+	    .num_threads(threads.unwrap())
+	    .thread_name(|i| format!("guide-thread-{}", i))
+	    .build()
+	    .unwrap()
+	    .install(|| dist::ani_from_fastx_files(&fastx_files, &Some(guide_params)))
+    } else {
+	dist::ani_from_fastx_files(
+            &fastx_files,
+            &Some(guide_params),
+	)
+    };
     let hclust_res = clust::single_linkage_cluster(
-        &ani_result,
+        &fastx_files,
+        &clust::ani_to_similarity(&ani_result, kodama_params),
+        None,
         kodama_params,
     );
 
@@ -163,24 +451,98 @@ fn guide_batching(seq_files: &[String], kodama_params: &Option<clust::KodamaPara
     return res;
 }
 
-pub fn dereplicate(
-    seq_files: &[String],
-    dereplicate_params: &Option<PanaaniParams>,
-    skani_params: &Option<dist::SkaniParams>,
-    kodama_params: &Option<clust::KodamaParams>,
+fn collapse_identical(seq_files: &[String], min_ani: f32) -> HashMap<String, String> {
+    // Groups of inputs above min_ani with both aligned fractions above min_ani,
+    // the largest input in each group represents the rest
+    let mut collapse_params = coarse_skani_params();
+    collapse_params.min_aligned_frac = min_ani as f64;
+    collapse_params.af_combine = "both".to_string();
+    let kodama_params = Some(clust::KodamaParams {
+	cutoff: min_ani,
+	method: kodama::Method::Single,
+	..Default::default()
+    });
+
+    let fastx_files: Vec<String> = seq_files.iter().cloned().unique().sorted().collect();
+    let ani_result = dist::ani_from_fastx_files(&fastx_files, &Some(collapse_params));
+    let groups = clust::single_linkage_cluster(&fastx_files, &clust::ani_to_similarity(&ani_result, &kodama_params), None, &kodama_params);
+
+    let mut group_members: HashMap<usize, Vec<String>> = HashMap::new();
+    fastx_files.iter().zip(groups.iter()).for_each(|x| { group_members.entry(*x.1).or_default().push(x.0.clone()); });
+
+    let mut representative_of: HashMap<String, String> = HashMap::new();
+    group_members.iter().filter(|x| x.1.len() > 1).for_each(|x| {
+	let representative = build::select_members(x.1, Some(1)).pop().unwrap();
+	x.1.iter().filter(|y| **y != representative).for_each(|y| { representative_of.insert(y.clone(), representative.clone()); });
+    });
+    return representative_of;
+}
+
+fn write_checkpoint(
+    cluster_contents: &HashMap<String, Vec<String>>,
+    iter: usize,
+    checkpoint_dir: &String,
+) -> std::io::Result<()> {
+    // Written under a temporary name so that a crash never leaves a partial checkpoint
+    let out_file = checkpoint_dir.to_string() + "/iter_" + &iter.to_string() + ".tsv";
+    let tmp_file = out_file.clone() + ".tmp";
+    let mut f = std::io::BufWriter::new(std::fs::File::create(&tmp_file)?);
+    for x in flatten_assignments(cluster_contents) {
+	writeln!(f, "{}\t{}", x.0, x.1)?;
+    }
+    f.flush()?;
+    std::fs::rename(&tmp_file, &out_file)?;
+    return Ok(());
+}
+
+fn read_checkpoints(checkpoint_dir: &String) -> Result<Vec<HashMap<String, Vec<String>>>, PanaaniError> {
+    // Consecutive checkpoints from iter_0.tsv, all of them are needed to
+    // replay the batch sizes and the cluster history
+    let mut checkpoints: Vec<HashMap<String, Vec<String>>> = Vec::new();
+    loop {
+	let in_file = checkpoint_dir.to_string() + "/iter_" + &checkpoints.len().to_string() + ".tsv";
+	let f = match std::fs::File::open(&in_file) {
+	    Ok(f) => f,
+	    Err(_) => break,
+	};
+	let mut seqs: Vec<String> = Vec::new();
+	let mut clusters: Vec<String> = Vec::new();
+	for line in BufReader::new(f).lines().map_while(Result::ok).filter(|x| !x.is_empty()) {
+	    let mut fields = line.split('\t');
+	    seqs.push(fields.next().unwrap().to_string());
+	    clusters.push(fields.next().ok_or_else(|| PanaaniError::InvalidCheckpoint(format!("line in {} has no cluster: {}", in_file, line)))?.to_string());
+	}
+	checkpoints.push(assign_seqs(&seqs, &clusters));
+    }
+    if checkpoints.is_empty() {
+	return Err(PanaaniError::InvalidCheckpoint(format!("no checkpoints found in {}, expected {}/iter_0.tsv", checkpoint_dir, checkpoint_dir)));
+    }
+    return Ok(checkpoints);
+}
+
+fn build_graphs(
+    cluster_contents: &HashMap<String, Vec<String>>,
     ggcat_params: &Option<build::GGCATParams>,
-) -> Vec<(String, String)> {
-    trace!("Dereplicate input contains {} sequences in {} clusters", seq_files.len(), seq_files.iter().unique().collect::<Vec<&String>>().len());
-    let my_params = dereplicate_params.clone().unwrap_or(PanaaniParams::default());
+) -> Result<(), PanaaniError> {
+    // The graphs are sketched in the next iteration, so a graph that failed
+    // to build without --strict is an error here
+    let (_, failed) = build::build_pangenome_representations(cluster_contents, ggcat_params);
+    return match failed.into_iter().next() {
+	Some((cluster, error)) => Err(PanaaniError::GraphFailed(cluster, error)),
+	None => Ok(()),
+    };
+}
 
-    let mut cluster_contents: HashMap<String, Vec<String>> = if my_params.external_clustering.is_some() {
+fn initial_assignments(
+    seq_files: &[String],
+    my_params: &PanaaniParams,
+    ggcat_params: &Option<build::GGCATParams>,
+) -> Result<HashMap<String, Vec<String>>, PanaaniError> {
+    if my_params.external_clustering.is_some() {
 	let mut external_clusters = my_params.external_clustering.as_ref().unwrap().clone();
 	let initial_contents = assign_seqs(seq_files, &external_clusters);
 	info!("Building {} external cluster representations...", initial_contents.len());
-	build::build_pangenome_representations(
-	    &initial_contents,
-            &ggcat_params,
-	);
+	build_graphs(&initial_contents, ggcat_params)?;
 	external_clusters
 	    .iter_mut()
 	    .zip(seq_files)
@@ -188,79 +550,583 @@ pub fn dereplicate(
 		*x.0 = x.1.clone();
 	    }
 	    });
-	assign_seqs(seq_files, &external_clusters)
+	Ok(assign_seqs(seq_files, &external_clusters))
     } else {
 	// Create hashmap mapping each cluster name to the sequences assigned to it
-	assign_seqs(seq_files, seq_files)
+	Ok(assign_seqs(seq_files, seq_files))
+    }
+}
+
+pub fn flatten_assignments(cluster_contents: &HashMap<String, Vec<String>>) -> Vec<(String, String)> {
+    return cluster_contents
+	.iter()
+	.map(|x| x.1.iter().cloned().zip(vec![x.0.clone(); x.1.len()]).collect::<Vec<(String, String)>>())
+	.flatten()
+        .sorted_by(|k1, k2| match k1.1.cmp(&k2.1) {
+            Ordering::Equal => k1.0.cmp(&k2.0),
+            other => other,
+        })
+	.collect();
+}
+
+pub fn refine(
+    seq_files: &[String],
+    dereplicate_params: &Option<PanaaniParams>,
+    skani_params: &Option<dist::SkaniParams>,
+    kodama_params: &Option<clust::KodamaParams>,
+    ggcat_params: &Option<build::GGCATParams>,
+) -> Result<Vec<(String, String)>, PanaaniError> {
+    let my_params = dereplicate_params.clone().unwrap_or(PanaaniParams::default());
+    let cluster_contents = initial_assignments(seq_files, &my_params, ggcat_params)?;
+
+    info!("Refining {} clusters...", cluster_contents.len());
+    let new_clusters = dereplicate_iter(
+	&cluster_contents,
+        &my_params.cluster_prefix,
+        skani_params,
+        kodama_params,
+        ggcat_params,
+    )?;
+
+    return Ok(flatten_assignments(&new_clusters));
+}
+
+pub fn merge_assignments(
+    runs: &[Vec<(String, String)>],
+    prefixes: &[String],
+) -> Vec<HashMap<String, Vec<String>>> {
+    // Cluster names get the prefix of their run so that names from
+    // different runs never collide, sequences keep their first assignment.
+    // Runs that share a prefix also get their run number.
+    let prefix_counts = prefixes.iter().counts();
+    let mut run_of_seq: HashMap<&String, usize> = HashMap::new();
+    return runs
+	.iter()
+	.zip(prefixes.iter())
+	.enumerate()
+	.map(|(i, (run, prefix))| {
+	    let prefix = if *prefix_counts.get(prefix).unwrap() > 1 { format!("{}{}_", prefix, i + 1) } else { prefix.clone() };
+	    let mut seqs: Vec<String> = Vec::new();
+	    let mut clusters: Vec<String> = Vec::new();
+	    run.iter().for_each(|x| {
+		match run_of_seq.get(&x.0) {
+		    Some(j) => warn!("{} is assigned in runs {} and {}, keeping the assignment from run {}", x.0, j + 1, i + 1, j + 1),
+		    None => {
+			run_of_seq.insert(&x.0, i);
+			seqs.push(x.0.clone());
+			// Singletons named after their only member are unique already
+			clusters.push(if x.0 == x.1 { x.1.clone() } else { prefix.clone() + &x.1 });
+		    },
+		}
+	    });
+	    assign_seqs(&seqs, &clusters)
+	})
+	.collect();
+}
+
+pub fn merge_close_clusters(
+    runs: &[HashMap<String, Vec<String>>],
+    skani_params: &Option<dist::SkaniParams>,
+    kodama_params: &Option<clust::KodamaParams>,
+) -> HashMap<String, Vec<String>> {
+    // Each cluster is represented by its largest member, clusters from
+    // different runs whose representatives are above the ANI threshold are
+    // merged. Pairs from the same run were already kept apart by that run.
+    let mut representative_of: HashMap<String, &String> = HashMap::new();
+    let mut run_of_representative: HashMap<String, usize> = HashMap::new();
+    runs.iter().enumerate().for_each(|(i, run)| {
+	run.iter().for_each(|x| {
+	    let representative = build::select_members(x.1, Some(1)).pop().unwrap();
+	    run_of_representative.insert(representative.clone(), i);
+	    representative_of.insert(representative, x.0);
+	});
+    });
+    let representatives: Vec<String> = representative_of.keys().cloned().sorted().collect();
+
+    let mut merged: HashMap<String, Vec<String>> = HashMap::new();
+    if representatives.len() < 2 {
+	runs.iter().for_each(|x| merged.extend(x.clone()));
+	return merged;
+    }
+
+    info!("Calculating ANIs between {} cluster representatives...", representatives.len());
+    let ani_result = dist::ani_from_fastx_files(&representatives, skani_params);
+    let similarities: Vec<(String, String, f32)> = clust::ani_to_similarity(&ani_result, kodama_params)
+	.into_iter()
+	.map(|x| {
+	    let same_run = run_of_representative.get(&x.0) == run_of_representative.get(&x.1);
+	    let ani = if same_run { 0.0 } else { x.2 };
+	    (x.0, x.1, ani)
+	})
+	.collect();
+    let (dend, labels) = clust::build_dendrogram(&representatives, &similarities, None, kodama_params);
+    let groups = clust::cluster_dendrogram(&dend, kodama_params);
+
+    // Merged clusters are named after all of their parts
+    let mut parts: HashMap<usize, Vec<&String>> = HashMap::new();
+    labels.iter().zip(groups.iter()).for_each(|x| parts.entry(*x.1).or_default().push(*representative_of.get(x.0).unwrap()));
+    let n_merged = parts.values().filter(|x| x.len() > 1).count();
+    info!("Merged {} clusters from different runs into {}", parts.values().filter(|x| x.len() > 1).map(|x| x.len()).sum::<usize>(), n_merged);
+
+    let cluster_of_run: HashMap<&String, usize> = runs.iter().enumerate().map(|(i, run)| run.keys().map(move |x| (x, i))).flatten().collect();
+    parts.values().for_each(|x| {
+	let name = x.iter().sorted().join("+");
+	let members = merged.entry(name).or_default();
+	x.iter().for_each(|y| members.extend(runs[*cluster_of_run.get(y).unwrap()].get(*y).unwrap().iter().cloned()));
+    });
+    return merged;
+}
+
+pub fn renumber_clusters(
+    cluster_contents: &HashMap<String, Vec<String>>,
+    cluster_prefix: &String,
+) -> HashMap<String, Vec<String>> {
+    // Clusters are numbered in the order of their alphabetically first member
+    return cluster_contents
+	.values()
+	.sorted_by_key(|x| x.iter().min())
+	.enumerate()
+	.map(|x| (cluster_prefix.to_owned() + &x.0.to_string(), x.1.clone()))
+	.collect();
+}
+
+fn sanitized_name(path: &str) -> String {
+    // File name of the path with anything unusual replaced by '_'
+    let file_name = std::path::Path::new(path).file_name().map_or(path.to_string(), |x| x.to_string_lossy().to_string());
+    return file_name
+	.chars()
+	.take(64)
+	.map(|x| if x.is_ascii_alphanumeric() || x == '-' || x == '_' || x == '.' { x } else { '_' })
+	.collect();
+}
+
+fn next_batch_size(batch_size: usize, batch_step: usize, batch_step_strategy: &str, n_remaining: usize) -> usize {
+    let mut next = match batch_step_strategy {
+        "linear" => batch_size + batch_step,
+        "double" => batch_size * 2,
+        &_ => batch_size + batch_step,
+    };
+
+    // If n_remaining/batch_size == 1 increase batch size so that
+    // the last chunk contains more than a single sequence.
+    while next > 0 && n_remaining % next == 1 {
+	next += 1;
+    }
+    return next;
+}
+
+fn n_comparisons_in_batches(n_seqs: usize, batch_size: usize) -> usize {
+    let n_pairs = |n: usize| n * n.saturating_sub(1) / 2;
+    return (n_seqs / batch_size) * n_pairs(batch_size) + n_pairs(n_seqs % batch_size);
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    return format!("{}h {:02}m {:02}s", secs / 3600, (secs % 3600) / 60, secs % 60);
+}
+
+fn log_eta(
+    n_remaining: usize,
+    batch_size: usize,
+    batch_step: usize,
+    my_params: &PanaaniParams,
+    iter: usize,
+    secs_per_comparison: f64,
+    secs_per_input: f64,
+) {
+    // Assumes no clusters merge from here on, so this errs on the long side
+    let mut n_comparisons: usize = 0;
+    let mut n_inputs: usize = 0;
+    let mut n_iters: usize = 0;
+    let mut planned_batch_size = batch_size;
+    while planned_batch_size < n_remaining && iter + n_iters < my_params.max_iters {
+	n_comparisons += n_comparisons_in_batches(n_remaining, planned_batch_size);
+	n_inputs += n_remaining;
+	n_iters += 1;
+	planned_batch_size = next_batch_size(planned_batch_size, batch_step, &my_params.batch_step_strategy, n_remaining);
+    }
+    // Final iteration compares everything that is left
+    n_comparisons += n_comparisons_in_batches(n_remaining, n_remaining);
+    n_inputs += n_remaining;
+
+    let eta = n_comparisons as f64 * secs_per_comparison + n_inputs as f64 * secs_per_input;
+    info!("Estimated time remaining {} for {} more iterations ({} comparisons at {:.2e} s, {} clusters at {:.2e} s)",
+	  format_duration(eta), n_iters + 1, n_comparisons, secs_per_comparison, n_inputs, secs_per_input);
+}
+
+pub fn dereplicate(
+    seq_files: &[String],
+    dereplicate_params: &Option<PanaaniParams>,
+    skani_params: &Option<dist::SkaniParams>,
+    kodama_params: &Option<clust::KodamaParams>,
+    ggcat_params: &Option<build::GGCATParams>,
+    mut on_iteration: Option<IterationCallback>,
+) -> Result<DereplicateResult, PanaaniError> {
+    trace!("Dereplicate input contains {} sequences in {} clusters", seq_files.len(), seq_files.iter().unique().collect::<Vec<&String>>().len());
+    let my_params = dereplicate_params.clone().unwrap_or(PanaaniParams::default());
+
+    if my_params.collapse_identical.is_some() {
+	let collapsed = collapse_identical(seq_files, my_params.collapse_identical.unwrap());
+	let representatives: Vec<String> = seq_files.iter().filter(|x| !collapsed.contains_key(*x)).cloned().collect();
+	info!("Collapsed {} inputs above ANI {} into their representatives, {} inputs remain", collapsed.len(), my_params.collapse_identical.unwrap(), representatives.len());
+
+	let mut representative_params = my_params.clone();
+	representative_params.collapse_identical = None;
+	let mut result = dereplicate(&representatives, &Some(representative_params), skani_params, kodama_params, ggcat_params, on_iteration)?;
+
+	// Collapsed inputs go in the same cluster as their representative
+	let mut cluster_of: HashMap<String, String> = HashMap::new();
+	result.clusters.iter().for_each(|x| { cluster_of.insert(x.0.clone(), x.1.clone()); });
+	let collapsed_clusters: Vec<(String, String)> = collapsed
+	    .iter()
+	    .map(|x| Ok((x.0.clone(), cluster_of.get(x.1).ok_or_else(|| PanaaniError::MissingAssignment(x.1.clone()))?.clone())))
+	    .collect::<Result<Vec<(String, String)>, PanaaniError>>()?;
+	result.clusters = result.clusters
+	    .into_iter()
+	    .chain(collapsed_clusters)
+	    .sorted_by(|k1, k2| match k1.1.cmp(&k2.1) {
+		Ordering::Equal => k1.0.cmp(&k2.0),
+		other => other,
+	    })
+	    .collect();
+	result.collapsed = collapsed;
+	return Ok(result);
+    }
+
+    // Skip chaining pairs that are too far apart to be merged
+    let skani_params = &if my_params.auto_screen {
+	let cutoff = kodama_params.as_ref().map_or(clust::KodamaParams::default().cutoff, |x| x.cutoff);
+	let mut params = skani_params.clone().unwrap_or(dist::SkaniParams::default());
+	params.screen_val = (cutoff as f64 - AUTO_SCREEN_MARGIN).max(0.0);
+	info!("Screening out pairs with ANI below {}", params.screen_val);
+	Some(params)
+    } else {
+	skani_params.clone()
     };
 
+    // Later iterations read the intermediate graphs so only the final
+    // graphs are limited by --build-limit and colored. Intermediate names
+    // can be reused by runs with other inputs so they are always rebuilt.
+    let intermediate_ggcat_params = &ggcat_params.clone().map(|mut x| { x.build_limit = None; x.colors = false; x.skip_existing = false; x });
+
+    let checkpoints: Vec<HashMap<String, Vec<String>>> = if my_params.resume_from.is_some() {
+	read_checkpoints(my_params.resume_from.as_ref().unwrap())?
+    } else {
+	Vec::new()
+    };
+    let mut cluster_contents: HashMap<String, Vec<String>> = if checkpoints.is_empty() {
+	initial_assignments(seq_files, &my_params, intermediate_ggcat_params)?
+    } else {
+	checkpoints[0].clone()
+    };
+    if my_params.checkpoint_dir.is_some() {
+	let checkpoint_dir = my_params.checkpoint_dir.as_ref().unwrap();
+	std::fs::create_dir_all(checkpoint_dir)?;
+	if checkpoints.is_empty() {
+	    // Later checkpoints from an earlier run would be replayed on resume
+	    let mut stale_iter = 1;
+	    while std::fs::remove_file(checkpoint_dir.to_string() + "/iter_" + &stale_iter.to_string() + ".tsv").is_ok() {
+		stale_iter += 1;
+	    }
+	}
+	write_checkpoint(&cluster_contents, 0, checkpoint_dir)?;
+    }
+
+    // Track the iteration where the cluster containing each sequence last changed
+    let mut last_changed: HashMap<String, usize> = HashMap::new();
+    seq_files.iter().for_each(|x| { last_changed.insert(x.clone(), 0); });
+    let mut emitted: HashSet<String> = HashSet::new();
+    if my_params.emit_finalized_file.is_some() {
+	std::fs::File::create(my_params.emit_finalized_file.as_ref().unwrap())?;
+    }
+
+    // Batches need at least one sequence to make progress
+    let batch_step = if my_params.batch_step == 0 {
+	warn!("--batch-step 0 would never finish, using 1 instead");
+	1
+    } else {
+	my_params.batch_step
+    };
+
+    // Guide batching only decides which sequences go together
+    let mut guide_params = kodama_params.clone().unwrap_or(clust::KodamaParams::default());
+    guide_params.cutoff = my_params.guide_ani_threshold.unwrap_or(guide_params.cutoff);
+    guide_params.method = my_params.guide_linkage_method.unwrap_or(guide_params.method);
+    guide_params.cutoff_mode = clust::CutoffMode::Height;
+    let guide_params = Some(guide_params);
+    let guide_skani_params = guide_skani_params(&my_params, skani_params);
+
+    // A number of clusters only makes sense for the final iteration, the
+    // batches are merged at the ANI threshold
+    let mut batch_kodama_params = kodama_params.clone().unwrap_or(clust::KodamaParams::default());
+    batch_kodama_params.cutoff_mode = clust::CutoffMode::Height;
+    let batch_kodama_params = Some(batch_kodama_params);
+
     let mut iter: usize = 0;
-    let mut batch_size = my_params.batch_step;
+    let mut batch_size = batch_step;
     let mut n_remaining: usize = cluster_contents.len();
+    if batch_size >= n_remaining {
+	info!("Skipping batched iterations, --batch-step {} covers all {} sequences", batch_size, n_remaining);
+    }
 
-    while batch_size < n_remaining && iter < my_params.max_iters {
-	info!("Iteration {} processing {} sequences in batches of {}...", iter + 1, n_remaining, batch_size);
-        let mut rng = rand::thread_rng();
+    // Observed work and time in the batched iterations for the ETA
+    let mut n_comparisons_done: usize = 0;
+    let mut n_inputs_done: usize = 0;
+    let mut comparison_secs: f64 = 0.0;
+    let mut consolidate_secs: f64 = 0.0;
+    // Batches across all iterations for --temp-naming sequential
+    let mut n_batches_run: usize = 0;
+    // Intermediate graphs written to temp_dir by this run
+    let mut temp_graphs: Vec<String> = Vec::new();
+    let graph_out_prefix = ggcat_params.as_ref().map_or(String::new(), |x| x.out_prefix.clone());
+    // Inputs that were too small or could not be sketched when they were compared
+    let mut skipped_inputs: Vec<String> = Vec::new();
+
+    // Intermediate graph names affect the final labels through their sort order
+    let mut rng: StdRng = if my_params.seed.is_some() {
+	StdRng::seed_from_u64(my_params.seed.unwrap())
+    } else if my_params.reproducible {
+	StdRng::seed_from_u64(REPRODUCIBLE_SEED)
+    } else {
+	StdRng::from_entropy()
+    };
+
+    let mut stopped = false;
+    let mut early_stopped = false;
 
+    // Replay the checkpointed iterations so that the batch sizes, cluster
+    // history and intermediate names continue as in an uninterrupted run
+    for (old_contents, new_contents) in checkpoints.iter().tuple_windows() {
+	let n_batches = old_contents.len().div_ceil(batch_size);
+	n_batches_run += n_batches;
+	if my_params.temp_naming != "sequential" && my_params.temp_naming != "cluster-based" && !my_params.keep_temp {
+	    (0..n_batches).for_each(|_| { rng.gen::<u64>(); });
+	}
+	update_cluster_history(old_contents, new_contents, iter + 1, &mut last_changed);
+	let unchanged = same_partition(old_contents, new_contents);
+	n_remaining = new_contents.len();
+	iter += 1;
+	batch_size = next_batch_size(batch_size, batch_step, &my_params.batch_step_strategy, n_remaining);
+	early_stopped = unchanged && my_params.early_stop && iter >= my_params.min_iters && batch_size < n_remaining;
+	if my_params.checkpoint_dir.is_some() && my_params.checkpoint_dir != my_params.resume_from {
+	    write_checkpoint(new_contents, iter, my_params.checkpoint_dir.as_ref().unwrap())?;
+	}
+    }
+    if !checkpoints.is_empty() {
+	cluster_contents = checkpoints.last().unwrap().clone();
+	info!("Resuming from iteration {} with {} clusters in batches of {}", iter, n_remaining, batch_size);
+	if early_stopped {
+	    info!("Converged after {} iterations", iter);
+	}
+    }
+
+    while !early_stopped && batch_size < n_remaining && iter < my_params.max_iters {
+	info!("Iteration {} processing {} sequences in batches of {}...", iter + 1, n_remaining, batch_size);
+	let (iter_start_ani_secs, iter_start_clustering_secs) = (comparison_secs, consolidate_secs);
+	if n_comparisons_done > 0 {
+	    log_eta(n_remaining, batch_size, batch_step, &my_params, iter,
+		    comparison_secs / n_comparisons_done as f64, consolidate_secs / n_inputs_done as f64);
+	}
 	let batch_assignments: Vec<String> = if iter == 0 && my_params.initial_batches.is_some() {
 	    my_params.initial_batches.as_ref().unwrap().clone()
 	} else if my_params.guided {
 	    let current_clusters: Vec<String> = cluster_contents.iter().map(|x| x.0.clone()).collect();
-	    guide_batching(&current_clusters, kodama_params)
+	    guide_batching(&current_clusters, &guide_skani_params, &guide_params, my_params.guide_threads)
+	} else if my_params.reproducible || my_params.seed.is_some() {
+	    cluster_contents.iter().map(|x| x.0.clone()).sorted().collect()
 	} else {
 	    cluster_contents.iter().map(|x| x.0.clone()).collect()
 	};
 
 	// horrible hack to use random file names within each batch
+	// --keep-temp names them by batch instead so they can be found later
+	// unless another --temp-naming is chosen
         let new_clusters: Vec<HashMap<String, Vec<String>>> = batch_assignments
             .chunks(batch_size)
-            .map(|x| {
+	    .enumerate()
+            .map(|(batch_nr, x)| {
 		let mut batch_inputs: HashMap<String, Vec<String>> = HashMap::new();
-		x.iter().for_each(|y| { batch_inputs.insert(y.clone(), cluster_contents.get(y).unwrap().clone()); });
-                dereplicate_iter(
+		for y in x {
+		    batch_inputs.insert(y.clone(), cluster_contents.get(y).ok_or_else(|| PanaaniError::MissingAssignment(y.clone()))?.clone());
+		}
+		let batch_id: String = match my_params.temp_naming.as_str() {
+		    "sequential" => {
+			n_batches_run += 1;
+			(n_batches_run - 1).to_string()
+		    },
+		    "cluster-based" => batch_nr.to_string() + "-" + &sanitized_name(x.iter().min().unwrap()),
+		    &_ => if my_params.keep_temp { batch_nr.to_string() } else { rng.gen::<u64>().to_string() },
+		};
+
+		let temp_prefix = my_params.temp_dir.to_string() + "/" + &iter.to_string() + "_" + &batch_id + "-";
+
+		// Same as dereplicate_iter but timed
+		let start = Instant::now();
+		let (ani_result, mut skipped) = cluster_ani(&batch_inputs, skani_params, &batch_kodama_params);
+		skipped_inputs.append(&mut skipped);
+		comparison_secs += start.elapsed().as_secs_f64();
+		let start = Instant::now();
+		let batch_clusters = consolidate_clusters(
 		    &batch_inputs,
-                    &(my_params.temp_dir.to_string() + "/" + &iter.to_string() + "_" + &(rng.gen::<u64>() as u64).to_string() + "-"),
-                    skani_params,
-                    kodama_params,
-                    ggcat_params,
-                )
+		    ani_result,
+                    &temp_prefix,
+                    &batch_kodama_params,
+                    intermediate_ggcat_params,
+                )?.0;
+		consolidate_secs += start.elapsed().as_secs_f64();
+		// Singletons keep their old name, only the new clusters have a graph
+		batch_clusters.keys().filter(|y| y.starts_with(&temp_prefix)).for_each(|y| temp_graphs.push(graph_out_prefix.clone() + y));
+		Ok(batch_clusters)
             })
-            .collect();
+            .collect::<Result<Vec<HashMap<String, Vec<String>>>, PanaaniError>>()?;
+	n_comparisons_done += n_comparisons_in_batches(n_remaining, batch_size);
+	n_inputs_done += n_remaining;
 
-	cluster_contents = assign_seqs(&new_clusters.iter().map(|x| x.iter().map(|y| y.1.clone()).flatten()).flatten().collect::<Vec<String>>(),
+	let new_contents = assign_seqs(&new_clusters.iter().map(|x| x.iter().map(|y| y.1.clone()).flatten()).flatten().collect::<Vec<String>>(),
 				       &new_clusters.iter().map(|x| x.iter().map(|y| vec![y.0.clone(); y.1.len()]).flatten()).flatten().collect::<Vec<String>>());
+	update_cluster_history(&cluster_contents, &new_contents, iter + 1, &mut last_changed);
+	let unchanged = same_partition(&cluster_contents, &new_contents);
+	cluster_contents = new_contents;
+	if my_params.checkpoint_dir.is_some() {
+	    write_checkpoint(&cluster_contents, iter + 1, my_params.checkpoint_dir.as_ref().unwrap())?;
+	}
+	if my_params.emit_finalized_file.is_some() {
+	    emit_finalized(&cluster_contents, &last_changed, iter + 1, my_params.finalized_after, &mut emitted, my_params.emit_finalized_file.as_ref().unwrap())?;
+	}
+
+	let stats = IterationStats {
+	    iteration: iter + 1,
+	    batch_size,
+	    n_clusters_in: n_remaining,
+	    n_clusters_out: cluster_contents.len(),
+	    n_changed: last_changed.values().filter(|x| **x == iter + 1).count(),
+	    ani_secs: comparison_secs - iter_start_ani_secs,
+	    clustering_secs: consolidate_secs - iter_start_clustering_secs,
+	};
 
 	n_remaining = cluster_contents.len();
         iter += 1;
-        match my_params.batch_step_strategy.as_str() {
-            "linear" => batch_size += my_params.batch_step,
-            "double" => batch_size *= 2,
-            &_ => batch_size += my_params.batch_step,
-        }
+	batch_size = next_batch_size(batch_size, batch_step, &my_params.batch_step_strategy, n_remaining);
 
-	// If n_remaining/batch_size == 1 increase batch size so that
-	// the last chunk contains more than a single sequence.
-	while n_remaining % batch_size == 1 {
-	    batch_size += 1;
+	// Library users can stop the batched iterations early, the final
+	// iteration still runs on whatever is left
+	if let Some(callback) = on_iteration.as_mut() {
+	    if callback(&stats, &cluster_contents).is_break() {
+		info!("Stopping batched iterations after iteration {} as requested", iter);
+		stopped = true;
+		break;
+	    }
 	}
+
+	// The final iteration still compares all remaining clusters
+	if unchanged && my_params.early_stop && iter >= my_params.min_iters && batch_size < n_remaining {
+	    info!("Converged after {} iterations", iter);
+	    early_stopped = true;
+	    break;
+	}
+    }
+    let converged = batch_size >= n_remaining || early_stopped;
+    let own_guide_cache = skani_params.as_ref().map_or(true, |x| x.sketch_cache_dir.is_none());
+    if my_params.guided && own_guide_cache && !my_params.keep_temp {
+	let _ = std::fs::remove_dir_all(guide_skani_params.sketch_cache_dir.as_ref().unwrap());
+    }
+    if !converged && !stopped {
+	warn!("Reached --max-iters {} with {} sequences remaining", my_params.max_iters, n_remaining);
     }
     info!("Final iteration processing {} sequences...", n_remaining);
+    if n_comparisons_done > 0 {
+	log_eta(n_remaining, n_remaining, batch_step, &my_params, my_params.max_iters,
+		comparison_secs / n_comparisons_done as f64, consolidate_secs / n_inputs_done as f64);
+    }
 
-    let final_clusters = dereplicate_iter(
+    let start = Instant::now();
+    let (final_ani, mut skipped) = cluster_ani(&cluster_contents, skani_params, kodama_params);
+    skipped_inputs.append(&mut skipped);
+    comparison_secs += start.elapsed().as_secs_f64();
+    if my_params.threshold_sweep.is_some() {
+	let sweep = clust::threshold_sweep(
+	    final_ani.clone(),
+	    cluster_contents.len(),
+	    my_params.threshold_sweep.as_ref().unwrap(),
+	    kodama_params,
+	);
+	sweep.iter().for_each(|x| { info!("ANI threshold {} produces {} clusters", x.0, x.1) });
+    }
+
+    // Show how the chosen threshold compares to standard ones on the final tree
+    let mut final_kodama_params = kodama_params.clone().unwrap_or(clust::KodamaParams::default());
+    final_kodama_params.report_granularity = true;
+    let start = Instant::now();
+    let (final_clusters, merge_heights) = consolidate_clusters(
 	&cluster_contents,
-        &"panANI-".to_string(),
-        skani_params,
-        kodama_params,
+	final_ani,
+        &my_params.cluster_prefix,
+        &Some(final_kodama_params),
         ggcat_params,
-    );
+    )?;
+    consolidate_secs += start.elapsed().as_secs_f64();
+    update_cluster_history(&cluster_contents, &final_clusters, iter + 1, &mut last_changed);
+
+    if !my_params.keep_temp && !temp_graphs.is_empty() {
+	let n_removed = remove_temp_files(&temp_graphs);
+	debug!("Removed {} intermediate files from {}", n_removed, my_params.temp_dir);
+    }
+
+    let mut stabilized_in: HashMap<String, usize> = HashMap::new();
+    for x in final_clusters.iter() {
+	let last_changes = x.1.iter().map(|y| last_changed.get(y).copied().ok_or_else(|| PanaaniError::MissingAssignment(y.clone()))).collect::<Result<Vec<usize>, PanaaniError>>()?;
+	stabilized_in.insert(x.0.clone(), *last_changes.iter().max().unwrap());
+    }
+
+    let clusters = flatten_assignments(&final_clusters);
 
-    return final_clusters
+    // Later iterations compare the intermediate graphs, only the inputs are reported
+    let skipped_inputs: Vec<String> = seq_files
 	.iter()
-	.map(|x| x.1.iter().cloned().zip(vec![x.0.clone(); x.1.len()]).collect::<Vec<(String, String)>>())
-	.flatten()
-        .sorted_by(|k1, k2| match k1.1.cmp(&k2.1) {
-            Ordering::Equal => k1.0.cmp(&k2.0),
-            other => other,
-        })
+	.filter(|x| skipped_inputs.contains(x))
+	.unique()
+	.cloned()
 	.collect();
+
+    return Ok(DereplicateResult {
+	clusters, stabilized_in, skipped_inputs, converged, merge_heights,
+	collapsed: HashMap::new(),
+	iterations: iter + 1,
+	ani_secs: comparison_secs,
+	clustering_secs: consolidate_secs,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_assignments_same_prefix() {
+	// Two runs named clusters.tsv in different directories
+	let runs: Vec<Vec<(String, String)>> = vec![
+	    vec![("a1.fa".to_string(), "panANI-0".to_string()), ("a2.fa".to_string(), "panANI-0".to_string())],
+	    vec![("b1.fa".to_string(), "panANI-0".to_string()), ("b2.fa".to_string(), "panANI-0".to_string())],
+	];
+	let prefixes = vec!["clusters_".to_string(), "clusters_".to_string()];
+	let merged: HashMap<String, Vec<String>> = merge_assignments(&runs, &prefixes).into_iter().flatten().collect();
+	assert_eq!(merged.len(), 2);
+	assert_eq!(merged.get("clusters_1_panANI-0").unwrap().iter().sorted().collect::<Vec<&String>>(), vec!["a1.fa", "a2.fa"]);
+	assert_eq!(merged.get("clusters_2_panANI-0").unwrap().iter().sorted().collect::<Vec<&String>>(), vec!["b1.fa", "b2.fa"]);
+
+	let renumbered = renumber_clusters(&merged, &"panANI-".to_string());
+	assert_eq!(renumbered.len(), 2);
+    }
+
+    #[test]
+    fn emit_finalized_missing_member() {
+	// Members from a checkpoint that are not among the current inputs
+	let out_file = std::env::temp_dir().join(format!("panaani-test-{}-finalized.tsv", std::process::id())).to_string_lossy().to_string();
+	let cluster_contents: HashMap<String, Vec<String>> = [("c".to_string(), vec!["a.fa".to_string(), "b.fa".to_string()])].into_iter().collect();
+	let last_changed: HashMap<String, usize> = [("a.fa".to_string(), 0)].into_iter().collect();
+	let result = emit_finalized(&cluster_contents, &last_changed, 3, 2, &mut HashSet::new(), &out_file);
+	let _ = std::fs::remove_file(&out_file);
+
+	assert!(matches!(result, Err(PanaaniError::MissingAssignment(x)) if x == "b.fa"));
+    }
 }
@@ -9,10 +9,19 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Write;
 
+use clap::CommandFactory;
 use clap::Parser;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
 use itertools::Itertools;
-use log::{info, Record, Level, Metadata};
+use log::{info, warn, Record, Level, Metadata};
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
 
@@ -20,6 +29,17 @@ mod build;
 mod cli;
 mod clust;
 mod dist;
+mod fastx;
+
+// Exit codes for `dereplicate`, checked in this order. Panics exit with 101.
+const EXIT_SKIPPED_INPUTS: i32 = 3;
+const EXIT_NO_CLUSTERS: i32 = 4;
+const EXIT_NOT_CONVERGED: i32 = 5;
+// Exit code for `build` when some graphs failed without --strict
+const EXIT_FAILED_GRAPHS: i32 = 6;
+
+// Distance file lines parsed in parallel at a time
+const RECORDS_PER_CHUNK: usize = 100000;
 
 struct Logger;
 
@@ -56,34 +76,319 @@ fn init(threads: usize, log_max_level: usize) {
         .unwrap();
 }
 
-fn read_input_list(input_list_file: &String) -> Vec<String> {
+fn show_progress(verbose: bool, no_progress: bool) -> bool {
+    // Progress bars would pollute captured logs
+    verbose && !no_progress && std::io::stderr().is_terminal()
+}
+
+fn parse_linkage_method(linkage_method: &Option<String>) -> kodama::Method {
+    if linkage_method.is_some() {
+        match linkage_method.as_ref().unwrap().as_str() {
+            "single" => kodama::Method::Single,
+            "complete" => kodama::Method::Complete,
+            "average" => kodama::Method::Average,
+            "weighted" => kodama::Method::Weighted,
+            "ward" => kodama::Method::Ward,
+            "centroid" => kodama::Method::Centroid,
+            "median" => kodama::Method::Median,
+            &_ => kodama::Method::Single,
+        }
+    } else {
+        kodama::Method::Single
+    }
+}
+
+fn parse_unitig_type(unitig_type: &Option<String>) -> ggcat_api::ExtraElaboration {
+    if unitig_type.is_some() {
+        match unitig_type.as_ref().unwrap().as_str() {
+            "greedymatchtigs" => ggcat_api::ExtraElaboration::GreedyMatchtigs,
+            "unitiglinks" => ggcat_api::ExtraElaboration::UnitigLinks,
+            "eulertigs" => ggcat_api::ExtraElaboration::Eulertigs,
+            "pathtigs" => ggcat_api::ExtraElaboration::Pathtigs,
+            &_ => ggcat_api::ExtraElaboration::GreedyMatchtigs,
+        }
+    } else {
+        ggcat_api::ExtraElaboration::GreedyMatchtigs
+    }
+}
+
+// Parameters shared by the subcommands that sketch, compare, cluster or build
+fn sketch_params_from_args(args: &cli::SketchArgs) -> panaani::dist::SkaniParams {
+    dist::check_compression_factors(args.kmer_subsampling_rate, args.marker_compression_factor);
+    panaani::dist::SkaniParams {
+	kmer_size: args.skani_kmer_size,
+	kmer_subsampling_rate: args.kmer_subsampling_rate,
+	marker_compression_factor: args.marker_compression_factor,
+	min_contig_length: args.min_contig_length,
+	..Default::default()
+    }
+}
+
+fn skani_params_from_args(args: &cli::AniArgs) -> panaani::dist::SkaniParams {
+    panaani::dist::SkaniParams {
+	rescue_small: args.rescue_small,
+	min_genome_length: args.min_genome_length,
+	sketch_cache_dir: args.sketch_cache_dir.clone(),
+
+	clip_tails: args.clip_tails,
+	median: args.median,
+	adjust_ani: args.adjust_ani,
+
+	min_aligned_frac: args.min_aligned_frac,
+	min_aligned_frac_ref: args.min_aligned_frac_ref,
+	min_aligned_frac_query: args.min_aligned_frac_query,
+	af_combine: args.af_combine.clone(),
+	max_ani: args.max_ani,
+	screen_val: dist::screen_val(args.screen),
+	..sketch_params_from_args(&args.sketch)
+    }
+}
+
+fn kodama_params_from_args(args: &cli::ClusteringArgs) -> panaani::clust::KodamaParams {
+    panaani::clust::KodamaParams {
+	cutoff: args.ani_threshold,
+	cutoff_mode: args.num_clusters.map_or(panaani::clust::CutoffMode::Height, panaani::clust::CutoffMode::NumClusters),
+	method: parse_linkage_method(&args.linkage_method),
+	distance_metric: args.distance_metric.clone(),
+	inclusive_cutoff: !args.exclusive_threshold,
+	..Default::default()
+    }
+}
+
+fn ggcat_params_from_args(args: &cli::GraphArgs) -> panaani::build::GGCATParams {
+    panaani::build::GGCATParams {
+	kmer_size: args.ggcat_kmer_size,
+	kmer_min_multiplicity: args.kmer_min_multiplicity,
+	min_multiplicity_per_input: if args.multiplicity_map_file.is_some() { read_multiplicity_map(args.multiplicity_map_file.as_ref().unwrap()) } else { HashMap::new() },
+	colors: args.colors,
+	skip_existing: !args.force_rebuild,
+	minimizer_length: args.minimizer_length,
+	no_reverse_complement: args.no_reverse_complement,
+	unitig_type: parse_unitig_type(&args.unitig_type),
+	intermediate_compression_level: args.intermediate_compression_level,
+	..Default::default()
+    }
+}
+
+fn skip_header_lines<R: Read>(f: R) -> BufReader<R> {
+    // Only the provenance lines at the top are comments, later lines
+    // starting with '#' are data (e.g. file names)
+    let mut reader = BufReader::new(f);
+    while reader.fill_buf().unwrap().first() == Some(&b'#') {
+	let mut line = String::new();
+	reader.read_line(&mut line).unwrap();
+    }
+    return reader;
+}
+
+fn resolve_list_path(path: &str, input_list_file: &String, relative_to_list: bool) -> String {
+    // Relative paths in the list point to files next to the list itself
+    let path_buf = std::path::Path::new(path);
+    if !relative_to_list || path_buf.is_absolute() {
+	return path.to_string();
+    }
+    let list_dir = std::path::Path::new(input_list_file).parent().unwrap_or(std::path::Path::new(""));
+    return list_dir.join(path_buf).to_string_lossy().to_string();
+}
+
+fn read_input_list(input_list_file: &String, relative_to_list: bool) -> Vec<String> {
     let f = std::fs::File::open(input_list_file).unwrap();
     let mut reader = csv::ReaderBuilder::new()
         .delimiter(b'\t')
         .has_headers(false)
-        .from_reader(f);
+        .flexible(true)
+        .from_reader(skip_header_lines(f));
 
     let mut seq_files: Vec<String> = Vec::new();
     reader.records().into_iter().for_each(|line| {
         let record = line.unwrap();
-	seq_files.push(record[0].to_string().clone());
+	seq_files.push(resolve_list_path(&record[0], input_list_file, relative_to_list));
     });
     seq_files
 }
 
+fn collect_inputs(seq_files: &[String], input_list: &Option<String>, relative_to_list: bool) -> Vec<String> {
+    // Positional files come first, followed by the list, without duplicates
+    let mut seq_files_in: Vec<String> = seq_files.to_vec();
+    if input_list.is_some() {
+	seq_files_in.append(read_input_list(input_list.as_ref().unwrap(), relative_to_list).as_mut());
+    }
+    let n_inputs = seq_files_in.len();
+    let unique_files: Vec<String> = seq_files_in.into_iter().unique().collect();
+    if unique_files.len() < n_inputs {
+	warn!("Ignored {} duplicate input files", n_inputs - unique_files.len());
+    }
+    return unique_files;
+}
+
+fn read_sketch_list(input_list_file: &String, relative_to_list: bool) -> HashMap<String, String> {
+    // Optional second column in the input list points to a precomputed sketch
+    let f = std::fs::File::open(input_list_file).unwrap();
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(skip_header_lines(f));
+
+    let mut sketch_files: HashMap<String, String> = HashMap::new();
+    reader.records().into_iter().for_each(|line| {
+        let record = line.unwrap();
+	if record.len() > 1 && !record[1].is_empty() {
+	    sketch_files.insert(resolve_list_path(&record[0], input_list_file, relative_to_list),
+				resolve_list_path(&record[1], input_list_file, relative_to_list));
+	}
+    });
+    sketch_files
+}
+
+fn sketch_sources(seq_files_in: &[String], skani_params: &mut panaani::dist::SkaniParams) -> Vec<String> {
+    // Graphs are built from the sequences the sketches were made from
+    seq_files_in
+	.iter()
+	.map(|x| {
+	    if dist::is_sketch_file(x) {
+		let seq_file = dist::sketch_source(x);
+		skani_params.sketch_files.insert(seq_file.clone(), x.clone());
+		seq_file
+	    } else {
+		x.clone()
+	    }
+	})
+	.unique()
+	.collect()
+}
+
+fn read_list_clusters(seq_files_in: &[String], input_list_file: &String, relative_to_list: bool) -> Vec<String> {
+    // Second column of the input list is the initial cluster, in the order of the inputs
+    let f = std::fs::File::open(input_list_file).unwrap();
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(skip_header_lines(f));
+
+    let mut seq_assignments: HashMap<String, String> = HashMap::new();
+    reader.records().into_iter().for_each(|line| {
+        let record = line.unwrap();
+	if record.len() > 1 && !record[1].is_empty() {
+	    seq_assignments.insert(resolve_list_path(&record[0], input_list_file, relative_to_list), record[1].to_string());
+	}
+    });
+    return seq_files_in
+	.iter()
+	.map(|x| seq_assignments.get(x).unwrap_or_else(|| { panic!("Input sequence {} has no cluster in {}!", x, input_list_file) }).clone())
+	.collect();
+}
+
+fn check_sketch_memory(estimate: f64, n_inputs: usize, memory: u32) {
+    info!("Estimated peak sketch memory {:.2} GB for {} inputs", estimate, n_inputs);
+    if estimate > memory as f64 {
+	warn!("Estimated sketch memory {:.2} GB exceeds --memory {} GB, consider increasing --kmer-subsampling-rate", estimate, memory);
+    }
+}
+
+fn largest_batch(seq_files: &[String], batch_size: usize) -> Vec<String> {
+    // Dereplicate sketches one batch at a time, the largest inputs bound the peak
+    return seq_files
+	.iter()
+	.map(|x| (fastx::approx_sequence_length(x), x))
+	.sorted_by(|a, b| b.0.total_cmp(&a.0))
+	.take(batch_size.max(1))
+	.map(|x| x.1.clone())
+	.collect();
+}
+
+fn write_used_members(files_in_cluster: &HashMap<String, Vec<String>>, members_per_cluster: Option<usize>, out_file: &String) {
+    // Members that went into each graph, singletons have no graph
+    let mut f = std::fs::File::create(out_file).unwrap();
+    files_in_cluster
+	.iter()
+	.filter(|x| x.1.len() > 1)
+	.sorted_by(|k1, k2| k1.0.cmp(&k2.0))
+	.for_each(|x| {
+	    panaani::build::select_members(x.1, members_per_cluster).iter().for_each(|y| { writeln!(f, "{}\t{}", x.0, y).unwrap() });
+	});
+}
+
+fn read_ref_thresholds(ref_thresholds_file: &String) -> HashMap<String, f32> {
+    let f = std::fs::File::open(ref_thresholds_file).unwrap();
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .from_reader(skip_header_lines(f));
+
+    let mut ref_thresholds: HashMap<String, f32> = HashMap::new();
+    reader.records().into_iter().for_each(|line| {
+        let record = line.unwrap();
+	ref_thresholds.insert(record[0].to_string(), record[1].parse::<f32>().unwrap());
+    });
+    ref_thresholds
+}
+
+fn best_matches(
+    query_dists: &[(String, String, f32)],
+    threshold_of: impl Fn(&String) -> f32,
+) -> HashMap<String, (String, f32, bool)> {
+    // Closest reference among those whose own threshold the query passes,
+    // ambiguous if it passes more than one. Queries that pass none keep their
+    // closest reference.
+    let mut best_match: HashMap<String, (String, f32, bool)> = HashMap::new();
+    query_dists.iter().for_each(|x| {
+	let passes = x.2 > threshold_of(&x.1);
+	match best_match.get_mut(&x.0) {
+	    None => { best_match.insert(x.0.clone(), (x.1.clone(), x.2, false)); },
+	    Some(prev_match) => {
+		let prev_passes = prev_match.1 > threshold_of(&prev_match.0);
+		let assigned_twice: bool = (passes && prev_passes) || prev_match.2;
+		if (passes && !prev_passes) || (passes == prev_passes && x.2 > prev_match.1) {
+		    *prev_match = (x.1.clone(), x.2, assigned_twice);
+		} else {
+		    prev_match.2 = assigned_twice;
+		}
+	    },
+	}
+    });
+    best_match
+}
+
+fn read_multiplicity_map(multiplicity_map_file: &String) -> HashMap<String, u64> {
+    let f = std::fs::File::open(multiplicity_map_file).unwrap();
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .from_reader(skip_header_lines(f));
+
+    let mut min_multiplicities: HashMap<String, u64> = HashMap::new();
+    reader.records().into_iter().for_each(|line| {
+        let record = line.unwrap();
+	min_multiplicities.insert(record[0].to_string(), record[1].parse::<u64>().unwrap());
+    });
+    min_multiplicities
+}
+
 fn read_seq_assignments(seq_files_in: &[String], seq_assignments_file: &String) -> Vec<(String, String)> {
-    let f = std::fs::File::open(seq_assignments_file).unwrap();
+    // `-` reads the assignments from stdin
+    let f: Box<dyn std::io::Read> = if seq_assignments_file == "-" {
+	Box::new(std::io::stdin())
+    } else {
+	Box::new(std::fs::File::open(seq_assignments_file).unwrap())
+    };
     let mut reader = csv::ReaderBuilder::new()
         .delimiter(b'\t')
         .has_headers(false)
-        .from_reader(f);
+        .from_reader(skip_header_lines(f));
 
     // Read the cluster assignments into a HashMap to get the order correct
     let mut seq_assignments: HashMap<String, String> = HashMap::new();
+    let mut seqs_in_file: Vec<String> = Vec::new();
     reader.records().into_iter().for_each(|line| {
         let record = line.unwrap();
 	seq_assignments.insert(record[0].to_string(), record[1].to_string());
+	seqs_in_file.push(record[0].to_string());
     });
+
+    // Use all sequences in the file if no inputs were given
+    let seq_files_in: &[String] = if seq_files_in.is_empty() { &seqs_in_file } else { seq_files_in };
     return seq_files_in
 	.iter()
 	.map(|x| (x.clone(), seq_assignments.get(x).unwrap_or_else(|| { panic!("Input sequence {} was not found in {}!", x, seq_assignments_file) }).clone()))
@@ -94,6 +399,308 @@ fn read_seq_assignments(seq_files_in: &[String], seq_assignments_file: &String)
 	.collect::<Vec<(String, String)>>();
 }
 
+// Arguments that only change where the results go or how fast they are
+// computed, left out of the params hash
+const RUNTIME_ARGS: [&str; 31] = [
+    "threads", "memory", "guide_threads", "verbose", "no_progress", "header",
+    "temp_dir_path", "keep_temp", "keep_decompressed", "checkpoint_dir", "resume_from",
+    "max_pairs_in_memory", "sketch_cache_dir", "query_batch_size", "intermediate_compression_level",
+    "output_file", "out_dir", "out_prefix", "binary_output_file", "cluster_history_file",
+    "collapsed_file", "dedup_queries_file", "detailed_file", "dist_out_file", "emit_finalized_file",
+    "merge_heights_file", "newick_file", "save_dendrogram_file", "skipped_graphs_file", "summary_json_file",
+    "used_members_file",
+];
+
+fn resolved_params(matches: &clap::ArgMatches) -> String {
+    // Values as clap resolved them, defaults included, so the hash does not
+    // depend on how the command line was spelled
+    let (name, args) = match matches.subcommand() {
+	Some(x) => x,
+	None => return String::new(),
+    };
+    // Argument groups only list which of their arguments were given
+    let groups: Vec<String> = cli::Cli::command().find_subcommand(name).unwrap().get_groups().map(|x| x.get_id().to_string()).collect();
+    let mut params: Vec<String> = vec![name.to_string()];
+    args.ids().map(|x| x.as_str()).filter(|x| !RUNTIME_ARGS.contains(x) && !groups.contains(&x.to_string())).sorted().for_each(|id| {
+	if let Ok(Some(values)) = args.try_get_raw(id) {
+	    params.push(id.to_string() + "=" + &values.map(|x| x.to_string_lossy()).join(","));
+	}
+    });
+    return params.join("\n");
+}
+
+fn params_hash(params: &str) -> u64 {
+    // FNV-1a, stable across platforms and Rust versions
+    params.bytes().fold(0xcbf29ce484222325, |hash, x| (hash ^ x as u64).wrapping_mul(0x100000001b3))
+}
+
+fn open_output(output_file: &Option<String>) -> Box<dyn Write> {
+    // Results go to stdout unless --output-file is given. Callers flush
+    // before exiting since std::process::exit skips the destructors.
+    return match output_file {
+	Some(file) => Box::new(BufWriter::new(std::fs::File::create(file).unwrap())),
+	None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+}
+
+fn print_header(cli: &cli::Cli, out: &mut impl Write) {
+    if cli.header {
+	writeln!(out, "# panaani v{}, params hash {:016x}", env!("CARGO_PKG_VERSION"), params_hash(&resolved_params(&cli::Cli::command().get_matches()))).unwrap();
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    value.chars().for_each(|x| {
+	match x {
+	    '"' => escaped.push_str("\\\""),
+	    '\\' => escaped.push_str("\\\\"),
+	    '\n' => escaped.push_str("\\n"),
+	    '\t' => escaped.push_str("\\t"),
+	    x if (x as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", x as u32)),
+	    x => escaped.push(x),
+	}
+    });
+    escaped.push('"');
+    return escaped;
+}
+
+fn json_number(value: f32) -> String {
+    // JSON has no NaN or infinity, e.g. the merge height of a singleton
+    if value.is_finite() {
+	return value.to_string();
+    }
+    return "null".to_string();
+}
+
+fn json_list(values: &[String]) -> String {
+    return "[".to_string() + &values.iter().map(|x| json_string(x)).join(", ") + "]";
+}
+
+fn write_summary_json(
+    result: &panaani::DereplicateResult,
+    params_hash: u64,
+    total_secs: f64,
+    out_file: &String,
+) {
+    // Hand written since the output is small and flat
+    let clusters = &result.clusters;
+    let cluster_sizes = clusters.iter().counts_by(|x| &x.1);
+    let mut f = std::fs::File::create(out_file).unwrap();
+    writeln!(f, "{{").unwrap();
+    writeln!(f, "  \"version\": {},", json_string(env!("CARGO_PKG_VERSION"))).unwrap();
+    writeln!(f, "  \"params_hash\": \"{:016x}\",", params_hash).unwrap();
+    writeln!(f, "  \"n_inputs\": {},", clusters.len()).unwrap();
+    writeln!(f, "  \"n_clusters\": {},", cluster_sizes.len()).unwrap();
+    writeln!(f, "  \"n_singletons\": {},", cluster_sizes.values().filter(|x| **x == 1).count()).unwrap();
+    writeln!(f, "  \"iterations\": {},", result.iterations).unwrap();
+    writeln!(f, "  \"converged\": {},", result.converged).unwrap();
+    writeln!(f, "  \"timings\": {{\"ani_secs\": {:.3}, \"clustering_secs\": {:.3}, \"total_secs\": {:.3}}},",
+	     result.ani_secs, result.clustering_secs, total_secs).unwrap();
+    writeln!(f, "  \"skipped_inputs\": {},", json_list(&result.skipped_inputs)).unwrap();
+    writeln!(f, "  \"collapsed\": {{{}}},", result.collapsed.iter().sorted().map(|x| json_string(x.0) + ": " + &json_string(x.1)).join(", ")).unwrap();
+    writeln!(f, "  \"merge_heights\": {{{}}},", result.merge_heights.iter().sorted_by(|a, b| a.0.cmp(b.0)).map(|x| json_string(x.0) + ": " + &json_number(*x.1)).join(", ")).unwrap();
+    writeln!(f, "  \"stabilized_in\": {{{}}},", result.stabilized_in.iter().sorted().map(|x| json_string(x.0) + ": " + &x.1.to_string()).join(", ")).unwrap();
+    writeln!(f, "  \"clusters\": [").unwrap();
+    clusters.iter().enumerate().for_each(|x| {
+	writeln!(f, "    [{}, {}]{}", json_string(&x.1.0), json_string(&x.1.1), if x.0 + 1 < clusters.len() { "," } else { "" }).unwrap();
+    });
+    writeln!(f, "  ]").unwrap();
+    writeln!(f, "}}").unwrap();
+}
+
+fn print_clusters(clusters: &[(String, String)], output_format: &str, out: &mut impl Write) {
+    match output_format {
+	"drep" => {
+	    // dRep Cdb.csv identifies genomes by their file name
+	    writeln!(out, "genome,secondary_cluster").unwrap();
+	    clusters.iter().for_each(|x| {
+		let genome = std::path::Path::new(&x.0).file_name().map_or(x.0.clone(), |y| y.to_string_lossy().to_string());
+		writeln!(out, "{},{}", genome, x.1).unwrap()
+	    });
+	},
+	"partition" => {
+	    // One cluster per row, rows in order of their smallest member
+	    let cluster_contents = panaani::assign_seqs(&clusters.iter().map(|x| x.0.clone()).collect::<Vec<String>>(),
+							&clusters.iter().map(|x| x.1.clone()).collect::<Vec<String>>());
+	    cluster_contents
+		.values()
+		.map(|x| x.iter().sorted().cloned().collect::<Vec<String>>())
+		.sorted()
+		.for_each(|x| writeln!(out, "{}", x.join("\t")).unwrap());
+	},
+	&_ => clusters.iter().for_each(|x| writeln!(out, "{}\t{}", x.0, x.1).unwrap()),
+    }
+}
+
+fn read_pairs(seq_files_in: &[String], pairs_file: &String) -> Vec<(String, String)> {
+    let f = std::fs::File::open(pairs_file).unwrap();
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .from_reader(skip_header_lines(f));
+
+    let seq_files: HashSet<&String> = seq_files_in.iter().collect();
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    reader.records().into_iter().for_each(|line| {
+        let record = line.unwrap();
+	for i in 0..2 {
+	    if !seq_files.contains(&record[i].to_string()) {
+		panic!("Sequence {} in {} was not found in the input files!", &record[i], pairs_file);
+	    }
+	}
+	pairs.push((record[0].to_string(), record[1].to_string()));
+    });
+    pairs
+}
+
+fn read_fastani(fastani_file: &String, missing_distance: f32) -> (Vec<String>, Vec<f32>) {
+    // fastANI output has columns: query, reference, ANI (%), matched and total fragments
+    let f = std::fs::File::open(fastani_file).unwrap();
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .from_reader(skip_header_lines(f));
+
+    // fastANI reports both directions separately, use the higher ANI
+    let mut pair_ani: HashMap<(String, String), f32> = HashMap::new();
+    let mut seq_names: HashSet<String> = HashSet::new();
+    reader.records().into_iter().for_each(|line| {
+        let record = line.unwrap();
+	let ani = record[2].parse::<f32>().unwrap() / 100.0;
+	let pair = if record[0] < record[1] { (record[0].to_string(), record[1].to_string()) } else { (record[1].to_string(), record[0].to_string()) };
+	let val = pair_ani.entry(pair).or_insert(ani);
+	*val = val.max(ani);
+	seq_names.insert(record[0].to_string());
+	seq_names.insert(record[1].to_string());
+    });
+
+    let sorted_names: Vec<String> = seq_names.into_iter().sorted().collect();
+    let ani_result: Vec<(String, String, f32)> = pair_ani.into_iter().map(|x| (x.0.0, x.0.1, x.1)).collect();
+    // fastANI leaves out pairs below ~80% ANI
+    let flattened_dissimilarity_matrix = clust::condensed_dissimilarity_matrix(&sorted_names, &ani_result, missing_distance);
+    return (sorted_names, flattened_dissimilarity_matrix);
+}
+
+fn read_mash_triangle(mash_file: &String) -> (Vec<String>, Vec<f32>) {
+    // `mash triangle` output starts with the number of sequences, then
+    // row i contains the name of sequence i and its distances to rows 0..i
+    let f = std::fs::File::open(mash_file).unwrap();
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(f);
+
+    let mut seq_names: Vec<String> = Vec::new();
+    let mut ani_result: Vec<(String, String, f32)> = Vec::new();
+    reader.records().into_iter().enumerate().for_each(|(line_nr, line)| {
+        let record = line.unwrap();
+	if line_nr == 0 && record[0].is_empty() {
+	    return;
+	}
+	let name = record[0].to_string();
+	if record.len() - 1 != seq_names.len() {
+	    panic!("Row for {} in {} has {} distances, expected {}!", name, mash_file, record.len() - 1, seq_names.len());
+	}
+	record.iter().skip(1).zip(seq_names.iter()).for_each(|x| {
+	    ani_result.push((x.1.clone(), name.clone(), 1.0 - x.0.parse::<f32>().unwrap()));
+	});
+	seq_names.push(name);
+    });
+
+    let sorted_names: Vec<String> = seq_names.into_iter().sorted().collect();
+    let flattened_dissimilarity_matrix = clust::condensed_dissimilarity_matrix(&sorted_names, &ani_result, 1.0);
+    return (sorted_names, flattened_dissimilarity_matrix);
+}
+
+fn dist_reader(dist_file: &String, has_header: bool) -> csv::Reader<BufReader<std::fs::File>> {
+    // Pairs in the first two columns and their ANI or distance in the third
+    let f = std::fs::File::open(dist_file).unwrap();
+    return csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(has_header)
+        .from_reader(skip_header_lines(f));
+}
+
+fn parse_ani(value: &str, input_is_distance: bool) -> f32 {
+    // skani reports ANI in percent
+    let x = value.parse::<f32>().unwrap();
+    return if !input_is_distance && x > 1.0 { x / 100.0 } else { x };
+}
+
+fn read_condensed_matrix(dist_file: &String, input_is_distance: bool, has_header: bool, missing_distance: f32, show_progress: bool) -> (Vec<String>, Vec<f32>) {
+    // First pass: collect the sequence names to size the matrix
+    let mut reader = dist_reader(dist_file, has_header);
+
+    let mut seq_names: HashSet<String> = HashSet::new();
+    let mut num_records: u64 = 0;
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record).unwrap() {
+	if !seq_names.contains(&record[0]) {
+            seq_names.insert(record[0].to_string());
+	}
+	if !seq_names.contains(&record[1]) {
+            seq_names.insert(record[1].to_string());
+	}
+	num_records += 1;
+    }
+    let sorted_names: Vec<String> = seq_names.into_iter().sorted().collect();
+    let mut name_to_index: HashMap<&str, usize> = HashMap::new();
+    sorted_names.iter().enumerate().for_each(|x| { name_to_index.insert(x.1.as_str(), x.0); });
+
+    // Second pass: fill the condensed dissimilarity matrix
+    let num_seqs = sorted_names.len();
+    let mut flattened_dissimilarity_matrix: Vec<f32> = vec![missing_distance; num_seqs*(num_seqs.max(1) - 1)/2];
+
+    let progress = if show_progress { ProgressBar::new(num_records) } else { ProgressBar::hidden() };
+    progress.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}").unwrap());
+    progress.set_message("pairs read");
+
+    let mut reader = dist_reader(dist_file, has_header);
+    let mut n_placed: usize = 0;
+    let mut chunk: Vec<csv::StringRecord> = vec![csv::StringRecord::new(); RECORDS_PER_CHUNK];
+    loop {
+	let mut n_read: usize = 0;
+	while n_read < RECORDS_PER_CHUNK && reader.read_record(&mut chunk[n_read]).unwrap() {
+	    n_read += 1;
+	}
+
+	// Lookups and parsing run in parallel, writes stay in file order so
+	// that repeated pairs resolve the same way every time
+	let placed: Vec<(usize, f32)> = chunk[0..n_read]
+	    .par_iter()
+	    .filter_map(|x| {
+		let i = *name_to_index.get(&x[0]).unwrap();
+		let j = *name_to_index.get(&x[1]).unwrap();
+		if i != j {
+		    let value = parse_ani(&x[2], input_is_distance);
+		    Some((clust::condensed_index(i.min(j), i.max(j), num_seqs), if input_is_distance { value } else { 1.0 - value }))
+		} else {
+		    None
+		}
+	    })
+	    .collect();
+	placed.iter().for_each(|x| { flattened_dissimilarity_matrix[x.0] = x.1 });
+	n_placed += placed.len();
+	progress.inc(n_read as u64);
+
+	if n_read < RECORDS_PER_CHUNK {
+	    break;
+	}
+    }
+    progress.finish_and_clear();
+    // Repeated pairs are counted twice so this is a lower bound
+    if n_placed < flattened_dissimilarity_matrix.len() {
+	warn!("At least {}/{} pairs between {} sequences are missing from {}, filling them with distance {}",
+	      flattened_dissimilarity_matrix.len() - n_placed, flattened_dissimilarity_matrix.len(), num_seqs, dist_file, missing_distance);
+    }
+    clust::check_dissimilarity_range(&flattened_dissimilarity_matrix, input_is_distance, dist_file);
+
+    return (sorted_names, flattened_dissimilarity_matrix);
+}
+
 fn main() {
     let cli = cli::Cli::parse();
 
@@ -101,119 +708,125 @@ fn main() {
     match &cli.command {
         // Run the full pipeline
         Some(cli::Commands::Dereplicate {
+	    output_file,
             seq_files,
             input_list,
+	    refs_are_sketch,
             batch_step,
-            linkage_method,
-            skani_kmer_size,
-            kmer_subsampling_rate,
-            marker_compression_factor,
-            rescue_small,
-            clip_tails,
-            median,
-            adjust_ani,
-            min_aligned_frac,
-            ggcat_kmer_size,
-            kmer_min_multiplicity,
-            minimizer_length,
-            no_reverse_complement,
-            unitig_type,
-            intermediate_compression_level,
+	    ani,
+	    clustering,
+	    graph,
             threads,
             memory,
             temp_dir_path,
-            ani_threshold,
+	    keep_temp,
+	    keep_decompressed,
+	    max_pairs_in_memory,
+	    temp_naming,
+	    reproducible,
+	    seed,
+	    threshold_sweep,
 	    verbose,
+	    no_progress,
 	    max_iters,
+	    min_iters,
+	    no_early_stop,
+	    checkpoint_dir,
+	    resume_from,
 	    batch_step_strategy,
 	    out_prefix,
+	    cluster_prefix,
 	    guided_batching,
+	    guide_ani_threshold,
+	    guide_linkage_method,
+	    guide_threads,
+	    guide_subsampling,
+	    guide_compression,
+	    auto_screen,
 	    external_clustering_file,
+	    list_has_clusters,
+	    collapse_identical,
+	    collapsed_file,
 	    initial_batches_file,
+	    cluster_history_file,
+	    emit_finalized_file,
+	    finalized_after,
+	    merge_heights_file,
+	    summary_json_file,
+	    members_per_cluster,
+	    build_limit,
+	    max_concurrent_graphs,
+	    used_members_file,
+	    output_format,
         }) => {
 	    init_log(if *verbose { 2 } else { 1 });
 
-            let skani_params = panaani::dist::SkaniParams {
-                kmer_size: *skani_kmer_size,
-                kmer_subsampling_rate: *kmer_subsampling_rate,
-                marker_compression_factor: *marker_compression_factor,
-                rescue_small: *rescue_small,
-
-                clip_tails: *clip_tails,
-                median: *median,
-                adjust_ani: *adjust_ani,
-
-                min_aligned_frac: *min_aligned_frac,
-		progress: *verbose,
-                ..Default::default()
+            let mut skani_params = panaani::dist::SkaniParams {
+		sketch_files: if input_list.is_some() && !*list_has_clusters { read_sketch_list(input_list.as_ref().unwrap(), cli.paths_relative_to_list) } else { HashMap::new() },
+		temp_dir: temp_dir_path.clone().unwrap_or("/tmp".to_string()),
+		max_pairs_in_memory: *max_pairs_in_memory,
+		keep_decompressed: *keep_decompressed,
+		progress: show_progress(*verbose, *no_progress),
+                ..skani_params_from_args(ani)
             };
 
-            let kodama_params = panaani::clust::KodamaParams {
-                cutoff: *ani_threshold,
-                method: if linkage_method.is_some() {
-                    match linkage_method.as_ref().unwrap().as_str() {
-                        "single" => kodama::Method::Single,
-                        "complete" => kodama::Method::Complete,
-                        "average" => kodama::Method::Average,
-                        "weighted" => kodama::Method::Weighted,
-                        "ward" => kodama::Method::Ward,
-                        "centroid" => kodama::Method::Centroid,
-                        "median" => kodama::Method::Median,
-                        &_ => kodama::Method::Single,
-                    }
-                } else {
-                    kodama::Method::Single
-                },
-                ..Default::default()
-            };
+            let kodama_params = kodama_params_from_args(clustering);
 
             let ggcat_params = panaani::build::GGCATParams {
-                kmer_size: *ggcat_kmer_size,
-                kmer_min_multiplicity: *kmer_min_multiplicity,
-                minimizer_length: if minimizer_length.is_some() {
-                    *minimizer_length
-                } else {
-                    None
-                },
-                no_reverse_complement: *no_reverse_complement,
-		progress: *verbose,
-                unitig_type: if unitig_type.is_some() {
-                    match unitig_type.as_ref().unwrap().as_str() {
-                        "greedymatchtigs" => ggcat_api::ExtraElaboration::GreedyMatchtigs,
-                        "unitiglinks" => ggcat_api::ExtraElaboration::UnitigLinks,
-                        "eulertigs" => ggcat_api::ExtraElaboration::Eulertigs,
-                        "pathtigs" => ggcat_api::ExtraElaboration::Pathtigs,
-                        &_ => ggcat_api::ExtraElaboration::GreedyMatchtigs,
-                    }
-                } else {
-                    ggcat_api::ExtraElaboration::GreedyMatchtigs
-                },
-                intermediate_compression_level: if intermediate_compression_level.is_some() {
-                    *intermediate_compression_level
-                } else {
-                    None
-                },
+		progress: show_progress(*verbose, *no_progress),
                 temp_dir_path: temp_dir_path.clone().unwrap_or("./".to_string()),
+                keep_decompressed: *keep_decompressed,
                 threads: *threads,
                 memory: *memory,
 		out_prefix: out_prefix.clone().unwrap_or("".to_string()),
-                ..Default::default()
+		members_per_cluster: *members_per_cluster,
+		build_limit: *build_limit,
+		max_concurrent_graphs: *max_concurrent_graphs,
+                ..ggcat_params_from_args(graph)
             };
 
-	    // TODO seq_files should be mutable by default to avoid cloning
-	    let mut seq_files_in: Vec<String> = seq_files.clone();
-	    if input_list.is_some() {
-		seq_files_in.append(read_input_list(input_list.as_ref().unwrap()).as_mut());
+	    let mut seq_files_in: Vec<String> = collect_inputs(seq_files, input_list, cli.paths_relative_to_list);
+	    if *refs_are_sketch {
+		seq_files_in = sketch_sources(&seq_files_in, &mut skani_params);
+	    }
+
+	    if *list_has_clusters && external_clustering_file.is_some() {
+		warn!("Using the clusters in --external-clustering instead of the second column of --input-list");
 	    }
 
+	    fastx::check_kmer_size(&seq_files_in, ani.sketch.skani_kmer_size as usize, "skani");
+	    let batch = largest_batch(&seq_files_in, *batch_step);
+	    check_sketch_memory(panaani::dist::estimate_sketch_memory(&batch, &Some(skani_params.clone())), batch.len(), *memory);
+	    fastx::check_kmer_size(&seq_files_in, graph.ggcat_kmer_size as usize, "ggcat");
+
             let params: panaani::PanaaniParams = panaani::PanaaniParams {
                 batch_step: *batch_step,
                 batch_step_strategy: batch_step_strategy.clone(),
                 max_iters: *max_iters,
+		min_iters: *min_iters,
+		early_stop: !*no_early_stop,
+		checkpoint_dir: checkpoint_dir.clone(),
+		resume_from: resume_from.clone(),
 		temp_dir: temp_dir_path.clone().unwrap_or("/tmp".to_string()),
+		keep_temp: *keep_temp,
+		temp_naming: temp_naming.clone(),
+		reproducible: *reproducible,
+		seed: *seed,
+		emit_finalized_file: emit_finalized_file.clone(),
+		finalized_after: *finalized_after,
+		collapse_identical: *collapse_identical,
 		guided: *guided_batching,
+		guide_ani_threshold: *guide_ani_threshold,
+		guide_linkage_method: if guide_linkage_method.is_some() { Some(parse_linkage_method(guide_linkage_method)) } else { None },
+		guide_threads: *guide_threads,
+		guide_subsampling: *guide_subsampling,
+		guide_compression: *guide_compression,
 		external_clustering: if external_clustering_file.is_some() {
-		    Some(read_seq_assignments(&seq_files_in, &external_clustering_file.as_ref().unwrap()).iter().map(|x| x.1.clone()).collect())
+		    // Labels in the order of the inputs
+		    let external_clusters: HashMap<String, String> = read_seq_assignments(&seq_files_in, &external_clustering_file.as_ref().unwrap()).into_iter().collect();
+		    Some(seq_files_in.iter().map(|x| external_clusters.get(x).unwrap().clone()).collect())
+		} else if *list_has_clusters {
+		    Some(read_list_clusters(&seq_files_in, input_list.as_ref().unwrap(), cli.paths_relative_to_list))
 		} else {
 		    None
 		},
@@ -222,66 +835,283 @@ fn main() {
 		} else {
 		    None
 		},
+		threshold_sweep: threshold_sweep.clone(),
+		cluster_prefix: cluster_prefix.clone(),
+		auto_screen: *auto_screen,
 		..Default::default()
             };
 
 	    panaani::build::init_ggcat(&Some(ggcat_params.clone()));
 
-            let clusters = panaani::dereplicate(
+	    let start = std::time::Instant::now();
+            let result = panaani::dereplicate(
                 &seq_files_in,
                 &Some(params),
                 &Some(skani_params),
                 &Some(kodama_params),
                 &Some(ggcat_params),
-            );
+                None,
+            ).unwrap_or_else(|e| panic!("{}", e));
+	    let clusters = &result.clusters;
+
+	    if cluster_history_file.is_some() {
+		let mut f = std::fs::File::create(cluster_history_file.as_ref().unwrap()).unwrap();
+		result.stabilized_in
+		    .iter()
+		    .sorted_by(|k1, k2| k1.0.cmp(&k2.0))
+		    .for_each(|x| { writeln!(f, "{}\t{}", x.0, x.1).unwrap(); });
+	    }
+
+	    if collapsed_file.is_some() {
+		let mut f = std::fs::File::create(collapsed_file.as_ref().unwrap()).unwrap();
+		result.collapsed
+		    .iter()
+		    .sorted_by(|k1, k2| k1.0.cmp(&k2.0))
+		    .for_each(|x| { writeln!(f, "{}\t{}", x.0, x.1).unwrap(); });
+	    }
+
+	    if used_members_file.is_some() {
+		// Collapsed inputs were not used in the graphs
+		let built_from: Vec<&(String, String)> = clusters.iter().filter(|x| !result.collapsed.contains_key(&x.0)).collect();
+		let final_clusters = panaani::assign_seqs(&built_from.iter().map(|x| x.0.clone()).collect::<Vec<String>>(),
+							  &built_from.iter().map(|x| x.1.clone()).collect::<Vec<String>>());
+		write_used_members(&final_clusters, *members_per_cluster, used_members_file.as_ref().unwrap());
+	    }
+
+	    if merge_heights_file.is_some() {
+		let mut f = std::fs::File::create(merge_heights_file.as_ref().unwrap()).unwrap();
+		result.merge_heights
+		    .iter()
+		    .sorted_by(|k1, k2| k1.0.cmp(&k2.0))
+		    .for_each(|x| { writeln!(f, "{}\t{}", x.0, x.1).unwrap(); });
+	    }
+
             let n_clusters = clusters.iter().map(|x| x.1.clone()).unique().collect::<Vec<String>>().len();
 
             info!("Created {} clusters", n_clusters);
-            clusters
-                .iter()
-                .for_each(|x| println!("{}\t{}", x.0, x.1));
+	    if summary_json_file.is_some() {
+		write_summary_json(&result, params_hash(&resolved_params(&cli::Cli::command().get_matches())), start.elapsed().as_secs_f64(), summary_json_file.as_ref().unwrap());
+	    }
+	    let mut out = open_output(output_file);
+	    print_header(&cli, &mut out);
+	    print_clusters(&clusters, output_format, &mut out);
+	    out.flush().unwrap();
+
+	    if !result.skipped_inputs.is_empty() {
+		warn!("{} inputs were invalid or skipped", result.skipped_inputs.len());
+		std::process::exit(EXIT_SKIPPED_INPUTS);
+	    } else if n_clusters == 0 {
+		std::process::exit(EXIT_NO_CLUSTERS);
+	    } else if !result.converged {
+		std::process::exit(EXIT_NOT_CONVERGED);
+	    }
+        }
+
+        // Run a single dereplication iteration on an existing clustering
+        Some(cli::Commands::Refine {
+            seq_files,
+            input_list,
+	    refs_are_sketch,
+	    output_file,
+	    out_prefix,
+	    cluster_prefix,
+	    output_format,
+            threads,
+            memory,
+            temp_dir_path,
+	    external_clustering_file,
+	    verbose,
+	    no_progress,
+	    ani,
+	    clustering,
+	    graph,
+        }) => {
+	    init_log(if *verbose { 2 } else { 1 });
+
+            let mut skani_params = panaani::dist::SkaniParams {
+		sketch_files: if input_list.is_some() { read_sketch_list(input_list.as_ref().unwrap(), cli.paths_relative_to_list) } else { HashMap::new() },
+		temp_dir: temp_dir_path.clone().unwrap_or("/tmp".to_string()),
+		progress: show_progress(*verbose, *no_progress),
+                ..skani_params_from_args(ani)
+            };
+
+            let kodama_params = kodama_params_from_args(clustering);
+
+            let ggcat_params = panaani::build::GGCATParams {
+		progress: show_progress(*verbose, *no_progress),
+                temp_dir_path: temp_dir_path.clone().unwrap_or("./".to_string()),
+                threads: *threads,
+                memory: *memory,
+		out_prefix: out_prefix.clone().unwrap_or("".to_string()),
+                ..ggcat_params_from_args(graph)
+            };
+
+	    let mut seq_files_in: Vec<String> = collect_inputs(seq_files, input_list, cli.paths_relative_to_list);
+	    if *refs_are_sketch {
+		seq_files_in = sketch_sources(&seq_files_in, &mut skani_params);
+	    }
+
+	    fastx::check_kmer_size(&seq_files_in, ani.sketch.skani_kmer_size as usize, "skani");
+	    check_sketch_memory(panaani::dist::estimate_sketch_memory(&seq_files_in, &Some(skani_params.clone())), seq_files_in.len(), *memory);
+	    fastx::check_kmer_size(&seq_files_in, graph.ggcat_kmer_size as usize, "ggcat");
+
+	    // Sequences need to be in the same order as their external clusters
+	    let external_clusters = read_seq_assignments(&seq_files_in, external_clustering_file.as_ref().unwrap());
+	    seq_files_in = external_clusters.iter().map(|x| x.0.clone()).collect();
+
+            let params: panaani::PanaaniParams = panaani::PanaaniParams {
+		temp_dir: temp_dir_path.clone().unwrap_or("/tmp".to_string()),
+		external_clustering: Some(external_clusters.iter().map(|x| x.1.clone()).collect()),
+		cluster_prefix: cluster_prefix.clone(),
+		..Default::default()
+            };
+
+	    panaani::build::init_ggcat(&Some(ggcat_params.clone()));
+
+            let clusters = panaani::refine(
+                &seq_files_in,
+                &Some(params),
+                &Some(skani_params),
+                &Some(kodama_params),
+                &Some(ggcat_params),
+            ).unwrap_or_else(|e| panic!("{}", e));
+            let n_clusters = clusters.iter().map(|x| x.1.clone()).unique().collect::<Vec<String>>().len();
+
+            info!("Refined into {} clusters", n_clusters);
+	    let mut out = open_output(output_file);
+	    print_header(&cli, &mut out);
+	    print_clusters(&clusters, output_format, &mut out);
+	    out.flush().unwrap();
         }
 
         // Calculate distances between some input fasta files
         Some(cli::Commands::Dist {
+	    output_file,
             seq_files,
 	    input_list,
+	    refs_are_sketch,
             threads,
-            skani_kmer_size,
-            kmer_subsampling_rate,
-            marker_compression_factor,
-            rescue_small,
-            clip_tails,
-            median,
-            adjust_ani,
-            min_aligned_frac,
-	    verbose
+	    memory,
+	    ani,
+	    temp_dir_path,
+	    keep_temp,
+	    keep_decompressed,
+	    max_pairs_in_memory,
+	    verbose,
+	    no_progress,
+	    pairs_file,
+	    both_directions,
+	    include_self,
+	    matrix_format,
+	    matrix_distance,
+	    matrix_fill,
+	    binary_output_file,
+	    with_header,
+	    detailed,
+	    bootstrap_ci,
+	    detailed_out,
+	    detailed_file,
         }) => {
 	    init(*threads as usize, if *verbose { 2 } else { 1 });
 
-            let skani_params = dist::SkaniParams {
-                kmer_size: *skani_kmer_size,
-                kmer_subsampling_rate: *kmer_subsampling_rate,
-                marker_compression_factor: *marker_compression_factor,
-                rescue_small: *rescue_small,
+            let skani_params = panaani::dist::SkaniParams {
+		temp_dir: temp_dir_path.clone().unwrap_or("/tmp".to_string()),
+		max_pairs_in_memory: *max_pairs_in_memory,
+		keep_temp: *keep_temp,
+		keep_decompressed: *keep_decompressed,
+		sketch_files: if input_list.is_some() { read_sketch_list(input_list.as_ref().unwrap(), cli.paths_relative_to_list) } else { HashMap::new() },
+		include_self: *include_self,
+		bootstrap_ci: *bootstrap_ci,
+		detailed_out: *detailed_out,
+		detailed_file: detailed_file.clone(),
+		refs_are_sketch: *refs_are_sketch,
+		progress: show_progress(*verbose, *no_progress),
+                ..skani_params_from_args(ani)
+            };
 
-                clip_tails: *clip_tails,
-                median: *median,
-                adjust_ani: *adjust_ani,
+	    let seq_files_in: Vec<String> = collect_inputs(seq_files, input_list, cli.paths_relative_to_list);
 
-                min_aligned_frac: *min_aligned_frac,
-		progress: *verbose,
-                ..Default::default()
-            };
+	    // Only the inputs that are not sketches yet are checked
+	    let to_sketch: Vec<String> = seq_files_in.iter().filter(|x| !*refs_are_sketch || !dist::is_sketch_file(x)).cloned().collect();
+	    fastx::check_kmer_size(&to_sketch, ani.sketch.skani_kmer_size as usize, "skani");
+	    check_sketch_memory(panaani::dist::estimate_sketch_memory(&to_sketch, &Some(skani_params.clone())), to_sketch.len(), *memory);
 
-	    // TODO seq_files should be mutable by default to avoid cloning
-	    let mut seq_files_in: Vec<String> = seq_files.clone();
-	    if input_list.is_some() {
-		seq_files_in.append(read_input_list(input_list.as_ref().unwrap()).as_mut());
-	    }
+	    let fastx_pairs: Option<Vec<(String, String)>> = if pairs_file.is_some() {
+		Some(read_pairs(&seq_files_in, pairs_file.as_ref().unwrap()))
+	    } else {
+		None
+	    };
+
+	    if *both_directions {
+		// Sketches are shared so only the chaining is done twice
+		let forward: Vec<(String, String)> = fastx_pairs.unwrap_or(seq_files_in.iter().cloned().tuple_combinations().collect());
+		let both: Vec<(String, String)> = forward.iter().cloned().chain(forward.iter().map(|x| (x.1.clone(), x.0.clone()))).collect();
+		let results = panaani::dist::ani_from_fastx_pairs(&seq_files_in, &Some(both), &Some(skani_params));
 
-            let results = dist::ani_from_fastx_files(&seq_files_in, &Some(skani_params));
-	    results.iter().for_each(|x| { println!("{}\t{}\t{}", x.0, x.1, x.2) });
+		let mut pair_to_ani: HashMap<(&String, &String), f32> = HashMap::new();
+		results.iter().for_each(|x| { pair_to_ani.insert((&x.0, &x.1), x.2); });
+		let mut out = open_output(output_file);
+		print_header(&cli, &mut out);
+		if *with_header {
+		    writeln!(out, "reference\tquery\tani\treverse_ani\tdifference").unwrap();
+		}
+		// Pairs without a result in either direction are reported as NA
+		let na = |x: Option<f32>| x.map_or("NA".to_string(), |y| y.to_string());
+		forward.iter().sorted().for_each(|x| {
+		    let ani_ab = pair_to_ani.get(&(&x.0, &x.1)).copied();
+		    let ani_ba = pair_to_ani.get(&(&x.1, &x.0)).copied();
+		    let difference = ani_ab.zip(ani_ba).map(|(ab, ba)| ab - ba);
+		    writeln!(out, "{}\t{}\t{}\t{}\t{}", x.0, x.1, na(ani_ab), na(ani_ba), na(difference)).unwrap()
+		});
+		out.flush().unwrap();
+	    } else if *detailed {
+		let records = panaani::dist::ani_record_stream_from_fastx_pairs(&seq_files_in, &fastx_pairs, &Some(skani_params.clone()));
+		let mut out = open_output(output_file);
+		print_header(&cli, &mut out);
+		if *with_header {
+		    writeln!(out, "reference\tquery\tani\talign_fraction_ref\talign_fraction_query\tci_lower\tci_upper").unwrap();
+		}
+		let ci = |x: Option<f32>| x.map_or("NA".to_string(), |y| y.to_string());
+		records.for_each(|x| {
+		    writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}", x.ref_file, x.query_file, x.ani, x.af_ref, x.af_query, ci(x.ci_low), ci(x.ci_high)).unwrap()
+		});
+		out.flush().unwrap();
+	    } else if matrix_format.is_some() {
+		// The matrix needs all pairs at once
+		let results = panaani::dist::ani_from_fastx_pairs(&seq_files_in, &fastx_pairs, &Some(skani_params.clone()));
+		if binary_output_file.is_some() {
+		    let seq_names: Vec<String> = seq_files_in.iter().cloned().unique().sorted().collect();
+		    let flattened_dissimilarity_matrix = clust::condensed_dissimilarity_matrix(
+			&seq_names,
+			&results.iter().map(|x| (x.0.clone(), x.1.clone(), x.2)).collect::<Vec<(String, String, f32)>>(),
+			1.0,
+		    );
+		    panaani::dist::write_binary_matrix(&seq_names, &flattened_dissimilarity_matrix, &skani_params, binary_output_file.as_ref().unwrap());
+		}
+		let mut out = open_output(output_file);
+		let seq_names: Vec<String> = seq_files_in.iter().cloned().unique().collect();
+		let fill_value = matrix_fill.unwrap_or(if *matrix_distance { 1.0 } else { 0.0 });
+		dist::write_phylip_matrix(&results.iter().map(|x| (x.0.clone(), x.1.clone(), x.2)).collect::<Vec<(String, String, f32)>>(),
+					  &seq_names, *matrix_distance, fill_value, &mut out);
+		out.flush().unwrap();
+	    } else {
+		// Pairs are written as they are merged back with --max-pairs-in-memory
+		let mut out = open_output(output_file);
+		print_header(&cli, &mut out);
+		if *with_header {
+		    writeln!(out, "reference\tquery\tani").unwrap();
+		}
+		let results = panaani::dist::ani_stream_from_fastx_pairs(&seq_files_in, &fastx_pairs, &Some(skani_params.clone()))
+		    .inspect(|x| { writeln!(out, "{}\t{}\t{}", x.0, x.1, x.2).unwrap() });
+		if binary_output_file.is_some() {
+		    let seq_names: Vec<String> = seq_files_in.iter().cloned().unique().sorted().collect();
+		    let flattened_dissimilarity_matrix = panaani::clust::ani_dissimilarity_matrix(&seq_names, results, &None);
+		    panaani::dist::write_binary_matrix(&seq_names, &flattened_dissimilarity_matrix, &skani_params, binary_output_file.as_ref().unwrap());
+		} else {
+		    results.for_each(drop);
+		}
+		out.flush().unwrap();
+	    }
         }
 
         // Build pangenome representations from input fasta files and their clusters
@@ -290,62 +1120,59 @@ fn main() {
 	    input_list,
             external_clustering_file,
 	    target_cluster,
+	    min_cluster_size,
+	    max_cluster_size,
+	    max_ram_per_graph,
+	    oversized_graph,
+	    fallback_min_multiplicity,
+	    skipped_graphs_file,
+	    strict,
+	    members_per_cluster,
+	    build_limit,
+	    max_concurrent_graphs,
+	    used_members_file,
             threads,
             memory,
             temp_dir_path,
-            ggcat_kmer_size,
-            kmer_min_multiplicity,
-            minimizer_length,
-            no_reverse_complement,
-            unitig_type,
-            intermediate_compression_level,
+            keep_decompressed,
+            graph_stats,
+            gfa,
+            pangenome_summary,
+	    graph,
 	    verbose,
+	    no_progress,
 	    out_prefix,
         }) => {
 	    init_log(if *verbose { 2 } else { 1 });
 
             let ggcat_params = panaani::build::GGCATParams {
-                kmer_size: *ggcat_kmer_size,
-                kmer_min_multiplicity: *kmer_min_multiplicity,
-                minimizer_length: if minimizer_length.is_some() {
-                    *minimizer_length
-                } else {
-                    None
-                },
-                no_reverse_complement: *no_reverse_complement,
-		progress: *verbose,
-                unitig_type: if unitig_type.is_some() {
-                    match unitig_type.as_ref().unwrap().as_str() {
-                        "greedymatchtigs" => ggcat_api::ExtraElaboration::GreedyMatchtigs,
-                        "unitiglinks" => ggcat_api::ExtraElaboration::UnitigLinks,
-                        "eulertigs" => ggcat_api::ExtraElaboration::Eulertigs,
-                        "pathtigs" => ggcat_api::ExtraElaboration::Pathtigs,
-                        &_ => ggcat_api::ExtraElaboration::GreedyMatchtigs,
-                    }
-                } else {
-                    ggcat_api::ExtraElaboration::GreedyMatchtigs
-                },
-                intermediate_compression_level: if intermediate_compression_level.is_some() {
-                    *intermediate_compression_level
-                } else {
-                    None
-                },
+		graph_stats: *graph_stats,
+		output_format: if *gfa { panaani::build::GraphFormat::Gfa } else { panaani::build::GraphFormat::Fasta },
+		progress: show_progress(*verbose, *no_progress),
                 temp_dir_path: temp_dir_path.clone().unwrap_or("./".to_string()),
+                keep_decompressed: *keep_decompressed,
                 threads: *threads,
                 memory: *memory,
 		out_prefix: out_prefix.clone().unwrap_or("".to_string()),
-                ..Default::default()
+		min_cluster_size: *min_cluster_size,
+		max_cluster_size: *max_cluster_size,
+		max_ram_per_graph: *max_ram_per_graph,
+		oversized_graph: oversized_graph.clone(),
+		fallback_min_multiplicity: *fallback_min_multiplicity,
+		strict: *strict,
+		members_per_cluster: *members_per_cluster,
+		build_limit: *build_limit,
+		max_concurrent_graphs: *max_concurrent_graphs,
+                ..ggcat_params_from_args(graph)
             };
 
 	    panaani::build::init_ggcat(&Some(ggcat_params.clone()));
 
-	    // TODO seq_files should be mutable by default to avoid cloning
-	    let mut seq_files_in: Vec<String> = seq_files.clone();
-	    if input_list.is_some() {
-		seq_files_in.append(read_input_list(input_list.as_ref().unwrap()).as_mut());
-	    }
+	    let seq_files_in: Vec<String> = collect_inputs(seq_files, input_list, cli.paths_relative_to_list);
 
 	    let external_clusters: Vec<(String, String)> = read_seq_assignments(&seq_files_in, &external_clustering_file.as_ref().unwrap());
+	    fastx::check_kmer_size(&external_clusters.iter().map(|x| x.0.clone()).collect::<Vec<String>>(), graph.ggcat_kmer_size as usize, "ggcat");
+
 	    let mut seq_to_cluster = panaani::assign_seqs(&external_clusters.iter().map(|x| x.0.clone()).collect::<Vec<String>>(),
 							  &external_clusters.iter().map(|x| x.1.clone()).collect::<Vec<String>>());
 
@@ -355,70 +1182,158 @@ fn main() {
 		seq_to_cluster = target_to_seqs;
 	    }
 
-            panaani::build::build_pangenome_representations(
+	    if used_members_file.is_some() {
+		write_used_members(&seq_to_cluster, *members_per_cluster, used_members_file.as_ref().unwrap());
+	    }
+
+	    if *pangenome_summary && !graph.colors {
+		warn!("--pangenome-summary without --colors only reports the number of unitigs in each graph");
+	    }
+
+	    let ggcat_params = Some(ggcat_params);
+            let (skipped, failed) = panaani::build::build_pangenome_representations(
 		&seq_to_cluster,
-                &Some(ggcat_params),
+                &ggcat_params,
             );
+
+	    if *pangenome_summary {
+		let summary_file = out_prefix.clone().unwrap_or("".to_string()) + "pangenome_summary.tsv";
+		panaani::build::pangenome_summary(&seq_to_cluster, &ggcat_params, &summary_file);
+	    }
+
+	    if skipped_graphs_file.is_some() {
+		let mut f = std::fs::File::create(skipped_graphs_file.as_ref().unwrap()).unwrap();
+		skipped.iter().for_each(|x| { writeln!(f, "{}\t{:.2}", x.0, x.1).unwrap() });
+	    }
+
+	    if !failed.is_empty() {
+		let failed_file = out_prefix.clone().unwrap_or("".to_string()) + "failed_clusters.tsv";
+		warn!("Failed to build {}/{} graphs, see {}", failed.len(), seq_to_cluster.len(), failed_file);
+		let mut f = std::fs::File::create(&failed_file).unwrap();
+		failed.iter().for_each(|x| { writeln!(f, "{}\t{}", x.0, x.1.replace(['\t', '\n'], " ")).unwrap() });
+		std::process::exit(EXIT_FAILED_GRAPHS);
+	    }
         }
 
         // Cluster distance data created with `skani dist` or `panaani dist`.
         Some(cli::Commands::Cluster {
+	    output_file,
             dist_file,
+	    fastani_file,
+	    mash_file,
+	    binary_file,
+	    dendrogram_file,
+	    sketch_dir,
+	    input_is_distance,
+	    has_header,
+	    missing_distance,
             ani_threshold,
+	    exclusive_threshold,
+	    num_clusters,
+	    primary_ani,
+	    secondary_ani,
             linkage_method,
+            skani_kmer_size,
+            kmer_subsampling_rate,
+            marker_compression_factor,
+            min_aligned_frac,
+            min_aligned_frac_ref,
+            min_aligned_frac_query,
+            af_combine,
+	    threads,
 	    verbose,
+	    no_progress,
 	    out_prefix,
+	    cluster_prefix,
+	    output_format,
+	    save_dendrogram_file,
+	    newick_file,
         }) => {
-	    init(1, if *verbose { 2 } else { 1 });
+	    init(*threads as usize, if *verbose { 2 } else { 1 });
 
             let kodama_params = clust::KodamaParams {
                 cutoff: *ani_threshold,
-                method: if linkage_method.is_some() {
-                    match linkage_method.as_ref().unwrap().as_str() {
-                        "single" => kodama::Method::Single,
-                        "complete" => kodama::Method::Complete,
-                        "average" => kodama::Method::Average,
-                        "weighted" => kodama::Method::Weighted,
-                        "ward" => kodama::Method::Ward,
-                        "centroid" => kodama::Method::Centroid,
-                        "median" => kodama::Method::Median,
-                        &_ => kodama::Method::Single,
-                    }
-                } else {
-                    kodama::Method::Single
-                },
+		cutoff_mode: num_clusters.map_or(clust::CutoffMode::Height, clust::CutoffMode::NumClusters),
+                method: parse_linkage_method(linkage_method),
+		report_granularity: true,
+		input_is_distance: *input_is_distance,
+		inclusive_cutoff: !*exclusive_threshold,
                 ..Default::default()
             };
 
-            let f = std::fs::File::open(dist_file).unwrap();
-            let mut reader = csv::ReaderBuilder::new()
-                .delimiter(b'\t')
-                .has_headers(false)
-                .from_reader(f);
-
-            let mut seq_names: HashSet<String> = HashSet::new();
-            let mut res: Vec<(String, String, f32)> = Vec::new();
-            for line in reader.records().into_iter() {
-                let record = line.unwrap();
-                res.push((
-                    record[0].to_string().clone(),
-                    record[1].to_string().clone(),
-                    record[2].parse::<f32>().unwrap(),
-                ));
-                seq_names.insert(record[0].to_string());
-                seq_names.insert(record[1].to_string());
-            }
-	    res.sort_by(|k1, k2| match k1.0.cmp(&k2.0) {
-		Ordering::Equal => k1.1.cmp(&k2.1),
-		other => other,
-            });
-
-	    let old_clusters = seq_names.iter().map(|x| x).cloned().collect::<Vec<String>>();
-            let hclust_res = clust::single_linkage_cluster(&res, &Some(kodama_params));
-
-	    let prefix = out_prefix.clone().unwrap_or("".to_string()) + &"panANI-".to_string();
+	    let (old_clusters, dend) = if dendrogram_file.is_some() {
+		clust::read_dendrogram(dendrogram_file.as_ref().unwrap())
+	    } else {
+		let (seq_names, mut flattened_dissimilarity_matrix) = if fastani_file.is_some() {
+		    read_fastani(fastani_file.as_ref().unwrap(), *missing_distance)
+		} else if mash_file.is_some() {
+		    read_mash_triangle(mash_file.as_ref().unwrap())
+		} else if binary_file.is_some() {
+		    dist::read_binary_matrix(binary_file.as_ref().unwrap())
+		} else if sketch_dir.is_some() {
+		    let skani_params = panaani::dist::SkaniParams {
+			min_aligned_frac: *min_aligned_frac,
+			min_aligned_frac_ref: *min_aligned_frac_ref,
+			min_aligned_frac_query: *min_aligned_frac_query,
+			af_combine: af_combine.clone(),
+			..sketch_params_from_args(&cli::SketchArgs {
+			    skani_kmer_size: *skani_kmer_size,
+			    kmer_subsampling_rate: *kmer_subsampling_rate,
+			    marker_compression_factor: *marker_compression_factor,
+			    min_contig_length: 0,
+			})
+		    };
+		    let sketch_params = panaani::dist::sketch_params(&skani_params);
+		    let sketches = panaani::dist::sketches_from_dir(sketch_dir.as_ref().unwrap(), &sketch_params);
+		    let ani_result = panaani::dist::pairwise_ani_from_sketches(&sketches, &Some(skani_params));
+		    let seq_names: Vec<String> = sketches.iter().map(|x| x.file_name.clone()).sorted().collect();
+		    let flattened_dissimilarity_matrix = clust::condensed_dissimilarity_matrix(&seq_names, &ani_result, 1.0);
+		    (seq_names, flattened_dissimilarity_matrix)
+		} else {
+		    read_condensed_matrix(dist_file.as_ref().unwrap(), *input_is_distance, *has_header, *missing_distance, show_progress(*verbose, *no_progress))
+		};
+		if seq_names.is_empty() {
+		    warn!("No sequences found in the input, nothing to cluster");
+		}
+		let dend = clust::condensed_dendrogram(&mut flattened_dissimilarity_matrix, seq_names.len(), &Some(kodama_params.clone()));
+		(seq_names, dend)
+	    };
+	    if save_dendrogram_file.is_some() {
+		clust::write_dendrogram(&dend, &old_clusters, save_dendrogram_file.as_ref().unwrap());
+	    }
+	    if newick_file.is_some() {
+		std::fs::write(newick_file.as_ref().unwrap(), clust::dendrogram_to_newick(&dend, &old_clusters) + "\n").unwrap();
+	    }
+	    let seq_names = &old_clusters;
+	    let prefix = out_prefix.clone().unwrap_or("".to_string()) + cluster_prefix;
+
+	    if primary_ani.is_some() {
+		// Primary clusters are named <prefix><N> and secondary <prefix><N>_<M>
+		let levels: Vec<(String, String)> = clust::two_level_clusters(&dend, primary_ani.unwrap(), secondary_ani.unwrap(), &Some(kodama_params))
+		    .iter()
+		    .map(|x| (prefix.clone() + &x.0.to_string(), prefix.clone() + &x.0.to_string() + "_" + &x.1.to_string()))
+		    .collect();
+		let mut out = open_output(output_file);
+		print_header(&cli, &mut out);
+		match output_format.as_str() {
+		    "drep" => {
+			writeln!(out, "genome,secondary_cluster,primary_cluster").unwrap();
+			seq_names.iter().zip(levels.iter()).for_each(|x| {
+			    let genome = std::path::Path::new(x.0).file_name().map_or(x.0.clone(), |y| y.to_string_lossy().to_string());
+			    writeln!(out, "{},{},{}", genome, x.1.1, x.1.0).unwrap()
+			});
+		    },
+		    // Rows are the secondary clusters since the primary ones are unions of them
+		    "partition" => print_clusters(&seq_names.iter().cloned().zip(levels.iter().map(|x| x.1.clone())).collect::<Vec<(String, String)>>(), output_format, &mut out),
+		    &_ => seq_names.iter().zip(levels.iter()).for_each(|x| { writeln!(out, "{}\t{}\t{}", x.0, x.1.0, x.1.1).unwrap() }),
+		}
+		out.flush().unwrap();
+		return;
+	    }
+
+            let hclust_res = clust::cluster_dendrogram(&dend, &Some(kodama_params));
 	    let new_clusters: &mut Vec<String> = &mut
-		panaani::match_clustering_results(&old_clusters, &old_clusters, &hclust_res, &prefix);
+		panaani::match_clustering_results(&old_clusters, &old_clusters, &hclust_res, &prefix).unwrap_or_else(|e| panic!("{}", e));
 
 	    let mut files_in_cluster: HashMap<String, Vec<String>> = HashMap::new();
 	    seq_names.iter().zip(new_clusters.iter()).for_each(|x| {
@@ -434,16 +1349,21 @@ fn main() {
 		}
 	    });
 
-	    old_clusters.iter().zip(new_clusters.iter()).for_each(|x| { println!("{}\t{}", x.0, x.1) } );
+	    let mut out = open_output(output_file);
+	    print_header(&cli, &mut out);
+	    print_clusters(&old_clusters.iter().cloned().zip(new_clusters.iter().cloned()).collect::<Vec<(String, String)>>(), output_format, &mut out);
+	    out.flush().unwrap();
         }
 
         // Calculate distances between some input fasta files
         Some(cli::Commands::Assign {
+	    output_file,
             query_files,
 	    query_files_list,
 	    ref_files_list,
             threads,
 	    verbose,
+	    no_progress,
             skani_kmer_size,
             kmer_subsampling_rate,
             marker_compression_factor,
@@ -452,14 +1372,20 @@ fn main() {
             median,
             adjust_ani,
             min_aligned_frac,
+            min_aligned_frac_ref,
+            min_aligned_frac_query,
+            af_combine,
 	    ani_threshold,
+	    keep_refs,
+	    query_batch_size,
+	    dedup_queries_file,
+	    ref_thresholds_file,
+	    dist_out_file,
+	    top_k,
         }) => {
 	    init(*threads as usize, if *verbose { 2 } else { 1 });
 
-            let skani_params = dist::SkaniParams {
-                kmer_size: *skani_kmer_size,
-                kmer_subsampling_rate: *kmer_subsampling_rate,
-                marker_compression_factor: *marker_compression_factor,
+            let skani_params = panaani::dist::SkaniParams {
                 rescue_small: *rescue_small,
 
                 clip_tails: *clip_tails,
@@ -467,132 +1393,397 @@ fn main() {
                 adjust_ani: *adjust_ani,
 
                 min_aligned_frac: *min_aligned_frac,
-		progress: *verbose,
-                ..Default::default()
+                min_aligned_frac_ref: *min_aligned_frac_ref,
+                min_aligned_frac_query: *min_aligned_frac_query,
+                af_combine: af_combine.clone(),
+		keep_refs: *keep_refs,
+		progress: show_progress(*verbose, *no_progress),
+                ..sketch_params_from_args(&cli::SketchArgs {
+		    skani_kmer_size: *skani_kmer_size,
+		    kmer_subsampling_rate: *kmer_subsampling_rate,
+		    marker_compression_factor: *marker_compression_factor,
+		    min_contig_length: 0,
+		})
             };
 
-	    let cmd_params = skani::params::CommandParams {
-		screen: false,
-		screen_val: 0.00,
-		mode: skani::params::Mode::Dist,
-		out_file_name: "".to_string(),
-		ref_files: vec![],
-		query_files: vec![],
-		refs_are_sketch: false,
-		queries_are_sketch: false,
-		robust: skani_params.clip_tails,
-		median: skani_params.median,
-		sparse: false,
-		full_matrix: false,
-		max_results: 10000000,
-		individual_contig_q: false,
-		individual_contig_r: false,
-		min_aligned_frac: 0.0,
-		keep_refs: false,
-		est_ci: skani_params.bootstrap_ci,
-		learned_ani: skani_params.adjust_ani,
-		detailed_out: false,
-		rescue_small: skani_params.rescue_small,
-		distance: true,
-	    };
-	    let adjust_ani = skani::regression::get_model(skani_params.kmer_subsampling_rate.into(), false);
+	    let sketch_params = panaani::dist::sketch_params(&skani_params);
+
+	    let query_files_in: Vec<String> = collect_inputs(query_files, query_files_list, cli.paths_relative_to_list);
 
-	    let mut query_files_in: Vec<String> = query_files.clone();
+	    let mut ref_files_in: Vec<String> = Vec::new();
+	    ref_files_in.append(read_input_list(ref_files_list.as_ref().unwrap(), cli.paths_relative_to_list).as_mut());
+
+	    let mut sketch_files: HashMap<String, String> = read_sketch_list(ref_files_list.as_ref().unwrap(), cli.paths_relative_to_list);
 	    if query_files_list.is_some() {
-		query_files_in.append(read_input_list(query_files_list.as_ref().unwrap()).as_mut());
+		sketch_files.extend(read_sketch_list(query_files_list.as_ref().unwrap(), cli.paths_relative_to_list));
 	    }
 
-	    let mut ref_files_in: Vec<String> = Vec::new();
-	    ref_files_in.append(read_input_list(ref_files_list.as_ref().unwrap()).as_mut());
-
-	    let ref_db = dist::sketch_fastx_files(&ref_files_in, Some(skani::params::SketchParams::new(
-		skani_params.marker_compression_factor as usize,
-		skani_params.kmer_subsampling_rate as usize,
-		skani_params.kmer_size as usize,
-		false,
-		false,
-	    )));
-
-	    let query_db = dist::sketch_fastx_files(&query_files_in, Some(skani::params::SketchParams::new(
-		skani_params.marker_compression_factor as usize,
-		skani_params.kmer_subsampling_rate as usize,
-		skani_params.kmer_size as usize,
-		false,
-		false,
-	    )));
-
-	    let query_dists = ref_db
-		.iter()
-		.map(|r| { query_db
-			   .par_iter()
-			   .map(|q| {
-			       (q.file_name.clone(),
-				r.file_name.clone(),
-				skani::chain::chain_seeds(
-				    r,
-				    q,
-				    skani::chain::map_params_from_sketch(
-					r,
-					false,
-					&cmd_params,
-					&adjust_ani,
-				    ),
-				)
-			       )
-			   })
-			   .collect::<Vec<(String, String, skani::types::AniEstResult)>>()
-		})
-		.flatten()
-		.map(|x| {
-		    (x.0,
-		     x.1,
-		     dist::filter_ani(x.2.ani, x.2.align_fraction_ref, x.2.align_fraction_query, skani_params.min_aligned_frac as f32, skani_params.min_aligned_frac as f32)
-		    )
-		})
-		.collect::<Vec<(String, String, f32)>>();
+	    fastx::check_kmer_size(&query_files_in, *skani_kmer_size as usize, "skani");
+	    fastx::check_kmer_size(&ref_files_in, *skani_kmer_size as usize, "skani");
 
-	    // Check that all queries were assigned
-	    let mut all_assigned = true;
-	    let mut best_match: HashMap<String, (String, f32, bool)> = HashMap::new();
-	    query_dists
-		.iter()
+	    // Sketch the references once and reuse them for all query batches
+	    let ref_db = panaani::dist::load_or_sketch_fastx_files(&ref_files_in, &sketch_params, &sketch_files, &skani_params);
+
+	    let batch_size = if query_batch_size.is_some() { query_batch_size.unwrap().max(1) } else { query_files_in.len().max(1) };
+	    let mut n_queries: usize = 0;
+	    let mut query_dists: Vec<(String, String, f32)> = Vec::new();
+	    let mut all_queries: Vec<skani::types::Sketch> = Vec::new();
+	    query_files_in
+		.chunks(batch_size)
 		.for_each(|x| {
-		    if !best_match.contains_key(&x.0) {
-			best_match.insert(x.0.clone(), (x.1.clone(), x.2.clone(), false));
-		    } else if x.2 > best_match.get(&x.0).unwrap().1 {
-			let assigned_twice: bool = (best_match.get(&x.0).unwrap().1 > *ani_threshold && x.2 > *ani_threshold) || best_match.get(&x.0).unwrap().2;
-			*best_match.get_mut(&x.0).unwrap() = (x.1.clone(), x.2.clone(), assigned_twice);
+		    let mut query_db = panaani::dist::load_or_sketch_fastx_files(&x.to_vec(), &sketch_params, &sketch_files, &skani_params);
+		    n_queries += query_db.len();
+		    query_dists.append(&mut panaani::dist::ani_from_sketches(&ref_db, &query_db, &Some(skani_params.clone())));
+		    if dedup_queries_file.is_some() {
+			all_queries.append(&mut query_db);
 		    }
 		});
 
+	    if dist_out_file.is_some() {
+		let mut f = std::fs::File::create(dist_out_file.as_ref().unwrap()).unwrap();
+		if top_k.is_some() {
+		    // Closest references first
+		    query_dists
+			.iter()
+			.sorted_by(|a, b| a.0.cmp(&b.0).then(b.2.total_cmp(&a.2)).then(a.1.cmp(&b.1)))
+			.group_by(|x| &x.0)
+			.into_iter()
+			.for_each(|(_, x)| { x.take(top_k.unwrap()).for_each(|y| { writeln!(f, "{}\t{}\t{}", y.0, y.1, y.2).unwrap(); }) });
+		} else {
+		    query_dists
+			.iter()
+			.sorted_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)))
+			.for_each(|x| { writeln!(f, "{}\t{}\t{}", x.0, x.1, x.2).unwrap(); });
+		}
+	    }
+
+	    if dedup_queries_file.is_some() {
+		// Group near-identical queries with single linkage at the ANI threshold
+		let query_pairs = panaani::dist::pairwise_ani_from_sketches(&all_queries, &Some(skani_params.clone()));
+		let query_names: Vec<String> = all_queries.iter().map(|x| x.file_name.clone()).sorted().collect();
+		let num_seqs = query_names.len();
+		let mut flattened_dissimilarity_matrix = clust::condensed_dissimilarity_matrix(&query_names, &query_pairs, 1.0);
+
+		let groups = if num_seqs > 1 {
+		    clust::single_linkage_cluster_condensed(&mut flattened_dissimilarity_matrix, num_seqs, &Some(clust::KodamaParams {
+			cutoff: *ani_threshold,
+			method: kodama::Method::Single,
+			..Default::default()
+		    }))
+		} else {
+		    vec![0; num_seqs]
+		};
+
+		// Labels are in order of first member so the first one seen is the representative
+		let mut representatives: HashMap<usize, &String> = HashMap::new();
+		query_names.iter().zip(groups.iter()).for_each(|x| { representatives.entry(*x.1).or_insert(x.0); });
+		info!("Found {} groups of near-identical queries among {} queries", representatives.len(), num_seqs);
+
+		let mut f = std::fs::File::create(dedup_queries_file.as_ref().unwrap()).unwrap();
+		query_names
+		    .iter()
+		    .zip(groups.iter())
+		    .for_each(|x| { writeln!(f, "{}\t{}", x.0, representatives.get(x.1).unwrap()).unwrap(); });
+	    }
+
+	    let mut out = open_output(output_file);
+	    print_header(&cli, &mut out);
+
+	    // References missing from --ref-thresholds use --ani-threshold
+	    let ref_thresholds: HashMap<String, f32> = if ref_thresholds_file.is_some() {
+		read_ref_thresholds(ref_thresholds_file.as_ref().unwrap())
+	    } else {
+		HashMap::new()
+	    };
+	    let threshold_of = |x: &String| -> f32 { *ref_thresholds.get(x).unwrap_or(ani_threshold) };
+
+	    // Check that all queries were assigned
+	    let mut all_assigned = true;
+	    let best_match = best_matches(&query_dists, threshold_of);
+
 	    let mut all_unambiguous = true;
 	    best_match
 		.iter()
-		.for_each(|x| { all_assigned &= x.1.1 > *ani_threshold; all_unambiguous &= !x.1.2 });
+		.for_each(|x| { all_assigned &= x.1.1 > threshold_of(&x.1.0); all_unambiguous &= !x.1.2 });
 
 	    if all_assigned && all_unambiguous {
-		info!("Assigned {}/{} queries unambiguously to reference database (ANI threshold {})", query_db.len(), query_db.len(), ani_threshold);
+		info!("Assigned {}/{} queries unambiguously to reference database (ANI threshold {})", n_queries, n_queries, ani_threshold);
 		best_match
 		    .iter()
-		    .for_each(|x| { println!("{}\t{}", x.0, x.1.0); });
+		    .for_each(|x| { writeln!(out, "{}\t{}\t{}", x.0, x.1.0, x.1.1).unwrap(); });
 	    } else if all_unambiguous {
-		let n_assigned: usize = best_match.iter().filter(|x| x.1.1 > *ani_threshold).count();
-		info!("Assigned {}/{} queries unambiguously to reference database (ANI threshold {})", n_assigned, query_db.len(), ani_threshold);
-		info!("{}/{} queries could not be assigned to any reference", query_db.len() - n_assigned,  query_db.len());
+		let n_assigned: usize = best_match.iter().filter(|x| x.1.1 > threshold_of(&x.1.0)).count();
+		info!("Assigned {}/{} queries unambiguously to reference database (ANI threshold {})", n_assigned, n_queries, ani_threshold);
+		info!("{}/{} queries could not be assigned to any reference", n_queries - n_assigned,  n_queries);
 		best_match
 		    .iter()
-		    .for_each(|x| { if x.1.1 > *ani_threshold { println!("{}\t{}", x.0, x.1.0); } else { println!("{}\t{}", x.0, "new_cluster"); } });
+		    .for_each(|x| { if x.1.1 > threshold_of(&x.1.0) { writeln!(out, "{}\t{}\t{}", x.0, x.1.0, x.1.1).unwrap(); } else { writeln!(out, "{}\t{}\t{}", x.0, "new_cluster", x.1.1).unwrap(); } });
 	    } else {
-		let n_assigned: usize = best_match.iter().filter(|x| x.1.1 > *ani_threshold).count();
+		let n_assigned: usize = best_match.iter().filter(|x| x.1.1 > threshold_of(&x.1.0)).count();
 		let n_ambiguous: usize = best_match.iter().filter(|x| x.1.2).count();
-		info!("Assigned {}/{} queries unambiguously to reference database (ANI threshold {})", n_assigned - n_ambiguous, query_db.len(), ani_threshold);
-		info!("{}/{} queries could not be assigned to any reference", query_db.len() - n_assigned,  query_db.len());
-		info!("{}/{} queries were assigned to multiple references", n_ambiguous, query_db.len());
+		info!("Assigned {}/{} queries unambiguously to reference database (ANI threshold {})", n_assigned - n_ambiguous, n_queries, ani_threshold);
+		info!("{}/{} queries could not be assigned to any reference", n_queries - n_assigned,  n_queries);
+		info!("{}/{} queries were assigned to multiple references", n_ambiguous, n_queries);
 		best_match
 		    .iter()
-		    .for_each(|x| { if x.1.1 > *ani_threshold && !x.1.2 { println!("{}\t{}", x.0, x.1.0); } else if x.1.1 > *ani_threshold && x.1.2 { println!("{}\t{}", x.0, "ambiguous"); } else { println!("{}\t{}", x.0, "new_cluster"); } });
+		    .for_each(|x| { if x.1.1 > threshold_of(&x.1.0) && !x.1.2 { writeln!(out, "{}\t{}\t{}", x.0, x.1.0, x.1.1).unwrap(); } else if x.1.1 > threshold_of(&x.1.0) && x.1.2 { writeln!(out, "{}\t{}\t{}", x.0, "ambiguous", x.1.1).unwrap(); } else { writeln!(out, "{}\t{}\t{}", x.0, "new_cluster", x.1.1).unwrap(); } });
 	    }
+	    out.flush().unwrap();
+	}
+
+        // Precompute skani sketches for the input fasta files
+        Some(cli::Commands::Sketch {
+            seq_files,
+            input_list,
+	    out_dir,
+            threads,
+	    temp_dir_path,
+	    verbose,
+	    no_progress,
+	    sketch,
+        }) => {
+	    init(*threads as usize, if *verbose { 2 } else { 1 });
+
+            let skani_params = panaani::dist::SkaniParams {
+		temp_dir: temp_dir_path.clone().unwrap_or("/tmp".to_string()),
+		progress: show_progress(*verbose, *no_progress),
+                ..sketch_params_from_args(sketch)
+            };
+
+	    let seq_files_in: Vec<String> = collect_inputs(seq_files, input_list, cli.paths_relative_to_list);
+	    fastx::check_kmer_size(&seq_files_in, sketch.skani_kmer_size as usize, "skani");
+
+	    // Same format as --input-list so the output can be passed back as is
+	    let manifest = panaani::dist::write_sketches(&seq_files_in, &Some(skani_params), out_dir);
+	    info!("Wrote {}/{} sketches to {}", manifest.len(), seq_files_in.len(), out_dir);
+	    print_header(&cli, &mut std::io::stdout());
+	    manifest.iter().for_each(|x| println!("{}\t{}", x.0, x.1));
+	}
+
+        // Assembly statistics for the input fasta files
+        Some(cli::Commands::Seqstats {
+            seq_files,
+            input_list,
+            threads,
+	    verbose,
+        }) => {
+	    init(*threads as usize, if *verbose { 2 } else { 1 });
+
+	    let mut seq_files_in: Vec<String> = collect_inputs(seq_files, input_list, cli.paths_relative_to_list);
+	    seq_files_in.sort();
+
+	    let stats: Vec<(usize, usize, usize, f64)> = seq_files_in
+		.par_iter()
+		.map(fastx::sequence_stats)
+		.collect();
+
+	    print_header(&cli, &mut std::io::stdout());
+	    seq_files_in.iter().zip(stats.iter()).for_each(|x| {
+		println!("{}\t{}\t{}\t{}\t{:.4}", x.0, x.1.0, x.1.1, x.1.2, x.1.3)
+	    });
+	}
+
+        // Check a clustering against pairwise ANIs
+        Some(cli::Commands::Validate {
+            clusters_file,
+            dist_file,
+	    has_header,
+	    verbose,
+	    ani_threshold,
+        }) => {
+	    init_log(if *verbose { 2 } else { 1 });
+
+	    let seq_to_cluster: HashMap<String, String> = read_seq_assignments(&[], clusters_file).into_iter().collect();
+
+	    let mut reader = dist_reader(dist_file, *has_header);
+
+	    // Pairs in the same cluster below the threshold or in different clusters above it
+	    let mut violations: Vec<(String, String, f32, &str)> = Vec::new();
+	    let mut n_missing: usize = 0;
+	    let mut record = csv::StringRecord::new();
+	    while reader.read_record(&mut record).unwrap() {
+		let ani = parse_ani(&record[2], false);
+		match (seq_to_cluster.get(&record[0]), seq_to_cluster.get(&record[1])) {
+		    (Some(cluster1), Some(cluster2)) => {
+			if cluster1 == cluster2 && ani < *ani_threshold {
+			    violations.push((record[0].to_string(), record[1].to_string(), ani, "within"));
+			} else if cluster1 != cluster2 && ani >= *ani_threshold {
+			    violations.push((record[0].to_string(), record[1].to_string(), ani, "between"));
+			}
+		    },
+		    _ => n_missing += 1,
+		}
+	    }
+
+	    if n_missing > 0 {
+		warn!("{} pairs in {} contain sequences not found in {}", n_missing, dist_file, clusters_file);
+	    }
+	    if violations.is_empty() {
+		info!("No pairs violate ANI threshold {}", ani_threshold);
+	    } else {
+		let n_within = violations.iter().filter(|x| x.3 == "within").count();
+		warn!("{} pairs violate ANI threshold {}: {} within clusters, {} between clusters",
+		      violations.len(), ani_threshold, n_within, violations.len() - n_within);
+	    }
+
+	    print_header(&cli, &mut std::io::stdout());
+	    violations.iter().for_each(|x| { println!("{}\t{}\t{}\t{}", x.0, x.1, x.2, x.3) });
+	}
+
+        // Combine cluster assignments from independent runs
+        Some(cli::Commands::Merge {
+            assignment_files,
+	    prefixes,
+	    renumber,
+	    cluster_prefix,
+	    output_file,
+	    output_format,
+	    merge_close,
+	    ani_threshold,
+            threads,
+	    verbose,
+	    no_progress,
+	    sketch,
+        }) => {
+	    init(*threads as usize, if *verbose { 2 } else { 1 });
+
+	    let prefixes: Vec<String> = if prefixes.is_some() {
+		prefixes.clone().unwrap()
+	    } else {
+		assignment_files
+		    .iter()
+		    .map(|x| std::path::Path::new(x).file_stem().map_or(x.clone(), |y| y.to_string_lossy().to_string()) + "_")
+		    .collect()
+	    };
+	    if prefixes.len() != assignment_files.len() {
+		panic!("--prefixes has {} values for {} assignment files", prefixes.len(), assignment_files.len());
+	    }
+	    if !renumber && prefixes.iter().unique().count() != prefixes.len() {
+		warn!("Prefixes {} are not unique, adding the file number to the repeated ones", prefixes.join(","));
+	    }
+
+	    let runs: Vec<Vec<(String, String)>> = assignment_files.iter().map(|x| read_seq_assignments(&[], x)).collect();
+	    let runs = panaani::merge_assignments(&runs, &prefixes);
+
+	    let mut cluster_contents: HashMap<String, Vec<String>> = if *merge_close {
+		let skani_params = panaani::dist::SkaniParams {
+		    progress: show_progress(*verbose, *no_progress),
+                    ..sketch_params_from_args(sketch)
+		};
+		let kodama_params = panaani::clust::KodamaParams {
+                    cutoff: *ani_threshold,
+                    method: kodama::Method::Single,
+                    ..Default::default()
+		};
+		panaani::merge_close_clusters(&runs, &Some(skani_params), &Some(kodama_params))
+	    } else {
+		runs.into_iter().flatten().collect()
+	    };
+	    if *renumber {
+		cluster_contents = panaani::renumber_clusters(&cluster_contents, cluster_prefix);
+	    }
+	    info!("Merged {} files into {} clusters", assignment_files.len(), cluster_contents.len());
+
+	    let mut out = open_output(output_file);
+	    print_header(&cli, &mut out);
+	    print_clusters(&panaani::flatten_assignments(&cluster_contents), output_format, &mut out);
+	    out.flush().unwrap();
 	}
         None => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn best_matches_per_ref_thresholds() {
+	let query_dists: Vec<(String, String, f32)> = vec![
+	    ("q.fa".to_string(), "a.fa".to_string(), 0.96),
+	    ("q.fa".to_string(), "b.fa".to_string(), 0.95),
+	];
+
+	// The closer reference needs 0.97, so the query goes to the one it passes
+	let strict_a: HashMap<String, f32> = [("a.fa".to_string(), 0.97), ("b.fa".to_string(), 0.94)].into_iter().collect();
+	let best = best_matches(&query_dists, |x| *strict_a.get(x).unwrap());
+	assert_eq!(best.get("q.fa").unwrap(), &("b.fa".to_string(), 0.95, false));
+
+	let strict_b: HashMap<String, f32> = [("a.fa".to_string(), 0.95), ("b.fa".to_string(), 0.96)].into_iter().collect();
+	let best = best_matches(&query_dists, |x| *strict_b.get(x).unwrap());
+	assert_eq!(best.get("q.fa").unwrap(), &("a.fa".to_string(), 0.96, false));
+
+	// Passing both is ambiguous, passing neither keeps the closest reference
+	let best = best_matches(&query_dists, |_| 0.94);
+	assert_eq!(best.get("q.fa").unwrap(), &("a.fa".to_string(), 0.96, true));
+	let best = best_matches(&query_dists, |_| 0.97);
+	assert_eq!(best.get("q.fa").unwrap(), &("a.fa".to_string(), 0.96, false));
+    }
+
+    #[test]
+    fn read_fastani_sample() {
+	// Both directions and the self hit as fastANI writes them, b-c is below its cutoff
+	let fastani_file = std::env::temp_dir().join(format!("panaani-test-{}.fastani.tsv", std::process::id()));
+	std::fs::write(&fastani_file, "a.fa\ta.fa\t100\t1000\t1000\n\
+				       a.fa\tb.fa\t97.5\t900\t1000\n\
+				       b.fa\ta.fa\t98.0\t950\t1000\n\
+				       c.fa\ta.fa\t85.0\t400\t1000\n").unwrap();
+	let (seq_names, matrix) = read_fastani(&fastani_file.to_string_lossy().to_string(), 0.25);
+	std::fs::remove_file(&fastani_file).unwrap();
+
+	assert_eq!(seq_names, vec!["a.fa".to_string(), "b.fa".to_string(), "c.fa".to_string()]);
+	let expected: Vec<f32> = vec![0.02, 0.15, 0.25];
+	assert_eq!(matrix.len(), expected.len());
+	matrix.iter().zip(expected.iter()).for_each(|(x, y)| assert!((x - y).abs() < 1e-6, "{} != {}", x, y));
+    }
+
+    #[test]
+    fn collect_inputs_positional_and_list() {
+	let input_list = std::env::temp_dir().join(format!("panaani-test-{}.inputs.txt", std::process::id()));
+	std::fs::write(&input_list, "b.fa\nc.fa\n").unwrap();
+	let seq_files: Vec<String> = vec!["a.fa".to_string(), "b.fa".to_string()];
+	let inputs = collect_inputs(&seq_files, &Some(input_list.to_string_lossy().to_string()), false);
+	std::fs::remove_file(&input_list).unwrap();
+
+	// b.fa is in both and kept once, in its first position
+	assert_eq!(inputs, vec!["a.fa".to_string(), "b.fa".to_string(), "c.fa".to_string()]);
+    }
+
+    #[test]
+    fn read_input_list_relative_to_list() {
+	// The list sits in genomes/ next to the files it names
+	let list_dir = std::env::temp_dir().join(format!("panaani-test-{}", std::process::id())).join("genomes");
+	std::fs::create_dir_all(&list_dir).unwrap();
+	let input_list = list_dir.join("inputs.txt");
+	std::fs::write(&input_list, "a.fa\n/data/b.fa\n").unwrap();
+	let input_list = input_list.to_string_lossy().to_string();
+
+	let relative = read_input_list(&input_list, true);
+	let unchanged = read_input_list(&input_list, false);
+	std::fs::remove_dir_all(list_dir.parent().unwrap()).unwrap();
+
+	assert_eq!(relative, vec![list_dir.join("a.fa").to_string_lossy().to_string(), "/data/b.fa".to_string()]);
+	assert_eq!(unchanged, vec!["a.fa".to_string(), "/data/b.fa".to_string()]);
+    }
+
+    #[test]
+    fn read_input_list_hash_in_path() {
+	// Only the leading provenance line is a comment
+	let input_list = std::env::temp_dir().join(format!("panaani-test-{}-header.txt", std::process::id()));
+	std::fs::write(&input_list, "# panaani v0.1.0, params hash 0\na.fa\n#b.fa\n").unwrap();
+	let seq_files = read_input_list(&input_list.to_string_lossy().to_string(), false);
+	std::fs::remove_file(&input_list).unwrap();
+
+	assert_eq!(seq_files, vec!["a.fa".to_string(), "#b.fa".to_string()]);
+    }
+
+    #[test]
+    fn resolved_params_ignore_runtime_args() {
+	let hash = |args: &[&str]| params_hash(&resolved_params(&cli::Cli::command().get_matches_from(args)));
+	let default = hash(&["panaani", "dereplicate", "a.fa", "b.fa"]);
+
+	assert_eq!(default, hash(&["panaani", "dereplicate", "a.fa", "b.fa", "--threads", "4", "--verbose"]));
+	assert_eq!(default, hash(&["panaani", "dereplicate", "a.fa", "b.fa", "--ani-threshold", "0.97"]));
+	assert_ne!(default, hash(&["panaani", "dereplicate", "a.fa", "b.fa", "--ani-threshold", "0.9"]));
+    }
+}